@@ -0,0 +1,124 @@
+#![warn(clippy::all)]
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, AttributeArgs, ItemFn, Lit, Meta, NestedMeta};
+
+/// Where the temporary database for a test should live.
+enum Mode {
+    /// CREATE DATABASE inside an existing server reachable at this url.
+    External(String),
+    /// Start a temporary postgres container from this image first.
+    Docker(String),
+}
+
+fn parse_mode(args: AttributeArgs) -> syn::Result<Mode> {
+    let mut mode = None;
+    for arg in args {
+        let (name, value) = match arg {
+            NestedMeta::Meta(Meta::NameValue(ref nv)) => match nv.lit {
+                Lit::Str(ref value) => (nv.path.get_ident().map(|i| i.to_string()), value.value()),
+                ref lit => return Err(syn::Error::new_spanned(lit, "expected a string literal")),
+            },
+            ref other => {
+                return Err(syn::Error::new_spanned(
+                    other,
+                    "expected `url = \"...\"` or `docker = \"...\"`",
+                ))
+            }
+        };
+        if mode.is_some() {
+            return Err(syn::Error::new_spanned(
+                arg,
+                "only one of `url` or `docker` may be specified",
+            ));
+        }
+        mode = Some(match name.as_deref() {
+            Some("url") => Mode::External(value),
+            Some("docker") => Mode::Docker(value),
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    arg,
+                    "unknown argument, expected `url` or `docker`",
+                ))
+            }
+        });
+    }
+    Ok(mode.unwrap_or_else(|| {
+        Mode::External("postgres://postgres@localhost:5432/postgres".to_owned())
+    }))
+}
+
+/// Turn a function taking a `postgres::Connection` into a test which runs it
+/// against a freshly created temporary database.
+///
+/// ```ignore
+/// #[kpostgres_fixture::test]
+/// fn creates_table(conn: Connection) {
+///     conn.batch_execute("CREATE TABLE test()").unwrap();
+/// }
+///
+/// #[kpostgres_fixture::test(url = "postgres://postgres@db:5432/postgres")]
+/// fn against_another_server(conn: Connection) {}
+///
+/// #[kpostgres_fixture::test(docker = "postgres:11")]
+/// fn against_a_container(conn: Connection) {}
+/// ```
+///
+/// Without arguments the database is created inside the server at
+/// `postgres://postgres@localhost:5432/postgres`.
+#[proc_macro_attribute]
+pub fn test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let input = parse_macro_input!(item as ItemFn);
+    match expand(args, input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(args: AttributeArgs, input: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let mode = parse_mode(args)?;
+
+    let sig = &input.sig;
+    if let Some(asyncness) = sig.asyncness {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "async test functions are not supported",
+        ));
+    }
+    if sig.inputs.len() != 1 {
+        return Err(syn::Error::new_spanned(
+            &sig.inputs,
+            "the test function must take exactly one `Connection` argument",
+        ));
+    }
+
+    let attrs = &input.attrs;
+    let vis = &input.vis;
+    let name = &sig.ident;
+    let inputs = &sig.inputs;
+    let output = &sig.output;
+    let block = &input.block;
+    let body = syn::Ident::new("__kpg_fixture_body", Span::call_site());
+
+    let run = match mode {
+        Mode::External(url) => quote! {
+            ::kpostgres_fixture::__macro_support::run_with_database(#url, #body)
+        },
+        Mode::Docker(image) => quote! {
+            ::kpostgres_fixture::__macro_support::run_with_postgres(#image, #body)
+        },
+    };
+
+    Ok(quote! {
+        #[::core::prelude::v1::test]
+        #(#attrs)*
+        #vis fn #name() #output {
+            fn #body(#inputs) #output #block
+            #run
+        }
+    })
+}
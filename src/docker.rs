@@ -0,0 +1,1913 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use log::*;
+use postgres::params::{self, ConnectParams};
+use postgres::tls::TlsHandshake;
+use postgres::{Connection, TlsMode};
+
+use crate::hook::{self, ConnectHook};
+use crate::{ConnectionPurpose, DatabaseConfig, Error, Result, TempDbInfo};
+
+/// Options for the container created by [`with_temporary_postgres_config`].
+///
+/// Like [`DatabaseConfig`], a base config can be cloned and tweaked, e.g. to
+/// run the same tests against several images.
+#[derive(Debug, Clone)]
+pub struct PostgresConfig {
+    docker_image: String,
+    platform: Option<String>,
+    host: String,
+    ipv6: bool,
+    verify_query_on_ready: bool,
+    poll_interval: Duration,
+    wait_for_healthcheck: bool,
+    ready_log_pattern: Option<String>,
+    stop_signal: Option<String>,
+    restart_policy: Option<(String, u16)>,
+    max_lifetime: Option<Duration>,
+    logical_replication: bool,
+    pg_stat_statements: bool,
+    parallel_workers: Option<u32>,
+    force_parallel_mode: bool,
+    settings: Vec<(String, String)>,
+    connect_hook: Option<ConnectHook>,
+    tls: Option<Arc<dyn TlsHandshake + Send + Sync>>,
+    snapshot: Option<PathBuf>,
+    checkpoint_before_snapshot: bool,
+    restore_snapshot: Option<PathBuf>,
+    tmpfs_tablespace: bool,
+    stats_hook: Option<StatsHook>,
+}
+
+/// Whether a [`ConnectionEvent`] is a connection or a disconnection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEventKind {
+    /// A client authenticated, from `connection authorized:`.
+    Connected,
+    /// A session ended, from `disconnection:`.
+    Disconnected,
+}
+
+/// A connection or disconnection the server logged with
+/// [`PostgresConfig::log_connections`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionEvent {
+    pub kind: ConnectionEventKind,
+    pub user: String,
+    pub database: String,
+}
+
+impl ConnectionEvent {
+    /// The event logged on `line`, if any.
+    fn parse(line: &str) -> Option<ConnectionEvent> {
+        let (kind, details) = if let Some(at) = line.find("connection authorized: ") {
+            (
+                ConnectionEventKind::Connected,
+                &line[at + "connection authorized: ".len()..],
+            )
+        } else if let Some(at) = line.find("disconnection: ") {
+            (
+                ConnectionEventKind::Disconnected,
+                &line[at + "disconnection: ".len()..],
+            )
+        } else {
+            return None;
+        };
+        let field = |name: &str| {
+            details
+                .split_whitespace()
+                .find_map(|pair| pair.strip_prefix(name))
+                .map(str::to_owned)
+        };
+        Some(ConnectionEvent {
+            kind,
+            user: field("user=")?,
+            database: field("database=")?,
+        })
+    }
+}
+
+/// Resource usage of a container, as reported by the docker stats API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContainerStats {
+    /// Most memory the container used at any point, in bytes. Docker only
+    /// reports that on cgroup v1 hosts; on cgroup v2 this is the usage when
+    /// the stats were taken.
+    pub peak_memory: u64,
+    /// The container's memory limit, in bytes.
+    pub memory_limit: u64,
+    /// CPU time used by the container so far, across all CPUs.
+    pub cpu_time: Duration,
+}
+
+#[derive(Clone)]
+struct StatsHook(Arc<dyn Fn(&ContainerStats) + Send + Sync>);
+
+impl fmt::Debug for StatsHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("StatsHook")
+    }
+}
+
+/// Roughly how long to keep polling a new container before giving up.
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a container's healthcheck to report healthy. Longer
+/// than [`READY_TIMEOUT`] since healthchecks usually run every few seconds.
+const HEALTHY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The line postgres logs once it accepts connections, in English, which
+/// [`PostgresConfig::wait_for_ready_log`] waits for.
+pub const DEFAULT_READY_LOG_PATTERN: &str = "database system is ready to accept connections";
+
+/// The tablespace created by [`PostgresConfig::tmpfs_tablespace`].
+pub const TMPFS_TABLESPACE: &str = "kpg_fixture_tmpfs";
+
+/// Where the tmpfs for [`TMPFS_TABLESPACE`] is mounted in the container.
+const TMPFS_TABLESPACE_MOUNT: &str = "/var/lib/postgresql/kpg_fixture_tmpfs";
+
+/// Where the image keeps the data directory, i.e. `PGDATA`.
+const DATA_DIRECTORY: &str = "/var/lib/postgresql/data";
+
+impl PostgresConfig {
+    /// Use `docker_image` (e.g. `postgres:11`) as the base of the container.
+    pub fn new(docker_image: &str) -> PostgresConfig {
+        PostgresConfig {
+            docker_image: docker_image.to_owned(),
+            platform: None,
+            // Not `localhost`: on some CI machines that resolves to ::1 first while
+            // docker only publishes the port on IPv4, and the connection is refused.
+            host: "127.0.0.1".to_owned(),
+            ipv6: false,
+            verify_query_on_ready: true,
+            poll_interval: Duration::from_millis(100),
+            wait_for_healthcheck: false,
+            ready_log_pattern: None,
+            stop_signal: None,
+            restart_policy: None,
+            max_lifetime: None,
+            logical_replication: false,
+            pg_stat_statements: false,
+            parallel_workers: None,
+            force_parallel_mode: false,
+            settings: Vec::new(),
+            connect_hook: None,
+            tls: None,
+            snapshot: None,
+            checkpoint_before_snapshot: true,
+            restore_snapshot: None,
+            tmpfs_tablespace: false,
+            stats_hook: None,
+        }
+    }
+
+    /// The host that the published postgres port is reachable at.
+    ///
+    /// Defaults to `127.0.0.1`.
+    pub fn host(&mut self, host: &str) -> &mut PostgresConfig {
+        self.host = host.to_owned();
+        self
+    }
+
+    /// Connect over IPv6, to the published port on `::1`, e.g. to test
+    /// dual-stack clients. This sets [`host`](#method.host), so call that
+    /// afterwards to connect to another IPv6 address.
+    ///
+    /// Docker has to be publishing ports on IPv6 too, which it usually does
+    /// on the same port number as on IPv4.
+    ///
+    /// Defaults to false.
+    pub fn ipv6(&mut self, ipv6: bool) -> &mut PostgresConfig {
+        self.ipv6 = ipv6;
+        self.host = if ipv6 { "::1" } else { "127.0.0.1" }.to_owned();
+        self
+    }
+
+    /// Start the server with these `listen_addresses` instead of the image's
+    /// `*`, e.g. `&["0.0.0.0", "::"]`.
+    pub fn listen_addresses(&mut self, addresses: &[&str]) -> &mut PostgresConfig {
+        self.setting("listen_addresses", &addresses.join(","))
+    }
+
+    /// Whether to run `SELECT 1` before considering the server ready, rather
+    /// than trusting that accepting a connection means it is usable.
+    ///
+    /// Defaults to true.
+    pub fn verify_query_on_ready(&mut self, verify: bool) -> &mut PostgresConfig {
+        self.verify_query_on_ready = verify;
+        self
+    }
+
+    /// How long to wait between attempts to connect to a new container. A
+    /// smaller interval, e.g. 25ms, shaves latency off startup on fast
+    /// machines. The total wait stays about the same, so it just makes for
+    /// more attempts.
+    ///
+    /// Defaults to 100ms.
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut PostgresConfig {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Wait for the image's own `HEALTHCHECK` (e.g. `pg_isready`) to report
+    /// the container as healthy before connecting, rather than polling with
+    /// connection attempts, so that `docker ps` and orchestration tools agree
+    /// with the fixture about when the server is up.
+    ///
+    /// The healthcheck has to be part of the image, e.g. one built `FROM
+    /// postgres` with `HEALTHCHECK --interval=1s CMD pg_isready -U postgres`;
+    /// the docker client used here can't add one when creating the
+    /// container. Fails if the image has none or it reports unhealthy.
+    ///
+    /// Defaults to false.
+    pub fn wait_for_healthcheck(&mut self, wait: bool) -> &mut PostgresConfig {
+        self.wait_for_healthcheck = wait;
+        self
+    }
+
+    /// Wait for the server to log [`DEFAULT_READY_LOG_PATTERN`] before
+    /// attempting to connect, so the readiness polling doesn't spend its
+    /// attempts on a server still initializing a large data directory.
+    ///
+    /// Defaults to false.
+    pub fn wait_for_ready_log(&mut self, wait: bool) -> &mut PostgresConfig {
+        self.ready_log_pattern = if wait {
+            Some(DEFAULT_READY_LOG_PATTERN.to_owned())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Like [`wait_for_ready_log`](#method.wait_for_ready_log), waiting for a
+    /// log line containing `pattern` instead, e.g. the translated message when
+    /// the image sets a non-English `LC_MESSAGES`, or a line a custom image
+    /// logs once its own initialization is done.
+    pub fn ready_log_pattern(&mut self, pattern: &str) -> &mut PostgresConfig {
+        self.ready_log_pattern = Some(pattern.to_owned());
+        self
+    }
+
+    /// The signal docker stops the container with, e.g. `SIGINT` for a
+    /// postgres "fast" shutdown, which disconnects clients instead of waiting
+    /// for them like the "smart" shutdown on `SIGTERM` does.
+    ///
+    /// A fast shutdown still writes a shutdown checkpoint, and the data is
+    /// thrown away with the container anyway, so there is nothing to lose
+    /// (except when taking a [`snapshot`](#method.snapshot), which happens
+    /// before stopping).
+    ///
+    /// Defaults to `SIGTERM`, which dockworker sends unless told otherwise.
+    pub fn stop_signal(&mut self, signal: &str) -> &mut PostgresConfig {
+        self.stop_signal = Some(signal.to_owned());
+        self
+    }
+
+    /// Force remove the container from a background thread if it is still
+    /// around `max_lifetime` after being created, failing the fixture with
+    /// [`Error::LifetimeExceeded`]. A safety net so a wedged test can't hold
+    /// on to a CI runner forever.
+    ///
+    /// Removing the container breaks the connections to it, so the closure
+    /// notices as soon as it next talks to the database, but a closure stuck
+    /// on something else keeps running.
+    pub fn max_lifetime(&mut self, max_lifetime: Duration) -> &mut PostgresConfig {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Have docker restart the container according to the restart policy
+    /// `name` (`on-failure`, `unless-stopped` or `always`), e.g. so a
+    /// [`PostgresContainer`] shared by many tests comes back after postgres
+    /// crashes. `maximum_retry_count` limits the restarts of `on-failure`, 0
+    /// meaning no limit.
+    ///
+    /// Stopping and removing the container when it is dropped still works,
+    /// since docker doesn't restart containers stopped through its API. But a
+    /// container leaked by a process that didn't get to drop it (e.g. killed
+    /// with SIGKILL) is kept running by an `unless-stopped` or `always` policy,
+    /// even across daemon restarts, until it is removed by hand. A restarted
+    /// container may also be published on another port, which the params
+    /// handed out don't follow.
+    ///
+    /// Defaults to `no`.
+    pub fn restart_policy(&mut self, name: &str, maximum_retry_count: u16) -> &mut PostgresConfig {
+        self.restart_policy = Some((name.to_owned(), maximum_retry_count));
+        self
+    }
+
+    /// Start the server with `-c name=value`. Later settings win over earlier
+    /// ones and over the ones implied by other options.
+    pub fn setting(&mut self, name: &str, value: &str) -> &mut PostgresConfig {
+        self.settings.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Start the server with `max_connections=connections`, e.g. to test how a
+    /// connection pool copes with a server that runs out of connections.
+    ///
+    /// The last `superuser_reserved_connections` (3 by default) of them are
+    /// only available to superusers, and the server refuses to start unless
+    /// there are more connections than that. The fixture's own admin
+    /// connections count too: [`with_temporary_postgres_database`] keeps one
+    /// open, so with the defaults `max_connections(6)` leaves exactly 2 for
+    /// the closure's connections as the temporary role.
+    pub fn max_connections(&mut self, connections: u32) -> &mut PostgresConfig {
+        self.setting("max_connections", &connections.to_string())
+    }
+
+    /// Start the server with `work_mem=work_mem`, e.g. `64kB` to make sorts
+    /// and hashes spill to disk early, which
+    /// [`with_spill_stats`](crate::with_spill_stats) can confirm. A single
+    /// database can get its own with
+    /// [`DatabaseConfig::database_setting`](crate::DatabaseConfig::database_setting).
+    pub fn work_mem(&mut self, work_mem: &str) -> &mut PostgresConfig {
+        self.setting("work_mem", work_mem)
+    }
+
+    /// Start the server with `max_prepared_transactions=transactions`, so
+    /// two phase commits can be tested with
+    /// [`prepare_transaction`](crate::prepare_transaction). The server's
+    /// default of 0 disables them.
+    pub fn max_prepared_transactions(&mut self, transactions: u32) -> &mut PostgresConfig {
+        self.setting("max_prepared_transactions", &transactions.to_string())
+    }
+
+    /// Start the server with `track_io_timing=on`, so
+    /// [`with_io_stats`](crate::with_io_stats) reports how long reading and
+    /// writing blocks took, as do `EXPLAIN (ANALYZE, BUFFERS)` and
+    /// `pg_stat_statements`. Costs a clock read per block on slow clocks.
+    ///
+    /// Defaults to false.
+    pub fn track_io_timing(&mut self, track: bool) -> &mut PostgresConfig {
+        self.setting("track_io_timing", if track { "on" } else { "off" })
+    }
+
+    /// Start the server with `max_locks_per_transaction=locks`, for schemas
+    /// with thousands of tables: a transaction locks every table it creates,
+    /// drops or truncates, e.g. in a single migration,
+    /// [`truncate_all`](crate::truncate_all) or `DROP SCHEMA ... CASCADE`, and
+    /// fails with "out of shared memory" once the lock table, sized for the
+    /// default of 64 per connection, is full.
+    pub fn max_locks_per_transaction(&mut self, locks: u32) -> &mut PostgresConfig {
+        self.setting("max_locks_per_transaction", &locks.to_string())
+    }
+
+    /// Start the server with `wal_level=logical` and room for replication
+    /// slots and WAL senders, for testing logical replication or CDC.
+    ///
+    /// Defaults to false.
+    pub fn logical_replication(&mut self, enable: bool) -> &mut PostgresConfig {
+        self.logical_replication = enable;
+        self
+    }
+
+    /// Start the server with `log_connections` and `log_disconnections` on, so
+    /// [`PostgresContainer::connection_events`] can tell how many connections
+    /// were opened and closed, e.g. by a connection pool under test.
+    ///
+    /// Defaults to false.
+    pub fn log_connections(&mut self, log: bool) -> &mut PostgresConfig {
+        let value = if log { "on" } else { "off" };
+        self.setting("log_connections", value)
+            .setting("log_disconnections", value)
+    }
+
+    /// Start the server with `pg_stat_statements` in `shared_preload_libraries`,
+    /// for capturing the queries a test runs with
+    /// [`with_statement_capture`](crate::with_statement_capture). The
+    /// extension still has to be created in the database, e.g. with
+    /// [`DatabaseConfig::extension`](crate::DatabaseConfig::extension).
+    ///
+    /// Defaults to false.
+    pub fn pg_stat_statements(&mut self, enable: bool) -> &mut PostgresConfig {
+        self.pg_stat_statements = enable;
+        self
+    }
+
+    /// Start the server with room for `workers` parallel workers, all of which
+    /// a single Gather can use, and with parallel plans costed as free, so
+    /// queries on the small tables of a test get parallel plans regardless of
+    /// the host's core count. Sets `max_parallel_workers`,
+    /// `max_parallel_workers_per_gather`, `max_worker_processes` (`workers`
+    /// on top of the default 8 for other background workers),
+    /// `parallel_setup_cost`, `parallel_tuple_cost`,
+    /// `min_parallel_table_scan_size` and `min_parallel_index_scan_size`.
+    /// Needs postgres 10+.
+    ///
+    /// Defaults to the server's own settings.
+    pub fn parallel_workers(&mut self, workers: u32) -> &mut PostgresConfig {
+        self.parallel_workers = Some(workers);
+        self
+    }
+
+    /// Start the server with `force_parallel_mode=on`, which runs every query
+    /// that can be parallelized under a Gather even if the planner doesn't
+    /// think it is worth it. Postgres 16 renamed it to `debug_parallel_query`,
+    /// set that with [`setting`](#method.setting) instead.
+    ///
+    /// Defaults to false.
+    pub fn force_parallel_mode(&mut self, force: bool) -> &mut PostgresConfig {
+        self.force_parallel_mode = force;
+        self
+    }
+
+    /// Start the server with `ssl=on`, and connect to it with
+    /// `TlsMode::Require(handshake)`: the connections the crate makes and the
+    /// `TlsMode` handed to the closures, e.g. a native-tls or rustls based
+    /// handshake.
+    ///
+    /// The server uses the self-signed snakeoil certificate of the Debian
+    /// based images, so `handshake` has to accept it without verifying it.
+    /// Other images need their certificate given with
+    /// [`setting`](#method.setting)s for `ssl_cert_file` and `ssl_key_file`.
+    pub fn tls<H>(&mut self, handshake: H) -> &mut PostgresConfig
+    where
+        H: TlsHandshake + Send + Sync + 'static,
+    {
+        self.tls = Some(Arc::new(handshake));
+        self
+    }
+
+    /// How to connect to the container, see [`tls`](#method.tls).
+    fn tls_mode(&self) -> TlsMode<'_> {
+        match self.tls {
+            Some(ref handshake) => TlsMode::Require(&**handshake),
+            None => TlsMode::None,
+        }
+    }
+
+    /// Call `hook` right before each connection attempt made while waiting for
+    /// the container to be ready, see [`DatabaseConfig::on_connect`](crate::DatabaseConfig::on_connect).
+    pub fn on_connect<H>(&mut self, hook: H) -> &mut PostgresConfig
+    where
+        H: Fn(ConnectionPurpose, &ConnectParams) + Send + Sync + 'static,
+    {
+        self.connect_hook = Some(ConnectHook(Arc::new(hook)));
+        self
+    }
+
+    /// After the closure returns, collect the container's resource usage, log
+    /// it and pass it to `hook`, e.g. to flag tests that make the database use
+    /// much more memory than expected.
+    ///
+    /// Failing to collect the stats is only logged, so that it doesn't fail
+    /// the test.
+    pub fn on_stats<H>(&mut self, hook: H) -> &mut PostgresConfig
+    where
+        H: Fn(&ContainerStats) + Send + Sync + 'static,
+    {
+        self.stats_hook = Some(StatsHook(Arc::new(hook)));
+        self
+    }
+
+    /// After the closure returns, save the server's data directory as a tar
+    /// archive at `path`, to start later containers from with
+    /// [`restore_snapshot`](#method.restore_snapshot).
+    ///
+    /// The snapshot is taken while the server is still running, see
+    /// [`checkpoint_before_snapshot`](#method.checkpoint_before_snapshot).
+    pub fn snapshot<P: AsRef<Path>>(&mut self, path: P) -> &mut PostgresConfig {
+        self.snapshot = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Run CHECKPOINT right before taking the snapshot, so everything written
+    /// so far is in the data files rather than only in the WAL. Without it the
+    /// snapshot can be missing recently written pages, and restoring it goes
+    /// through a long crash recovery, or fails if WAL it needs was recycled
+    /// while it was being copied.
+    ///
+    /// Defaults to true.
+    pub fn checkpoint_before_snapshot(&mut self, checkpoint: bool) -> &mut PostgresConfig {
+        self.checkpoint_before_snapshot = checkpoint;
+        self
+    }
+
+    /// Start from the data directory saved by [`snapshot`](#method.snapshot)
+    /// at `path` instead of an empty one. The image has to be the same major
+    /// version of postgres that took it.
+    pub fn restore_snapshot<P: AsRef<Path>>(&mut self, path: P) -> &mut PostgresConfig {
+        self.restore_snapshot = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Mount a tmpfs in the container and create the tablespace
+    /// [`TMPFS_TABLESPACE`] on it, so that tables and indexes live in memory.
+    /// The databases created by [`with_temporary_postgres_database`] use it
+    /// as their default tablespace unless the [`DatabaseConfig`] picks
+    /// another; for others pass it to [`DatabaseConfig::tablespace`].
+    ///
+    /// The catalogs and the WAL stay in the data directory. Since the
+    /// tablespace's contents aren't part of the data directory, this can't
+    /// be combined with [`restore_snapshot`](#method.restore_snapshot).
+    ///
+    /// Defaults to false.
+    pub fn tmpfs_tablespace(&mut self, enable: bool) -> &mut PostgresConfig {
+        self.tmpfs_tablespace = enable;
+        self
+    }
+
+    /// Require the image to be for `platform`, e.g. `linux/amd64` or
+    /// `linux/arm64`, failing before creating the container with
+    /// [`Error::InvalidConfig`] if the local image is for another one.
+    ///
+    /// The docker client used here can't pass a platform to docker, so it
+    /// can't pick or pull the right variant itself: pull it beforehand with
+    /// `docker pull --platform <platform> <image>`. This makes sure a test
+    /// doesn't silently run under emulation (or natively, when it meant to
+    /// test the emulated architecture).
+    ///
+    /// Defaults to whatever the local image is for.
+    pub fn platform(&mut self, platform: &str) -> &mut PostgresConfig {
+        self.platform = Some(platform.to_owned());
+        self
+    }
+
+    /// All the `-c` settings to start the server with, in order.
+    fn server_settings(&self) -> Vec<(String, String)> {
+        let mut settings = Vec::new();
+        if self.logical_replication {
+            for &(name, value) in &[
+                ("wal_level", "logical"),
+                ("max_replication_slots", "10"),
+                ("max_wal_senders", "10"),
+            ] {
+                settings.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        if self.pg_stat_statements {
+            settings.push((
+                "shared_preload_libraries".to_owned(),
+                "pg_stat_statements".to_owned(),
+            ));
+        }
+        if let Some(workers) = self.parallel_workers {
+            for (name, value) in [
+                ("max_worker_processes", (workers + 8).to_string()),
+                ("max_parallel_workers", workers.to_string()),
+                ("max_parallel_workers_per_gather", workers.to_string()),
+                ("parallel_setup_cost", "0".to_owned()),
+                ("parallel_tuple_cost", "0".to_owned()),
+                ("min_parallel_table_scan_size", "0".to_owned()),
+                ("min_parallel_index_scan_size", "0".to_owned()),
+            ] {
+                settings.push((name.to_owned(), value));
+            }
+        }
+        if self.force_parallel_mode {
+            settings.push(("force_parallel_mode".to_owned(), "on".to_owned()));
+        }
+        if self.tls.is_some() {
+            for &(name, value) in &[
+                ("ssl", "on"),
+                ("ssl_cert_file", "/etc/ssl/certs/ssl-cert-snakeoil.pem"),
+                ("ssl_key_file", "/etc/ssl/private/ssl-cert-snakeoil.key"),
+            ] {
+                settings.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        settings.extend(self.settings.iter().cloned());
+        settings
+    }
+
+    /// What tells the [`shared_postgres`] containers apart: everything that
+    /// changes how the server is set up or reached, but not the hooks.
+    fn shared_key(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                &self.docker_image,
+                &self.platform,
+                &self.host,
+                self.ipv6,
+                &self.stop_signal,
+                &self.restart_policy,
+                self.server_settings(),
+                &self.restore_snapshot,
+                self.tmpfs_tablespace,
+            )
+        )
+    }
+}
+
+/// Whether the docker daemon (from `DOCKER_HOST`, or the default socket)
+/// answers a ping, e.g. to skip container based tests where docker isn't
+/// installed or running with `if !docker_available() { return; }`.
+pub fn docker_available() -> bool {
+    match dockworker::Docker::connect_with_defaults().and_then(|docker| docker.ping()) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("Docker isn't available: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Create a temporary postgres:11 docker instance and pass the params
+/// required to create a connection to the closure, along with the postgres
+/// connection used to create this instance if you don't want to create
+/// your own connection.
+///
+/// Takes the image used as a base as a parameter
+pub fn with_temporary_postgres<T, F: FnOnce(ConnectParams, TlsMode, Connection) -> T>(
+    docker_image: &str,
+    f: F,
+) -> Result<T> {
+    with_temporary_postgres_config(&PostgresConfig::new(docker_image), f)
+}
+
+/// Like [`with_temporary_postgres`], but with the container customized by
+/// `config`.
+pub fn with_temporary_postgres_config<T, F: FnOnce(ConnectParams, TlsMode, Connection) -> T>(
+    config: &PostgresConfig,
+    f: F,
+) -> Result<T> {
+    let (container, connection) = PostgresContainer::start_ready(config, None)?;
+    let result = f(container.params.clone(), config.tls_mode(), connection);
+    let finished = (|| -> Result<()> {
+        if let Some(ref hook) = config.stats_hook {
+            match container_stats(&container.docker, &container.container) {
+                Ok(stats) => {
+                    info!("Postgres container used {:?}", stats);
+                    (hook.0)(&stats);
+                }
+                Err(err) => warn!("Failed to collect container stats: {:?}", err),
+            }
+        }
+        if let Some(ref path) = config.snapshot {
+            take_snapshot(
+                &container.docker,
+                &container.container.Id,
+                config,
+                &container.params,
+                &config.tls_mode(),
+                path,
+            )?;
+        }
+        Ok(())
+    })();
+    container.stop()?;
+    finished?;
+    Ok(result)
+}
+
+/// Start a temporary container like [`with_temporary_postgres_config`] and
+/// create a temporary database inside it like
+/// [`with_temporary_database_info`](crate::with_temporary_database_info).
+///
+/// The setup files of `database_config` are read while waiting for the
+/// container to be ready, so large migration sets don't add to the time
+/// afterwards. Files that fail to read are read again during setup, so errors
+/// are reported just as without the overlap.
+pub fn with_temporary_postgres_database<T, F: FnOnce(TempDbInfo, TlsMode) -> T>(
+    config: &PostgresConfig,
+    database_config: &DatabaseConfig,
+    f: F,
+) -> Result<T> {
+    let files = database_config.setup_files();
+    let reader = std::thread::spawn(move || {
+        files
+            .into_iter()
+            .filter_map(|path| {
+                let sql = std::fs::read_to_string(&path).ok()?;
+                Some((path, sql))
+            })
+            .collect::<HashMap<_, _>>()
+    });
+    with_temporary_postgres_config(config, |params, tls_mode, connection| {
+        // Not needed, and it would keep the admin database busy.
+        drop(connection);
+        let read = reader.join().unwrap_or_default();
+        debug!(
+            "Read {} setup files while the container started",
+            read.len()
+        );
+        let mut database_config = database_config.with_setup_files_read(read);
+        if config.tmpfs_tablespace && database_config.tablespace.is_none() {
+            database_config.tablespace(TMPFS_TABLESPACE);
+        }
+        crate::with_temporary_database_info(&params, &tls_mode, &database_config, f)
+    })?
+}
+
+/// The label holding the config key of a [`shared_postgres`] container.
+const SHARED_LABEL: &str = "kpg_fixture.shared";
+
+/// The key and params of the container found or started by the first
+/// [`shared_postgres`] call. The container itself is leaked, since it can't
+/// be shared between threads, and found again by its name in later runs.
+static SHARED_CONTAINER: Mutex<Option<(String, ConnectParams)>> = Mutex::new(None);
+
+/// Params to connect as the superuser to a container shared by the whole
+/// process, found or started by the first call. Later calls must pass the
+/// same config, except for the hooks, or fail with [`Error::InvalidConfig`].
+///
+/// The container is named after its config and never stopped, so the next
+/// run with the same config reuses it, along with whatever the tests left in
+/// it, instead of leaving another one behind each time. Remove it by hand
+/// (`docker rm -f`) to start over, e.g. after changing image tags. Its
+/// [`max_lifetime`](PostgresConfig::max_lifetime) only counts while the
+/// process which started it runs.
+///
+/// Concurrent first calls wait for the one starting the container. If
+/// starting it fails, the next call tries again.
+pub fn shared_postgres(config: &PostgresConfig) -> Result<ConnectParams> {
+    let mut shared = SHARED_CONTAINER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let key = config.shared_key();
+    if let Some((ref shared_key, ref params)) = *shared {
+        if *shared_key != key {
+            return Err(Error::InvalidConfig(
+                "shared_postgres was already called with another config".to_owned(),
+            ));
+        }
+        return Ok(params.clone());
+    }
+    let name = shared_container_name(&key);
+    let params = match find_shared_container(config, &name, &key)? {
+        Some(params) => params,
+        None => match PostgresContainer::start_ready(config, Some(&name)) {
+            Ok((container, _)) => {
+                let params = container.params().clone();
+                std::mem::forget(container);
+                params
+            }
+            // Another process may have just created it.
+            Err(err) => find_shared_container(config, &name, &key)?.ok_or(err)?,
+        },
+    };
+    *shared = Some((key, params.clone()));
+    Ok(params)
+}
+
+/// The name of the [`shared_postgres`] container for `key`, from its FNV-1a
+/// hash, which unlike `DefaultHasher` is the same in every build.
+fn shared_container_name(key: &str) -> String {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("kpg_fixture_shared_{:016x}", hash)
+}
+
+/// Params of the running container `name` left by an earlier run, once it is
+/// ready. One which stopped, e.g. when the docker daemon restarted, is
+/// removed so it can be created again.
+fn find_shared_container(
+    config: &PostgresConfig,
+    name: &str,
+    key: &str,
+) -> Result<Option<ConnectParams>> {
+    let docker = dockworker::Docker::connect_with_defaults()?;
+    let mut filters = dockworker::container::ContainerFilters::new();
+    filters.name(name);
+    let containers = docker.list_containers(Some(true), None, None, filters)?;
+    // The filter also matches longer names.
+    let container = match containers
+        .into_iter()
+        .find(|c| c.Names.iter().any(|n| n.trim_start_matches('/') == name))
+    {
+        Some(container) => container,
+        None => return Ok(None),
+    };
+    let label = container
+        .Labels
+        .as_ref()
+        .and_then(|labels| labels.get(SHARED_LABEL));
+    if label.map(String::as_str) != Some(key) {
+        return Err(Error::InvalidConfig(format!(
+            "container {} was started with another config",
+            name
+        )));
+    }
+    if !container.Status.starts_with("Up") {
+        if container.Status.starts_with("Exited") || container.Status.starts_with("Dead") {
+            info!("Removing stopped shared container {}", name);
+            docker.remove_container(&container.Id, None, Some(true), None)?;
+        }
+        return Ok(None);
+    }
+    let params = superuser_params(config, published_port(&docker, &container, config.ipv6)?);
+    wait_until_ready(config, &params, &config.tls_mode())?;
+    info!("Reusing shared container {}", name);
+    Ok(Some(params))
+}
+
+/// The lowest latency option for suites with many small tests: call `f` with
+/// a connection to a [`TemporarySchema`](crate::TemporarySchema) in the
+/// `postgres` database of the [`shared_postgres`] container. Only the first
+/// test pays for starting the container; every test after that only creates
+/// and drops a schema.
+///
+/// See [`TemporarySchema`](crate::TemporarySchema) for how this is less
+/// isolated than [`with_temporary_postgres_database`], which gives every test
+/// a container and database of its own. The connection uses the
+/// [`tls`](PostgresConfig::tls) handshake of `config`, if any.
+pub fn with_shared_postgres_schema<T, F: FnOnce(&Connection, &str) -> T>(
+    config: &PostgresConfig,
+    f: F,
+) -> Result<T> {
+    let params = shared_postgres(config)?;
+    crate::with_temporary_schema(params, config.tls_mode(), f)
+}
+
+/// A running temporary postgres container, which is stopped and removed when
+/// dropped.
+///
+/// Unlike [`with_temporary_postgres_config`] this lets one container be shared,
+/// e.g. by creating a temporary database in it for each test. A container in a
+/// `static` is never dropped, so it is left running after the tests exit.
+pub struct PostgresContainer {
+    docker: dockworker::Docker,
+    container: dockworker::container::Container,
+    config: PostgresConfig,
+    params: ConnectParams,
+    /// Keys passed to `once_per_container` whose setup succeeded.
+    setup_done: Mutex<HashSet<String>>,
+    watchdog: Option<Watchdog>,
+    removed: bool,
+}
+
+/// Force removes a container from a background thread once it has been
+/// around for too long, unless cancelled first.
+struct Watchdog {
+    cancel: mpsc::Sender<()>,
+    fired: Arc<AtomicBool>,
+    max_lifetime: Duration,
+}
+
+impl Watchdog {
+    fn start(container_id: String, max_lifetime: Duration) -> Watchdog {
+        let (cancel, cancelled) = mpsc::channel();
+        let fired = Arc::new(AtomicBool::new(false));
+        let watchdog = Watchdog {
+            cancel,
+            fired: fired.clone(),
+            max_lifetime,
+        };
+        std::thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = cancelled.recv_timeout(max_lifetime) {
+                fired.store(true, Ordering::SeqCst);
+                error!(
+                    "Container {} outlived {:?}, removing it",
+                    container_id, max_lifetime
+                );
+                let removed = dockworker::Docker::connect_with_defaults().and_then(|docker| {
+                    docker.remove_container(&container_id, None, Some(true), None)
+                });
+                if let Err(err) = removed {
+                    warn!("Failed to remove container {}: {:?}", container_id, err);
+                }
+            }
+        });
+        watchdog
+    }
+
+    /// Stop watching, failing if the container was already removed.
+    fn cancel(&self) -> Result<()> {
+        // Fails if the thread already fired and exited, which is checked next.
+        let _ = self.cancel.send(());
+        if self.fired.load(Ordering::SeqCst) {
+            return Err(Error::LifetimeExceeded(self.max_lifetime));
+        }
+        Ok(())
+    }
+}
+
+impl PostgresContainer {
+    /// Create and start a container, waiting until it is ready.
+    pub fn start(config: &PostgresConfig) -> Result<PostgresContainer> {
+        Ok(PostgresContainer::start_ready(config, None)?.0)
+    }
+
+    /// Like [`start`](#method.start), also returning the connection which
+    /// showed that the server is ready. A `shared_name` names and labels the
+    /// container for [`shared_postgres`].
+    fn start_ready(
+        config: &PostgresConfig,
+        shared_name: Option<&str>,
+    ) -> Result<(PostgresContainer, Connection)> {
+        if config.tmpfs_tablespace && config.restore_snapshot.is_some() {
+            return Err(Error::InvalidConfig(
+                "tmpfs_tablespace and restore_snapshot can't both be set".to_owned(),
+            ));
+        }
+        let docker = dockworker::Docker::connect_with_defaults()?;
+        if let Some(ref platform) = config.platform {
+            check_platform(&docker, &config.docker_image, platform)?;
+        }
+
+        let mut container_host_config = dockworker::ContainerHostConfig::new();
+        container_host_config.publish_all_ports(true);
+        if let Some((ref name, maximum_retry_count)) = config.restart_policy {
+            container_host_config.restart_policy(dockworker::RestartPolicy::new(
+                name.clone(),
+                maximum_retry_count,
+            ));
+        }
+        if config.tmpfs_tablespace {
+            // World writable, since the uid of the image's postgres user
+            // varies. The server creates the actual location itself below.
+            container_host_config.tmpfs(TMPFS_TABLESPACE_MOUNT, "rw,mode=1777");
+        }
+        let mut create_options = dockworker::ContainerCreateOptions::new(&config.docker_image);
+        create_options.host_config(container_host_config);
+        if let Some(ref signal) = config.stop_signal {
+            create_options.stop_signal(signal.clone());
+        }
+        let settings = config.server_settings();
+        if !settings.is_empty() {
+            // Replaces the image's CMD, which is just `postgres`.
+            create_options.cmd("postgres".to_owned());
+            for (name, value) in settings {
+                create_options.cmd("-c".to_owned());
+                create_options.cmd(format!("{}={}", name, value));
+            }
+        }
+        if shared_name.is_some() {
+            create_options.label(SHARED_LABEL.to_owned(), config.shared_key());
+        }
+        let container_id = docker.create_container(shared_name, &create_options)?.id;
+        let watchdog = config
+            .max_lifetime
+            .map(|max_lifetime| Watchdog::start(container_id.clone(), max_lifetime));
+
+        let started = (|| -> Result<_> {
+            if let Some(ref path) = config.restore_snapshot {
+                // The archive contains the `data` directory itself.
+                debug!("Restoring snapshot {:?}", path);
+                let parent = Path::new(DATA_DIRECTORY).parent().unwrap();
+                docker.put_file(&container_id, path, parent, false)?;
+            }
+            docker.start_container(&container_id)?;
+
+            let mut filters = dockworker::container::ContainerFilters::new();
+            filters.id(&container_id);
+            let container = docker.list_containers(None, None, None, filters)?;
+
+            let container = container.first().unwrap().clone();
+
+            let postgres_port = published_port(&docker, &container, config.ipv6)?;
+
+            info!("Postgres at port {} created", postgres_port);
+            if config.wait_for_healthcheck {
+                wait_until_healthy(&docker, &container, config.poll_interval)?;
+            }
+            if let Some(ref pattern) = config.ready_log_pattern {
+                wait_for_log(&docker, &container, pattern, config.poll_interval)?;
+            }
+            let connect_params = superuser_params(config, postgres_port);
+
+            let connection = wait_until_ready(config, &connect_params, &config.tls_mode())?;
+            if config.tmpfs_tablespace {
+                create_tmpfs_tablespace(&connection)?;
+            }
+            Ok((container, connect_params, connection))
+        })();
+        match started {
+            Ok((container, params, connection)) => Ok((
+                PostgresContainer {
+                    docker,
+                    container,
+                    config: config.clone(),
+                    params,
+                    setup_done: Mutex::new(HashSet::new()),
+                    watchdog,
+                    removed: false,
+                },
+                connection,
+            )),
+            Err(err) => {
+                if let Some(watchdog) = watchdog {
+                    watchdog.cancel()?;
+                }
+                if let Err(remove_err) =
+                    docker.remove_container(&container_id, None, Some(true), None)
+                {
+                    warn!(
+                        "Failed to remove container {}: {:?}",
+                        container_id, remove_err
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Params to connect to the `postgres` database as the superuser.
+    pub fn params(&self) -> &ConnectParams {
+        &self.params
+    }
+
+    /// The container's TlsMode: `TlsMode::None`, or `TlsMode::Require` with
+    /// the handshake of [`PostgresConfig::tls`].
+    pub fn tls_mode(&self) -> TlsMode<'_> {
+        self.config.tls_mode()
+    }
+
+    /// Connect to the `postgres` database as the superuser.
+    pub fn connect(&self) -> Result<Connection> {
+        self.connect_database("postgres")
+    }
+
+    /// Connect to `database` as the superuser, e.g. to `template1` to install
+    /// something every database created afterwards gets.
+    pub fn connect_database(&self, database: &str) -> Result<Connection> {
+        Ok(hook::connect(
+            self.config.connect_hook.as_ref(),
+            ConnectionPurpose::ContainerAdmin,
+            &crate::params_with_database(&self.params, Some(database)),
+            &self.tls_mode(),
+        )?)
+    }
+
+    /// Everything the container has logged so far, stdout and stderr
+    /// interleaved.
+    pub fn logs(&self) -> Result<String> {
+        container_logs(&self.docker, &self.container)
+    }
+
+    /// The connections and disconnections the server has logged so far, in
+    /// order, including the ones made while starting the container. Needs
+    /// [`PostgresConfig::log_connections`], and the English messages.
+    ///
+    /// A disconnection is logged by the server process once it exits, which
+    /// can be a moment after the client closed the connection.
+    pub fn connection_events(&self) -> Result<Vec<ConnectionEvent>> {
+        Ok(self
+            .logs()?
+            .lines()
+            .filter_map(ConnectionEvent::parse)
+            .collect())
+    }
+
+    /// Run `setup` with a superuser connection to the `postgres` database,
+    /// unless it already succeeded for the same `key` on this container, e.g.
+    /// to create a shared role or install an extension into `template1` once
+    /// for all the tests sharing the container.
+    ///
+    /// Calls are serialized, so concurrent tests wait for the first one's
+    /// setup instead of running it again. If `setup` fails, the next call for
+    /// the key runs it again.
+    pub fn once_per_container<F>(&self, key: &str, setup: F) -> Result<()>
+    where
+        F: FnOnce(&Connection) -> Result<()>,
+    {
+        // A panicking setup didn't get to record its key, so the set is fine.
+        let mut setup_done = self
+            .setup_done
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if setup_done.contains(key) {
+            return Ok(());
+        }
+        debug!(
+            "Running setup {:?} for container {}",
+            key, self.container.Id
+        );
+        setup(&self.connect()?)?;
+        setup_done.insert(key.to_owned());
+        Ok(())
+    }
+
+    /// Stop and remove the container, reporting errors that dropping it would
+    /// only log.
+    pub fn stop(mut self) -> Result<()> {
+        self.remove()
+    }
+
+    fn remove(&mut self) -> Result<()> {
+        self.removed = true;
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.cancel()?;
+        }
+        let stopped = self
+            .docker
+            .stop_container(&self.container.Id, Duration::from_secs(5));
+        self.docker
+            .remove_container(&self.container.Id, None, Some(true), None)?;
+        stopped?;
+        Ok(())
+    }
+}
+
+impl Drop for PostgresContainer {
+    fn drop(&mut self) {
+        if !self.removed {
+            if let Err(err) = self.remove() {
+                warn!(
+                    "Failed to remove container {}: {:?}",
+                    self.container.Id, err
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PostgresContainer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PostgresContainer")
+            .field("id", &self.container.Id)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+/// Params to connect to the `postgres` database as the superuser, at `port`
+/// of the config's host.
+fn superuser_params(config: &PostgresConfig, port: u16) -> ConnectParams {
+    ConnectParams::builder()
+        .port(port)
+        // .user("postgres", Some("postgres"))
+        .user("postgres", None)
+        .database("postgres")
+        .build(params::Host::Tcp(config.host.clone()))
+}
+
+/// The host port that the container's 5432 is published on, preferring an
+/// IPv6 binding if `ipv6`.
+fn published_port(
+    docker: &dockworker::Docker,
+    container: &dockworker::container::Container,
+    ipv6: bool,
+) -> Result<u16> {
+    let is_ipv6 = |ip: &str| ip.contains(':');
+    let mut ports: Vec<_> = container
+        .Ports
+        .iter()
+        .filter(|p| p.PrivatePort == 5432)
+        .collect();
+    if ipv6 {
+        // Prefer the IPv6 binding, if docker lists it separately.
+        ports.sort_by_key(|p| !p.IP.as_deref().is_some_and(is_ipv6));
+    }
+    if let Some(port) = ports.into_iter().flat_map(|p| p.PublicPort).next() {
+        return Ok(port as u16);
+    }
+
+    // Some setups (e.g. Docker Desktop, some network modes) leave PublicPort
+    // out of the container list even though the port is published, but it
+    // still shows up when inspecting the container.
+    debug!("No PublicPort listed, falling back to NetworkSettings.Ports");
+    let info = docker.container_info(container)?;
+    let mut mappings: Vec<_> = info
+        .NetworkSettings
+        .Ports
+        .as_ref()
+        .and_then(|ports| ports.get("5432/tcp"))
+        .and_then(|mappings| mappings.as_ref())
+        .map(|mappings| mappings.iter().collect())
+        .unwrap_or_default();
+    if ipv6 {
+        mappings.sort_by_key(|m| !is_ipv6(&m.HostIp));
+    }
+    mappings
+        .into_iter()
+        .flat_map(|m| m.HostPort.parse().ok())
+        .next()
+        .ok_or(Error::DockerCreationFailed("Failed to find postgres port"))
+}
+
+/// Take a single sample from the stats stream of `container`.
+fn container_stats(
+    docker: &dockworker::Docker,
+    container: &dockworker::container::Container,
+) -> Result<ContainerStats> {
+    // dockworker's own parsing needs fields docker leaves out on cgroup v2
+    // hosts, so read the JSON it failed on instead.
+    let stats: serde_json::Value = match docker.stats(container)?.next() {
+        Some(Ok(stats)) => serde_json::to_value(&stats),
+        Some(Err(err)) => match err.kind() {
+            dockworker::errors::ErrorKind::ParseError { input } => serde_json::from_str(input),
+            _ => return Err(err.into()),
+        },
+        None => return Err(Error::DockerCreationFailed("Empty container stats stream")),
+    }
+    .map_err(|_| Error::DockerCreationFailed("Invalid container stats"))?;
+    parse_stats(&stats)
+}
+
+/// The [`ContainerStats`] in a sample of the stats API, read leniently since
+/// cgroup v2 hosts leave out the cgroup v1 fields, `max_usage` included.
+fn parse_stats(stats: &serde_json::Value) -> Result<ContainerStats> {
+    let field = |path: &[&str]| {
+        path.iter()
+            .try_fold(stats, |value, key| value.get(key))
+            .and_then(serde_json::Value::as_u64)
+    };
+    let required = |path: &[&str]| {
+        field(path).ok_or(Error::DockerCreationFailed("Incomplete container stats"))
+    };
+    let usage = required(&["memory_stats", "usage"])?;
+    Ok(ContainerStats {
+        peak_memory: field(&["memory_stats", "max_usage"])
+            .filter(|&max_usage| max_usage > 0)
+            .unwrap_or(usage),
+        memory_limit: required(&["memory_stats", "limit"])?,
+        cpu_time: Duration::from_nanos(required(&["cpu_stats", "cpu_usage", "total_usage"])?),
+    })
+}
+
+/// Save the data directory of the running container `container_id` to `path`.
+fn take_snapshot(
+    docker: &dockworker::Docker,
+    container_id: &str,
+    config: &PostgresConfig,
+    connect_params: &ConnectParams,
+    tls_mode: &TlsMode,
+    path: &Path,
+) -> Result<()> {
+    if config.checkpoint_before_snapshot {
+        let conn = hook::connect(
+            config.connect_hook.as_ref(),
+            ConnectionPurpose::Snapshot,
+            connect_params,
+            tls_mode,
+        )?;
+        conn.batch_execute("CHECKPOINT;")?;
+    }
+    debug!("Saving snapshot to {:?}", path);
+    let archive = docker.get_file(container_id, Path::new(DATA_DIRECTORY))?;
+    let mut file = std::fs::File::create(path)?;
+    std::io::copy(&mut archive.into_inner(), &mut file)?;
+    Ok(())
+}
+
+/// Fail unless the local `image` is for `platform`, ignoring any variant
+/// like the `v8` of `linux/arm64/v8`.
+fn check_platform(docker: &dockworker::Docker, image: &str, platform: &str) -> Result<()> {
+    let inspected = docker.inspect_image(image)?;
+    let actual = format!("{}/{}", inspected.Os, inspected.Architecture);
+    let wanted = platform
+        .splitn(3, '/')
+        .take(2)
+        .collect::<Vec<_>>()
+        .join("/");
+    if actual != wanted {
+        return Err(Error::InvalidConfig(format!(
+            "Image {} is for {}, not {}; pull it with `docker pull --platform {} {}`",
+            image, actual, platform, platform, image
+        )));
+    }
+    Ok(())
+}
+
+/// Create [`TMPFS_TABLESPACE`] in a directory of the tmpfs mount. The
+/// directory is made by the server (as a superuser program), so that it is
+/// owned by the user postgres runs as, which CREATE TABLESPACE insists on.
+fn create_tmpfs_tablespace(conn: &Connection) -> Result<()> {
+    let location = format!("{}/data", TMPFS_TABLESPACE_MOUNT);
+    conn.batch_execute(&format!(
+        "COPY (SELECT 1) TO PROGRAM {};",
+        crate::quote_literal(&format!("mkdir -m 0700 {}", location))
+    ))?;
+    conn.batch_execute(&format!(
+        "CREATE TABLESPACE {} LOCATION {};",
+        crate::quote_ident(TMPFS_TABLESPACE),
+        crate::quote_literal(&location)
+    ))?;
+    debug!(
+        "Created tablespace {:?} at {:?}",
+        TMPFS_TABLESPACE, location
+    );
+    Ok(())
+}
+
+/// Poll docker until the container's healthcheck reports it healthy.
+fn wait_until_healthy(
+    docker: &dockworker::Docker,
+    container: &dockworker::container::Container,
+    interval: Duration,
+) -> Result<()> {
+    use dockworker::container::HealthState;
+
+    let max_attempts = (HEALTHY_TIMEOUT.as_nanos() / interval.as_nanos().max(1)).max(1);
+    for _ in 0..max_attempts {
+        let health = docker.container_info(container)?.State.Health;
+        match health.map(|health| health.Status) {
+            Some(HealthState::Healthy) => return Ok(()),
+            Some(HealthState::Starting) => trace!("Container not healthy yet"),
+            Some(HealthState::Unhealthy) => {
+                return Err(Error::DockerCreationFailed("Container became unhealthy"))
+            }
+            Some(HealthState::NoHealthcheck) | None => {
+                return Err(Error::DockerCreationFailed("Image has no HEALTHCHECK"))
+            }
+        }
+        std::thread::sleep(interval);
+    }
+    Err(Error::DockerCreationFailed(
+        "Container didn't become healthy in time",
+    ))
+}
+
+/// The container's logs so far.
+fn container_logs(
+    docker: &dockworker::Docker,
+    container: &dockworker::container::Container,
+) -> Result<String> {
+    let mut logs = Vec::new();
+    docker
+        .log_container(&container.Id, &dockworker::ContainerLogOptions::default())?
+        .read_to_end(&mut logs)?;
+    // Lossy since the stream is interleaved with binary frame headers.
+    Ok(String::from_utf8_lossy(&logs).into_owned())
+}
+
+/// Poll the container's logs until a line contains `pattern`.
+fn wait_for_log(
+    docker: &dockworker::Docker,
+    container: &dockworker::container::Container,
+    pattern: &str,
+    interval: Duration,
+) -> Result<()> {
+    let max_attempts = (READY_TIMEOUT.as_nanos() / interval.as_nanos().max(1)).max(1);
+    for _ in 0..max_attempts {
+        if container_logs(docker, container)?.contains(pattern) {
+            return Ok(());
+        }
+        trace!("Postgres hasn't logged {:?} yet", pattern);
+        std::thread::sleep(interval);
+    }
+    Err(Error::DockerCreationFailed(
+        "Container didn't log the ready pattern in time",
+    ))
+}
+
+/// Poll the freshly started server until it hands out a usable connection.
+fn wait_until_ready(
+    config: &PostgresConfig,
+    connect_params: &ConnectParams,
+    tls_mode: &TlsMode,
+) -> Result<Connection> {
+    let try_connect = || -> postgres::Result<Connection> {
+        let conn = hook::connect(
+            config.connect_hook.as_ref(),
+            ConnectionPurpose::Readiness,
+            connect_params,
+            tls_mode,
+        )?;
+        if config.verify_query_on_ready {
+            conn.batch_execute("SELECT 1")?;
+        }
+        Ok(conn)
+    };
+
+    let interval = config.poll_interval;
+    let max_attempts = (READY_TIMEOUT.as_nanos() / interval.as_nanos().max(1)).max(1);
+    let mut n = 0;
+    loop {
+        n += 1;
+        match try_connect() {
+            Ok(conn) => break Ok(conn),
+            // TODO timeouterror
+            Err(err) => {
+                if n >= max_attempts {
+                    break Err(err.into());
+                }
+                trace!("Postgres not ready yet: {}", err);
+            }
+        }
+
+        std::thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_logical_replication_slot, drop_replication_slot};
+
+    #[test]
+    fn temp_pg_logical_replication_slot() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.logical_replication(true);
+        with_temporary_postgres_config(&config, |_, _, conn| -> Result<()> {
+            let wal_level: String = conn.query("SHOW wal_level", &[])?.get(0).get(0);
+            assert_eq!(wal_level, "logical");
+            create_logical_replication_slot(&conn, "kpg_fixture_slot", "test_decoding")?;
+            drop_replication_slot(&conn, "kpg_fixture_slot")?;
+            Ok(())
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn connection_event_lines() {
+        assert_eq!(
+            ConnectionEvent::parse(
+                "2024-01-01 00:00:00.000 UTC [63] LOG:  connection authorized: user=app database=test application_name=x"
+            ),
+            Some(ConnectionEvent {
+                kind: ConnectionEventKind::Connected,
+                user: "app".to_owned(),
+                database: "test".to_owned(),
+            })
+        );
+        assert_eq!(
+            ConnectionEvent::parse(
+                "2024-01-01 00:00:00.000 UTC [63] LOG:  disconnection: session time: 0:00:00.012 user=app database=test host=172.17.0.1 port=50000"
+            ),
+            Some(ConnectionEvent {
+                kind: ConnectionEventKind::Disconnected,
+                user: "app".to_owned(),
+                database: "test".to_owned(),
+            })
+        );
+        assert_eq!(
+            ConnectionEvent::parse(
+                "2024-01-01 00:00:00.000 UTC [63] LOG:  connection received: host=172.17.0.1 port=50000"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_cgroup_v1_and_v2_stats() {
+        let v1 = serde_json::json!({
+            "memory_stats": {"max_usage": 300, "usage": 200, "limit": 1000, "stats": {}},
+            "cpu_stats": {"cpu_usage": {"total_usage": 5_000_000}},
+        });
+        let expected = ContainerStats {
+            peak_memory: 300,
+            memory_limit: 1000,
+            cpu_time: Duration::from_millis(5),
+        };
+        assert_eq!(parse_stats(&v1).unwrap(), expected);
+        // No max_usage, and none of the v1 memory stats.
+        let v2 = serde_json::json!({
+            "memory_stats": {"usage": 200, "limit": 1000, "stats": {"anon": 100}},
+            "cpu_stats": {"cpu_usage": {"total_usage": 5_000_000}},
+        });
+        assert_eq!(
+            parse_stats(&v2).unwrap(),
+            ContainerStats {
+                peak_memory: 200,
+                ..expected
+            }
+        );
+        assert!(parse_stats(&serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn temp_pg_connection_events() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.log_connections(true);
+        let container =
+            PostgresContainer::start(&config).expect("Failed to create temporary postgres");
+        let database = crate::TemporaryDatabase::create(
+            container.params(),
+            container.tls_mode(),
+            &DatabaseConfig::new(),
+        )
+        .unwrap();
+        for _ in 0..3 {
+            Connection::connect(database.params().clone(), container.tls_mode())
+                .unwrap()
+                .finish()
+                .unwrap();
+        }
+        // Only the ones above connect as the temporary role.
+        let info = database.info();
+        let count = |kind| -> usize {
+            container
+                .connection_events()
+                .unwrap()
+                .iter()
+                .filter(|event| {
+                    event.kind == kind && event.user == info.role && event.database == info.database
+                })
+                .count()
+        };
+        assert_eq!(count(ConnectionEventKind::Connected), 3);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while count(ConnectionEventKind::Disconnected) < 3 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(count(ConnectionEventKind::Disconnected), 3);
+        database.drop_database().unwrap();
+        container.stop().unwrap();
+    }
+
+    #[test]
+    fn shared_container_once_per_container() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let container = PostgresContainer::start(&PostgresConfig::new("postgres:11"))
+            .expect("Failed to create temporary postgres");
+        let runs = AtomicUsize::new(0);
+        for _ in 0..3 {
+            container
+                .once_per_container("shared role", |conn| {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    conn.batch_execute("CREATE ROLE shared_role")?;
+                    Ok(())
+                })
+                .unwrap();
+            crate::with_temporary_database(
+                container.params(),
+                container.tls_mode(),
+                |params, _| {
+                    let conn = Connection::connect(params, TlsMode::None).unwrap();
+                    let rows = conn
+                        .query("SELECT 1 FROM pg_roles WHERE rolname = 'shared_role'", &[])
+                        .unwrap();
+                    assert_eq!(rows.len(), 1);
+                },
+            )
+            .unwrap();
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        container.stop().unwrap();
+    }
+
+    #[test]
+    fn shared_postgres_schema() {
+        let config = PostgresConfig::new("postgres:11");
+        let schemas: Vec<String> = (0..2)
+            .map(|_| {
+                with_shared_postgres_schema(&config, |conn, schema| {
+                    conn.batch_execute("CREATE TABLE test (id int)").unwrap();
+                    schema.to_owned()
+                })
+                .expect("Failed to create temporary schema")
+            })
+            .collect();
+        assert_ne!(schemas[0], schemas[1]);
+        let conn = Connection::connect(shared_postgres(&config).unwrap(), TlsMode::None).unwrap();
+        let rows = conn
+            .query(
+                "SELECT 1 FROM pg_namespace WHERE nspname LIKE 'kpg\\_fixture\\_%'",
+                &[],
+            )
+            .unwrap();
+        assert!(rows.is_empty());
+
+        let mut other = config.clone();
+        other.max_connections(20);
+        match shared_postgres(&other) {
+            Err(Error::InvalidConfig(_)) => {}
+            other => panic!("Expected InvalidConfig, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn temp_pg_if_docker_available() {
+        if !docker_available() {
+            return;
+        }
+        with_temporary_postgres("postgres:11", |_, _, conn| {
+            conn.batch_execute("SELECT 1").unwrap();
+        })
+        .expect("Failed to create temporary postgres");
+    }
+
+    #[test]
+    fn temp_pg_statement_capture() {
+        let mut config = PostgresConfig::new("postgres:13");
+        config.pg_stat_statements(true);
+        with_temporary_postgres_config(&config, |_, _, conn| -> Result<()> {
+            conn.batch_execute("CREATE EXTENSION pg_stat_statements")?;
+            let ((), statements) = crate::with_statement_capture(&conn, || {
+                for i in 0..3 {
+                    conn.execute("SELECT $1::int", &[&i]).unwrap();
+                }
+            })?;
+            assert_eq!(statements.len(), 1, "{:#?}", statements);
+            assert_eq!(statements[0].query, "SELECT $1::int");
+            assert_eq!(statements[0].calls, 3);
+            Ok(())
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_database_with_setup_files() {
+        let path = std::env::temp_dir().join(format!("kpg_fixture_{}.sql", std::process::id()));
+        std::fs::write(
+            &path,
+            "CREATE TABLE test (id int); INSERT INTO test VALUES (42);",
+        )
+        .unwrap();
+        let mut database_config = DatabaseConfig::new();
+        database_config.setup_file(&path);
+        let id = with_temporary_postgres_database(
+            &PostgresConfig::new("postgres:11"),
+            &database_config,
+            |info, tls_mode| -> Result<i32> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                Ok(conn.query("TABLE test", &[])?.get(0).get(0))
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(id, 42);
+    }
+
+    #[test]
+    fn temp_pg_max_lifetime() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.max_lifetime(Duration::from_secs(15));
+        let result = with_temporary_postgres_config(&config, |_, _, conn| {
+            std::thread::sleep(Duration::from_secs(20));
+            conn.batch_execute("SELECT 1")
+        });
+        match result {
+            Err(Error::LifetimeExceeded(max_lifetime)) => {
+                assert_eq!(max_lifetime, Duration::from_secs(15))
+            }
+            other => panic!("Expected the watchdog to fire, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn temp_pg_stats() {
+        use std::sync::Mutex;
+
+        let collected = Arc::new(Mutex::new(None));
+        let mut config = PostgresConfig::new("postgres:11");
+        {
+            let collected = collected.clone();
+            config.on_stats(move |stats| *collected.lock().unwrap() = Some(*stats));
+        }
+        with_temporary_postgres_config(&config, |_, _, conn| {
+            conn.batch_execute("SELECT count(*) FROM generate_series(1, 100000)")
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+        let stats = collected.lock().unwrap().expect("No stats collected");
+        assert!(stats.peak_memory > 0);
+        assert!(stats.cpu_time > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn temp_pg_max_connections() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.max_connections(6);
+        with_temporary_postgres_database(
+            &config,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<()> {
+                let first =
+                    Connection::connect(info.params.clone(), crate::clone_tls_mode(&tls_mode))?;
+                let _second =
+                    Connection::connect(info.params.clone(), crate::clone_tls_mode(&tls_mode))?;
+                assert!(Connection::connect(info.params.clone(), tls_mode).is_err());
+                let max: String = first.query("SHOW max_connections", &[])?.get(0).get(0);
+                assert_eq!(max, "6");
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_parallel_workers() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.parallel_workers(4);
+        with_temporary_postgres_config(&config, |_, _, conn| -> Result<()> {
+            let per_gather: String = conn
+                .query("SHOW max_parallel_workers_per_gather", &[])?
+                .get(0)
+                .get(0);
+            assert_eq!(per_gather, "4");
+            conn.batch_execute(
+                "CREATE TABLE test AS SELECT i FROM generate_series(1, 1000) AS g (i); ANALYZE test;",
+            )?;
+            let plan: Vec<String> = conn
+                .query("EXPLAIN SELECT count(*) FROM test", &[])?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            assert!(plan.iter().any(|line| line.contains("Gather")), "{:#?}", plan);
+            Ok(())
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_ready_log_pattern() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.wait_for_ready_log(true);
+        with_temporary_postgres_config(&config, |_, _, conn| {
+            conn.batch_execute("SELECT 1").unwrap();
+        })
+        .expect("Failed to create temporary postgres");
+
+        config
+            .ready_log_pattern("never logged")
+            .poll_interval(Duration::from_secs(1));
+        match with_temporary_postgres_config(&config, |_, _, _| ()) {
+            Err(Error::DockerCreationFailed(_)) => {}
+            other => panic!("Expected DockerCreationFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn temp_pg_tls_handshake() {
+        static OFFERED: AtomicBool = AtomicBool::new(false);
+
+        /// Records that the server agreed to TLS, without completing it.
+        #[derive(Debug)]
+        struct Offered;
+
+        impl TlsHandshake for Offered {
+            fn tls_handshake(
+                &self,
+                _host: &str,
+                _stream: postgres::tls::Stream,
+            ) -> std::result::Result<
+                Box<dyn postgres::tls::TlsStream>,
+                Box<dyn std::error::Error + Sync + Send>,
+            > {
+                OFFERED.store(true, Ordering::SeqCst);
+                Err("not completing the handshake".into())
+            }
+        }
+
+        let mut config = PostgresConfig::new("postgres:11");
+        config.tls(Offered);
+        assert!(with_temporary_postgres_config(&config, |_, _, _| ()).is_err());
+        // Only asked to once the server answered the SSLRequest with yes.
+        assert!(OFFERED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn temp_pg_work_mem() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.work_mem("64kB");
+        with_temporary_postgres_database(
+            &config,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<()> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                assert_eq!(crate::show(&conn, "work_mem")?, "64kB");
+                let ((), spilled) = crate::with_spill_stats(&conn, || {
+                    conn.query(
+                        "SELECT count(*) FROM (
+                            SELECT i FROM generate_series(1, 100000) AS g (i) ORDER BY i DESC
+                        ) sorted",
+                        &[],
+                    )
+                    .unwrap();
+                })?;
+                assert!(spilled.spilled());
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_prepared_transactions() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.max_prepared_transactions(2);
+        with_temporary_postgres_database(
+            &config,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<()> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                conn.batch_execute("BEGIN; CREATE TABLE test ();")?;
+                crate::prepare_transaction(&conn, "left behind")?;
+                assert_eq!(crate::prepared_transactions(&conn)?.len(), 1);
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_max_locks_per_transaction() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.max_locks_per_transaction(1024);
+        with_temporary_postgres_database(
+            &config,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<()> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                assert_eq!(
+                    crate::show_as::<u32>(&conn, "max_locks_per_transaction")?,
+                    1024
+                );
+                // Each statement locks every one of them in a single transaction.
+                conn.batch_execute(
+                    "DO $$ BEGIN
+                        FOR i IN 1..8000 LOOP
+                            EXECUTE format('CREATE TABLE t%s (id int)', i);
+                        END LOOP;
+                    END $$;",
+                )?;
+                crate::truncate_all(&conn)?;
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_track_io_timing() {
+        let mut config = PostgresConfig::new("postgres:11");
+        // Small enough that scanning the table has to read most of it.
+        config
+            .track_io_timing(true)
+            .setting("shared_buffers", "128kB");
+        with_temporary_postgres_database(
+            &config,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<()> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                assert!(crate::show_bool(&conn, "track_io_timing")?);
+                conn.batch_execute(
+                    "CREATE TABLE test AS SELECT i FROM generate_series(1, 100000) AS g (i)",
+                )?;
+                let ((), io) = crate::with_io_stats(&conn, || {
+                    conn.query("SELECT count(*) FROM test", &[]).unwrap();
+                })?;
+                assert!(io.table("test").unwrap().blocks_read() > 0, "{:?}", io);
+                assert!(io.read_time > Duration::ZERO, "{:?}", io);
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_tmpfs_tablespace() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.tmpfs_tablespace(true);
+        with_temporary_postgres_database(
+            &config,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<()> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                conn.batch_execute("CREATE TABLE test (id int PRIMARY KEY)")?;
+                let rows = conn.query(
+                    "SELECT spcname::text FROM pg_database d
+                        JOIN pg_tablespace t ON t.oid = d.dattablespace
+                        WHERE datname = current_database()",
+                    &[],
+                )?;
+                assert_eq!(rows.get(0).get::<_, String>(0), TMPFS_TABLESPACE);
+                let location: String = conn
+                    .query("SELECT pg_relation_filepath('test')", &[])?
+                    .get(0)
+                    .get(0);
+                assert!(location.starts_with("pg_tblspc/"));
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_ipv6() {
+        let mut config = PostgresConfig::new("postgres:11");
+        config.ipv6(true).listen_addresses(&["0.0.0.0", "::"]);
+        with_temporary_postgres_config(&config, |params, _, conn| -> Result<()> {
+            assert_eq!(*params.host(), params::Host::Tcp("::1".to_owned()));
+            conn.batch_execute("SELECT 1")?;
+            Ok(())
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_pg_snapshot_restore() {
+        let path = std::env::temp_dir().join(format!("kpg_fixture_{}.tar", std::process::id()));
+        let mut config = PostgresConfig::new("postgres:11");
+        config.snapshot(&path);
+        with_temporary_postgres_config(&config, |_, _, conn| {
+            conn.batch_execute("CREATE TABLE test (id int); INSERT INTO test VALUES (42);")
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Inner result failed");
+
+        let mut config = PostgresConfig::new("postgres:11");
+        config.restore_snapshot(&path);
+        let id = with_temporary_postgres_config(&config, |_, _, conn| -> Result<i32> {
+            Ok(conn.query("TABLE test", &[])?.get(0).get(0))
+        })
+        .expect("Failed to restore temporary postgres")
+        .expect("Inner result failed");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(id, 42);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{quote_ident, quote_literal, Queryable, Result};
+use log::*;
+
+/// How [`generate_rows`] fills a column. `i` is the row's number, counting
+/// from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnGenerator {
+    /// `i` itself, e.g. for a primary key.
+    Series,
+    /// A random integer between `min` and `max`, inclusive.
+    RandomInt { min: i64, max: i64 },
+    /// A random float in [0, 1).
+    RandomFloat,
+    /// A random boolean.
+    RandomBool,
+    /// A random string of `length` hex digits.
+    RandomText { length: u32 },
+    /// One of these strings, picked at random.
+    Choice(Vec<String>),
+    /// A timestamp `i` seconds before now, so rows are ordered in time.
+    Timestamp,
+    /// Any SQL expression, which may refer to `i`.
+    Sql(String),
+}
+
+impl ColumnGenerator {
+    fn sql(&self) -> String {
+        match self {
+            ColumnGenerator::Series => "i".to_owned(),
+            ColumnGenerator::RandomInt { min, max } => format!(
+                "({min} + floor(random() * ({max}::numeric - {min} + 1)))::bigint",
+                min = min,
+                max = max
+            ),
+            ColumnGenerator::RandomFloat => "random()".to_owned(),
+            ColumnGenerator::RandomBool => "random() < 0.5".to_owned(),
+            ColumnGenerator::RandomText { length } => format!(
+                "left(repeat(md5(random()::text), {repeat}), {length})",
+                repeat = length / 32 + 1,
+                length = length
+            ),
+            ColumnGenerator::Choice(choices) => format!(
+                "(ARRAY[{}]::text[])[1 + floor(random() * {})::int]",
+                choices
+                    .iter()
+                    .map(|choice| quote_literal(choice))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                choices.len()
+            ),
+            ColumnGenerator::Timestamp => "now() - i * interval '1 second'".to_owned(),
+            ColumnGenerator::Sql(sql) => format!("({})", sql),
+        }
+    }
+}
+
+/// Insert `rows` rows into `table` in a single `INSERT ... SELECT FROM
+/// generate_series`, with each of `columns` filled by its generator and the
+/// others left to their defaults. Returns the number of rows inserted.
+///
+/// Much faster than inserting from the client, for quickly getting a table
+/// big enough for performance tests.
+pub fn generate_rows(
+    conn: &(impl Queryable + ?Sized),
+    table: &str,
+    rows: i64,
+    columns: &[(&str, ColumnGenerator)],
+) -> Result<u64> {
+    let names: Vec<String> = columns.iter().map(|(name, _)| quote_ident(name)).collect();
+    let values: Vec<String> = columns
+        .iter()
+        .map(|(_, generator)| generator.sql())
+        .collect();
+    let inserted = conn.execute(
+        &format!(
+            "INSERT INTO {} ({}) SELECT {} FROM generate_series(1, $1::bigint) AS g (i)",
+            quote_ident(table),
+            names.join(", "),
+            values.join(", ")
+        ),
+        &[&rows],
+    )?;
+    debug!("Generated {} rows in {:?}", inserted, table);
+    Ok(inserted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+    use crate::with_temporary_database;
+    use postgres::{Connection, TlsMode};
+
+    #[test]
+    fn generates_rows() {
+        let admin_params = admin_params();
+        with_temporary_database(admin_params, TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute(
+                "CREATE TABLE test (
+                    id bigint PRIMARY KEY, n int, x float8, b bool, t text, c text,
+                    at timestamptz, doubled bigint, untouched text DEFAULT 'default'
+                )",
+            )
+            .unwrap();
+            let inserted = generate_rows(
+                &conn,
+                "test",
+                1000,
+                &[
+                    ("id", ColumnGenerator::Series),
+                    ("n", ColumnGenerator::RandomInt { min: -2, max: 2 }),
+                    ("x", ColumnGenerator::RandomFloat),
+                    ("b", ColumnGenerator::RandomBool),
+                    ("t", ColumnGenerator::RandomText { length: 40 }),
+                    (
+                        "c",
+                        ColumnGenerator::Choice(vec!["a".to_owned(), "b'c".to_owned()]),
+                    ),
+                    ("at", ColumnGenerator::Timestamp),
+                    ("doubled", ColumnGenerator::Sql("i * 2".to_owned())),
+                ],
+            )
+            .unwrap();
+            assert_eq!(inserted, 1000);
+            let rows = conn
+                .query(
+                    "SELECT min(id) = 1 AND max(id) = 1000,
+                        min(n) >= -2 AND max(n) <= 2,
+                        bool_and(x >= 0 AND x < 1),
+                        bool_and(length(t) = 40),
+                        bool_and(c IN ('a', 'b''c')),
+                        bool_and(doubled = id * 2),
+                        bool_and(untouched = 'default')
+                    FROM test",
+                    &[],
+                )
+                .unwrap();
+            let row = rows.get(0);
+            for column in 0..row.len() {
+                assert!(row.get::<_, bool>(column), "Check {} failed", column);
+            }
+        })
+        .expect("Failed to create temporary database");
+    }
+}
@@ -0,0 +1,1302 @@
+use std::io::Read;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::*;
+use postgres::params::{ConnectParams, Host};
+use postgres::rows::Rows;
+use postgres::transaction::Transaction;
+use postgres::types::{FromSql, ToSql};
+use postgres::Connection;
+
+use crate::{quote_ident, quote_literal, Error, Queryable, Result};
+
+/// TRUNCATE every table outside the system schemas, restarting identity
+/// columns, so a database can be reused between tests without recreating it.
+///
+/// Partitions and inheritance children are left out of the list and covered
+/// by truncating their root table, which recurses into them, so a partition
+/// being named alongside its parent can't trip up the TRUNCATE.
+pub fn truncate_all(conn: &(impl Queryable + ?Sized)) -> Result<()> {
+    let rows = conn.query(
+        "SELECT format('%I.%I', n.nspname, c.relname)
+            FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE c.relkind IN ('r', 'p')
+                AND NOT c.relispartition
+                AND NOT EXISTS (SELECT 1 FROM pg_inherits i WHERE i.inhrelid = c.oid)
+                AND n.nspname NOT IN ('pg_catalog', 'information_schema')
+                AND n.nspname NOT LIKE 'pg\\_toast%'
+                AND n.nspname NOT LIKE 'pg\\_temp\\_%'
+            ORDER BY 1",
+        &[],
+    )?;
+    let tables: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    if tables.is_empty() {
+        return Ok(());
+    }
+    debug!("Truncating {:?}", tables);
+    conn.batch_execute(&format!(
+        "TRUNCATE {} RESTART IDENTITY CASCADE;",
+        tables.join(", ")
+    ))?;
+    Ok(())
+}
+
+/// Run `f` with `conn` switched to `role` using SET ROLE, e.g. to exercise
+/// row level security policies as a role created with
+/// [`DatabaseConfig::extra_role`](crate::DatabaseConfig::extra_role), then
+/// switch back.
+///
+/// `row_security` is turned on for the duration, so policies are applied
+/// rather than making queries fail if the session had turned it off, and set
+/// back to what it was afterwards. Both are also switched back if `f` panics.
+pub fn with_role<T, F: FnOnce() -> T>(
+    conn: &(impl Queryable + ?Sized),
+    role: &str,
+    f: F,
+) -> Result<T> {
+    let mut guard = RoleGuard {
+        conn,
+        row_security: show(conn, "row_security")?,
+        reset: false,
+    };
+    conn.batch_execute(&format!(
+        "SET ROLE {}; SET row_security = on;",
+        quote_ident(role)
+    ))?;
+    let result = f();
+    guard.reset()?;
+    Ok(result)
+}
+
+/// Switches the connection back from [`with_role`] when dropped, unless that
+/// was already done.
+struct RoleGuard<'a, Q: Queryable + ?Sized> {
+    conn: &'a Q,
+    row_security: String,
+    reset: bool,
+}
+
+impl<'a, Q: Queryable + ?Sized> RoleGuard<'a, Q> {
+    fn reset(&mut self) -> Result<()> {
+        self.reset = true;
+        self.conn.batch_execute(&format!(
+            "SET row_security = {}; RESET ROLE;",
+            quote_literal(&self.row_security)
+        ))?;
+        Ok(())
+    }
+}
+
+impl<'a, Q: Queryable + ?Sized> Drop for RoleGuard<'a, Q> {
+    fn drop(&mut self) {
+        if !self.reset {
+            if let Err(err) = self.reset() {
+                warn!("Failed to reset the role: {:?}", err);
+            }
+        }
+    }
+}
+
+/// A connection inside a transaction where every statement runs in a
+/// savepoint of its own, like psql's `ON_ERROR_ROLLBACK`: a failing statement
+/// is rolled back and returns its error, and the transaction carries on as if
+/// it hadn't run. See [`with_statement_savepoints`].
+#[derive(Debug)]
+pub struct StatementSavepoints<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> StatementSavepoints<'a> {
+    fn savepoint<T, F>(&self, f: F) -> postgres::Result<T>
+    where
+        F: FnOnce() -> postgres::Result<T>,
+    {
+        self.conn
+            .batch_execute("SAVEPOINT kpg_fixture_statement;")?;
+        match f() {
+            Ok(value) => {
+                self.conn
+                    .batch_execute("RELEASE SAVEPOINT kpg_fixture_statement;")?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.conn.batch_execute(
+                    "ROLLBACK TO SAVEPOINT kpg_fixture_statement;
+                    RELEASE SAVEPOINT kpg_fixture_statement;",
+                )?;
+                Err(err)
+            }
+        }
+    }
+
+    /// `Connection::execute` in a savepoint.
+    pub fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<u64> {
+        self.savepoint(|| self.conn.execute(sql, params))
+    }
+
+    /// `Connection::query` in a savepoint.
+    pub fn query(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<Rows> {
+        self.savepoint(|| self.conn.query(sql, params))
+    }
+
+    /// `Connection::batch_execute` in a savepoint. The whole batch is rolled
+    /// back if any statement in it fails.
+    pub fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        self.savepoint(|| self.conn.batch_execute(sql))
+    }
+
+    /// A `COPY ... FROM STDIN` reading its data from `reader`, in a
+    /// savepoint. Nothing is copied if any row is rejected.
+    pub fn copy_in(&self, sql: &str, mut reader: &mut dyn Read) -> postgres::Result<u64> {
+        self.savepoint(|| self.conn.prepare(sql)?.copy_in(&[], &mut reader))
+    }
+
+    /// The underlying connection, for statements which shouldn't get a
+    /// savepoint.
+    pub fn connection(&self) -> &'a Connection {
+        self.conn
+    }
+}
+
+/// Run `f` in a transaction on `conn` where every statement run through the
+/// [`StatementSavepoints`] gets a savepoint of its own, so individual failures
+/// (e.g. in generated or exploratory statements) don't abort the whole
+/// transaction. The transaction is committed afterwards, or rolled back if
+/// `f` panics.
+///
+/// Unlike most helpers this takes a bare [`Connection`], since it starts the
+/// transaction itself; inside a transaction, each statement can be given a
+/// savepoint with `Transaction::savepoint` instead.
+pub fn with_statement_savepoints<T, F>(conn: &Connection, f: F) -> Result<T>
+where
+    F: FnOnce(&StatementSavepoints) -> T,
+{
+    let transaction = conn.transaction()?;
+    let result = f(&StatementSavepoints { conn });
+    transaction.commit()?;
+    Ok(result)
+}
+
+/// Run `f` in a transaction on `conn`, committed if it returns `Ok` and rolled
+/// back if it returns `Err`, so a passing test's setup is kept (e.g. in a
+/// [`persistent_database`](crate::persistent_database) to look at afterwards)
+/// while a failing one leaves nothing half done.
+///
+/// The transaction is a guard which rolls back when dropped without being
+/// committed, so it is also rolled back if `f` panics. Errors committing are
+/// returned as the outer error, `f`'s own as the inner.
+pub fn with_committing_transaction<T, E, F>(
+    conn: &Connection,
+    f: F,
+) -> Result<std::result::Result<T, E>>
+where
+    F: FnOnce(&Transaction) -> std::result::Result<T, E>,
+{
+    let transaction = conn.transaction()?;
+    let result = f(&transaction);
+    if result.is_ok() {
+        transaction.commit()?;
+    } else {
+        debug!("Rolling back, the closure failed");
+        transaction.finish()?;
+    }
+    Ok(result)
+}
+
+/// Run `f` once for each of `cases`, each time in a fresh transaction on
+/// `conn` which is rolled back afterwards, so table driven tests sharing one
+/// database don't see each other's changes. Returns what `f` returned for
+/// each case, in order.
+///
+/// As with [`with_committing_transaction`], a case which panics has its
+/// transaction rolled back too.
+pub fn for_each_case<C, T, I, F>(conn: &Connection, cases: I, mut f: F) -> Result<Vec<T>>
+where
+    I: IntoIterator<Item = C>,
+    F: FnMut(&Transaction, C) -> T,
+{
+    let mut results = Vec::new();
+    for (i, case) in cases.into_iter().enumerate() {
+        trace!("Running case {}", i);
+        let transaction = conn.transaction()?;
+        results.push(f(&transaction, case));
+        transaction.finish()?;
+    }
+    Ok(results)
+}
+
+/// The SQLSTATE of deadlock_detected.
+const DEADLOCK_DETECTED: &str = "40P01";
+
+/// Run `f` until it doesn't fail with a deadlock (SQLSTATE `40P01`), at most
+/// `max_attempts` times in all, e.g. for tests of concurrent transactions
+/// where the server aborting one of them is expected. Returns what the last
+/// attempt returned, including its deadlock error if all attempts hit one.
+///
+/// Postgres aborts the whole transaction of the deadlock's victim, so `f`
+/// has to start its transaction itself for the next attempt to start over,
+/// e.g. with `conn.transaction()`. Attempts are spaced out a little more each
+/// time so the transactions don't keep running into each other.
+pub fn with_deadlock_retry<T, F: FnMut() -> Result<T>>(max_attempts: u32, mut f: F) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref err) if err.sqlstate() == Some(DEADLOCK_DETECTED) && attempt < max_attempts => {
+                debug!(
+                    "Deadlock on attempt {} of {}, retrying",
+                    attempt, max_attempts
+                );
+                std::thread::sleep(Duration::from_millis(10) * attempt);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A transaction waiting in `pg_prepared_xacts` for its second phase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedTransaction {
+    /// The identifier it was prepared with.
+    pub gid: String,
+    /// Its transaction id.
+    pub transaction: i64,
+    /// The role which prepared it.
+    pub owner: String,
+    /// The database it was prepared in.
+    pub database: String,
+}
+
+/// `PREPARE TRANSACTION gid`, the first phase of a two phase commit of the
+/// transaction `conn` is in, e.g. one started with `BEGIN`. Afterwards the
+/// connection is no longer in a transaction, and the prepared one can be
+/// finished with [`commit_prepared`] or [`rollback_prepared`] from any
+/// connection to the same database.
+///
+/// Needs `max_prepared_transactions` above 0, see
+/// [`PostgresConfig::max_prepared_transactions`](crate::PostgresConfig::max_prepared_transactions).
+/// Prepared transactions left behind are rolled back when the temporary
+/// database is dropped.
+pub fn prepare_transaction(conn: &(impl Queryable + ?Sized), gid: &str) -> Result<()> {
+    conn.batch_execute(&format!("PREPARE TRANSACTION {};", quote_literal(gid)))?;
+    Ok(())
+}
+
+/// `COMMIT PREPARED gid`.
+pub fn commit_prepared(conn: &(impl Queryable + ?Sized), gid: &str) -> Result<()> {
+    conn.batch_execute(&format!("COMMIT PREPARED {};", quote_literal(gid)))?;
+    Ok(())
+}
+
+/// `ROLLBACK PREPARED gid`.
+pub fn rollback_prepared(conn: &(impl Queryable + ?Sized), gid: &str) -> Result<()> {
+    conn.batch_execute(&format!("ROLLBACK PREPARED {};", quote_literal(gid)))?;
+    Ok(())
+}
+
+/// The prepared transactions of `conn`'s database, oldest first.
+pub fn prepared_transactions(conn: &(impl Queryable + ?Sized)) -> Result<Vec<PreparedTransaction>> {
+    let rows = conn.query(
+        "SELECT gid, transaction::text::bigint, owner::text, database::text
+            FROM pg_prepared_xacts
+            WHERE database = current_database()
+            ORDER BY prepared, gid",
+        &[],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| PreparedTransaction {
+            gid: row.get(0),
+            transaction: row.get(1),
+            owner: row.get(2),
+            database: row.get(3),
+        })
+        .collect())
+}
+
+/// The server's version as a number, e.g. `150002` for 15.2, to gate features
+/// on.
+pub fn server_version_num(conn: &(impl Queryable + ?Sized)) -> Result<u32> {
+    let version: String = conn.query("SHOW server_version_num", &[])?.get(0).get(0);
+    version
+        .parse()
+        .map_err(|_| Error::InvalidConfig(format!("Unexpected server_version_num {:?}", version)))
+}
+
+/// The current value of the setting `name`, as `SHOW` displays it, e.g.
+/// `"logical"` for `wal_level` or `"128MB"` for `shared_buffers`.
+pub fn show(conn: &(impl Queryable + ?Sized), name: &str) -> Result<String> {
+    Ok(conn
+        .query("SELECT current_setting($1)", &[&name])?
+        .get(0)
+        .get(0))
+}
+
+/// The current value of the boolean setting `name`, e.g. `jit`.
+pub fn show_bool(conn: &(impl Queryable + ?Sized), name: &str) -> Result<bool> {
+    let value = show(conn, name)?;
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(Error::UnexpectedResult(format!(
+            "{} = {:?} isn't a boolean",
+            name, value
+        ))),
+    }
+}
+
+/// The current value of the setting `name` parsed as a `T`, e.g. a `u32` for
+/// `max_connections`. Settings with units, like `"128MB"`, only parse as
+/// strings.
+pub fn show_as<T: FromStr>(conn: &(impl Queryable + ?Sized), name: &str) -> Result<T> {
+    let value = show(conn, name)?;
+    value.parse().map_err(|_| {
+        Error::UnexpectedResult(format!(
+            "{} = {:?} isn't a {}",
+            name,
+            value,
+            std::any::type_name::<T>()
+        ))
+    })
+}
+
+/// Temporary files written in a database, as counted by `pg_stat_database`,
+/// e.g. by sorts and hashes spilling to disk for lack of `work_mem`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TempFileStats {
+    /// Number of temporary files created.
+    pub files: i64,
+    /// Total size of the temporary files, in bytes.
+    pub bytes: i64,
+}
+
+impl TempFileStats {
+    /// Whether anything was spilled into a temporary file.
+    pub fn spilled(&self) -> bool {
+        self.files > 0
+    }
+}
+
+/// How long [`with_spill_stats`] waits for the statistics to show up.
+const SPILL_STATS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Make the statistics of `conn` visible to itself right away, and drop its
+/// snapshot of everyone's so the next read sees the current ones.
+fn refresh_stats(conn: &(impl Queryable + ?Sized)) -> Result<()> {
+    // Statistics are only flushed to shared memory now and then since
+    // postgres 15.
+    if server_version_num(conn)? >= 150000 {
+        conn.batch_execute("SELECT pg_stat_force_next_flush();")?;
+    }
+    conn.batch_execute("SELECT pg_stat_clear_snapshot();")?;
+    Ok(())
+}
+
+/// The temporary files written in `conn`'s database so far, including the
+/// ones of `conn` itself. The counts are shared by every connection to the
+/// database and only reset with the statistics, so use [`with_spill_stats`]
+/// to get the ones of a piece of code.
+pub fn temp_file_stats(conn: &(impl Queryable + ?Sized)) -> Result<TempFileStats> {
+    refresh_stats(conn)?;
+    let rows = conn.query(
+        "SELECT temp_files, temp_bytes FROM pg_stat_database
+            WHERE datname = current_database()",
+        &[],
+    )?;
+    if rows.is_empty() {
+        return Ok(TempFileStats::default());
+    }
+    Ok(TempFileStats {
+        files: rows.get(0).get(0),
+        bytes: rows.get(0).get(1),
+    })
+}
+
+/// Run `f` and return the [`TempFileStats`] of what ran in `conn`'s database
+/// meanwhile, e.g. to assert that a query with a low `work_mem` spills to disk
+/// or that an index keeps it from doing so. Other connections to the database
+/// running at the same time count too.
+///
+/// Statistics of other connections and of servers before 15 get to the
+/// catalogs with a delay, so this waits up to 2 seconds for them to show up,
+/// which is also how long it takes to find out that nothing spilled.
+pub fn with_spill_stats<T, F: FnOnce() -> T>(
+    conn: &(impl Queryable + ?Sized),
+    f: F,
+) -> Result<(T, TempFileStats)> {
+    let before = temp_file_stats(conn)?;
+    let result = f();
+    let deadline = Instant::now() + SPILL_STATS_TIMEOUT;
+    loop {
+        let after = temp_file_stats(conn)?;
+        if after != before || Instant::now() >= deadline {
+            let spilled = TempFileStats {
+                files: after.files - before.files,
+                bytes: after.bytes - before.bytes,
+            };
+            debug!("Spilled {:?}", spilled);
+            return Ok((result, spilled));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Blocks of a table and its indexes read from disk (or the OS cache) and
+/// found in shared buffers, as counted by `pg_statio_user_tables`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableIoStats {
+    /// The table, schema qualified unless it is on the `search_path`.
+    pub table: String,
+    pub heap_blocks_read: i64,
+    pub heap_blocks_hit: i64,
+    pub index_blocks_read: i64,
+    pub index_blocks_hit: i64,
+}
+
+impl TableIoStats {
+    /// Blocks of the table and its indexes which weren't in shared buffers.
+    pub fn blocks_read(&self) -> i64 {
+        self.heap_blocks_read + self.index_blocks_read
+    }
+}
+
+/// I/O in a database: the block counts of each table and the time spent
+/// reading and writing blocks, from `pg_stat_database`. Postgres only times
+/// I/O for the whole database, and only with `track_io_timing` on, see
+/// [`PostgresConfig::track_io_timing`](crate::PostgresConfig::track_io_timing);
+/// otherwise the times are zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub read_time: Duration,
+    pub write_time: Duration,
+    /// Ordered by table.
+    pub tables: Vec<TableIoStats>,
+}
+
+impl IoStats {
+    /// The counts of `table`, as in [`TableIoStats::table`].
+    pub fn table(&self, table: &str) -> Option<&TableIoStats> {
+        self.tables.iter().find(|stats| stats.table == table)
+    }
+}
+
+/// How long [`with_io_stats`] waits for the statistics to show up.
+const IO_STATS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The I/O in `conn`'s database so far, including that of `conn` itself.
+/// Like [`temp_file_stats`], the counts are shared by every connection, so
+/// use [`with_io_stats`] to get the ones of a piece of code.
+pub fn io_stats(conn: &(impl Queryable + ?Sized)) -> Result<IoStats> {
+    refresh_stats(conn)?;
+    let rows = conn.query(
+        "SELECT blk_read_time, blk_write_time FROM pg_stat_database
+            WHERE datname = current_database()",
+        &[],
+    )?;
+    let millis = |row: usize, column: usize| -> Duration {
+        Duration::from_secs_f64(rows.get(row).get::<_, f64>(column).max(0.0) / 1000.0)
+    };
+    let (read_time, write_time) = if rows.is_empty() {
+        (Duration::default(), Duration::default())
+    } else {
+        (millis(0, 0), millis(0, 1))
+    };
+    let tables = conn
+        .query(
+            "SELECT relid::regclass::text, coalesce(heap_blks_read, 0),
+                coalesce(heap_blks_hit, 0), coalesce(idx_blks_read, 0), coalesce(idx_blks_hit, 0)
+            FROM pg_statio_user_tables
+            ORDER BY 1",
+            &[],
+        )?
+        .iter()
+        .map(|row| TableIoStats {
+            table: row.get(0),
+            heap_blocks_read: row.get(1),
+            heap_blocks_hit: row.get(2),
+            index_blocks_read: row.get(3),
+            index_blocks_hit: row.get(4),
+        })
+        .collect();
+    Ok(IoStats {
+        read_time,
+        write_time,
+        tables,
+    })
+}
+
+/// Run `f` and return the [`IoStats`] of what ran in `conn`'s database
+/// meanwhile: the tables whose counts changed, with how much, and the time
+/// spent on I/O. Other connections to the database running at the same time
+/// count too, and as for [`with_spill_stats`], this waits up to 2 seconds
+/// for the statistics to show up.
+pub fn with_io_stats<T, F: FnOnce() -> T>(
+    conn: &(impl Queryable + ?Sized),
+    f: F,
+) -> Result<(T, IoStats)> {
+    let before = io_stats(conn)?;
+    let result = f();
+    let deadline = Instant::now() + IO_STATS_TIMEOUT;
+    loop {
+        let after = io_stats(conn)?;
+        if after != before || Instant::now() >= deadline {
+            let tables = after
+                .tables
+                .into_iter()
+                .map(|table| {
+                    let earlier = before.table(&table.table).cloned().unwrap_or_default();
+                    TableIoStats {
+                        heap_blocks_read: table.heap_blocks_read - earlier.heap_blocks_read,
+                        heap_blocks_hit: table.heap_blocks_hit - earlier.heap_blocks_hit,
+                        index_blocks_read: table.index_blocks_read - earlier.index_blocks_read,
+                        index_blocks_hit: table.index_blocks_hit - earlier.index_blocks_hit,
+                        table: table.table,
+                    }
+                })
+                .filter(|table| {
+                    table.blocks_read() != 0
+                        || table.heap_blocks_hit != 0
+                        || table.index_blocks_hit != 0
+                })
+                .collect();
+            let io = IoStats {
+                read_time: after.read_time.saturating_sub(before.read_time),
+                write_time: after.write_time.saturating_sub(before.write_time),
+                tables,
+            };
+            debug!("I/O {:?}", io);
+            return Ok((result, io));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Run an `INSERT ... RETURNING` (or any other query) which returns a single
+/// value, e.g. the generated id of the inserted row, and return that value.
+///
+/// Fails with [`Error::UnexpectedResult`] unless exactly one row comes back.
+pub fn insert_returning<T: FromSql>(
+    conn: &(impl Queryable + ?Sized),
+    sql: &str,
+    params: &[&dyn ToSql],
+) -> Result<T> {
+    let rows = conn.query(sql, params)?;
+    if rows.len() != 1 {
+        return Err(Error::UnexpectedResult(format!(
+            "Expected exactly one row from {:?}, got {}",
+            sql,
+            rows.len()
+        )));
+    }
+    match rows.get(0).get_opt(0) {
+        Some(value) => Ok(value?),
+        None => Err(Error::UnexpectedResult(format!(
+            "Expected a column from {:?}, got none",
+            sql
+        ))),
+    }
+}
+
+/// Names of the extensions the server could CREATE EXTENSION, whether or not
+/// they are installed in the current database.
+pub fn available_extensions(conn: &(impl Queryable + ?Sized)) -> Result<Vec<String>> {
+    let rows = conn.query(
+        "SELECT name FROM pg_available_extensions ORDER BY name",
+        &[],
+    )?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Create a logical replication slot using the output plugin `plugin` (e.g.
+/// `pgoutput` or `test_decoding`). Needs `wal_level=logical` on the server.
+///
+/// In a transaction this has to come before anything which writes, or the
+/// server refuses to create the slot.
+pub fn create_logical_replication_slot(
+    conn: &(impl Queryable + ?Sized),
+    slot: &str,
+    plugin: &str,
+) -> Result<()> {
+    debug!(
+        "Creating logical replication slot {:?} with {:?}",
+        slot, plugin
+    );
+    conn.execute(
+        "SELECT 1 FROM pg_create_logical_replication_slot($1, $2)",
+        &[&slot, &plugin],
+    )?;
+    Ok(())
+}
+
+/// Drop a replication slot, which otherwise keeps the server holding on to WAL
+/// (and blocks dropping its database).
+pub fn drop_replication_slot(conn: &(impl Queryable + ?Sized), slot: &str) -> Result<()> {
+    debug!("Dropping replication slot {:?}", slot);
+    conn.execute("SELECT 1 FROM pg_drop_replication_slot($1)", &[&slot])?;
+    Ok(())
+}
+
+/// A change decoded from a logical replication slot by its output plugin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogicalChange<D> {
+    /// Where the change is in the WAL, e.g. `0/16B3748`.
+    pub lsn: String,
+    /// The transaction which made the change.
+    pub xid: i64,
+    /// What the output plugin made of it: text for plugins like
+    /// `test_decoding`, bytes for binary ones like `pgoutput`.
+    pub data: D,
+}
+
+/// Flatten plugin options into the `VARIADIC text[]` the slot functions take.
+fn plugin_options(options: &[(&str, &str)]) -> Vec<String> {
+    options
+        .iter()
+        .flat_map(|&(name, value)| vec![name.to_owned(), value.to_owned()])
+        .collect()
+}
+
+/// Consume the changes waiting in the logical replication `slot`, with
+/// `pg_logical_slot_get_changes`, for text output plugins like
+/// `test_decoding`. `options` are passed to the plugin, e.g.
+/// `&[("include-xids", "0")]`.
+pub fn logical_slot_get_changes(
+    conn: &(impl Queryable + ?Sized),
+    slot: &str,
+    options: &[(&str, &str)],
+) -> Result<Vec<LogicalChange<String>>> {
+    let rows = conn.query(
+        "SELECT lsn::text, xid::text::bigint, data
+            FROM pg_logical_slot_get_changes($1, NULL, NULL, VARIADIC $2::text[])",
+        &[&slot, &plugin_options(options)],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| LogicalChange {
+            lsn: row.get(0),
+            xid: row.get(1),
+            data: row.get(2),
+        })
+        .collect())
+}
+
+/// Like [`logical_slot_get_changes`], with
+/// `pg_logical_slot_get_binary_changes` for binary output plugins like
+/// `pgoutput`, which needs e.g.
+/// `&[("proto_version", "1"), ("publication_names", "my_publication")]`.
+pub fn logical_slot_get_binary_changes(
+    conn: &(impl Queryable + ?Sized),
+    slot: &str,
+    options: &[(&str, &str)],
+) -> Result<Vec<LogicalChange<Vec<u8>>>> {
+    let rows = conn.query(
+        "SELECT lsn::text, xid::text::bigint, data
+            FROM pg_logical_slot_get_binary_changes($1, NULL, NULL, VARIADIC $2::text[])",
+        &[&slot, &plugin_options(options)],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| LogicalChange {
+            lsn: row.get(0),
+            xid: row.get(1),
+            data: row.get(2),
+        })
+        .collect())
+}
+
+/// Run `f` with a logical replication slot called `slot` decoding with
+/// `plugin`, e.g. to read the changes it made with
+/// [`logical_slot_get_changes`]. The slot is dropped afterwards, so it
+/// doesn't keep the server holding on to WAL. Needs `wal_level = logical`.
+pub fn with_logical_slot<T, F: FnOnce() -> T>(
+    conn: &(impl Queryable + ?Sized),
+    slot: &str,
+    plugin: &str,
+    f: F,
+) -> Result<T> {
+    create_logical_replication_slot(conn, slot, plugin)?;
+    let result = f();
+    drop_replication_slot(conn, slot)?;
+    Ok(result)
+}
+
+/// A libpq connection string for `params`, e.g. for CREATE SUBSCRIPTION or
+/// dblink, which take one instead of separate parameters.
+pub fn conninfo(params: &ConnectParams) -> String {
+    let host = match params.host() {
+        Host::Tcp(host) => host.clone(),
+        Host::Unix(path) => path.to_string_lossy().into_owned(),
+    };
+    // Unlike SQL literals, quotes and backslashes are escaped with a backslash.
+    let quote = |value: &str| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"));
+    let mut parts = vec![
+        format!("host={}", quote(&host)),
+        format!("port={}", params.port()),
+    ];
+    if let Some(user) = params.user() {
+        parts.push(format!("user={}", quote(user.name())));
+        if let Some(password) = user.password() {
+            parts.push(format!("password={}", quote(password)));
+        }
+    }
+    if let Some(database) = params.database() {
+        parts.push(format!("dbname={}", quote(database)));
+    }
+    parts.join(" ")
+}
+
+/// Block until nothing is streaming from `slot`, so it can be dropped.
+fn wait_until_slot_inactive(conn: &(impl Queryable + ?Sized), slot: &str) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let rows = conn.query(
+            "SELECT active FROM pg_replication_slots WHERE slot_name = $1",
+            &[&slot],
+        )?;
+        let active = !rows.is_empty() && rows.get(0).get::<_, bool>(0);
+        if !active || Instant::now() >= deadline {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Replicate every table of the publisher's database into the subscriber's
+/// database while `f` runs, by creating a publication and a subscription both
+/// called `name`.
+///
+/// `publisher` and `subscriber` must be superuser connections to the two
+/// databases (which may be on the same server or on different ones), and
+/// `publisher_conninfo` is how the subscriber's server reaches the publisher,
+/// see [`conninfo`]. The tables must already exist on both sides. They are
+/// bare [`Connection`]s since CREATE SUBSCRIPTION can't run in a transaction.
+///
+/// The slot is created separately from the subscription, since CREATE
+/// SUBSCRIPTION creating it would hang when both databases are on the same
+/// server. Afterwards everything is dropped subscription first, then slot,
+/// then publication, leaving both databases droppable.
+pub fn with_logical_replication<T, F: FnOnce() -> T>(
+    publisher: &Connection,
+    subscriber: &Connection,
+    publisher_conninfo: &str,
+    name: &str,
+    f: F,
+) -> Result<T> {
+    publisher.batch_execute(&format!(
+        "CREATE PUBLICATION {} FOR ALL TABLES;",
+        quote_ident(name)
+    ))?;
+    let result: Result<std::thread::Result<T>> = try_!({
+        create_logical_replication_slot(publisher, name, "pgoutput")?;
+        let result: Result<std::thread::Result<T>> = try_!({
+            subscriber.batch_execute(&format!(
+                "CREATE SUBSCRIPTION {name} CONNECTION {conninfo} PUBLICATION {name}
+                    WITH (create_slot = false, slot_name = {slot});",
+                name = quote_ident(name),
+                conninfo = quote_literal(publisher_conninfo),
+                slot = quote_literal(name),
+            ))?;
+            // Caught until everything is dropped, since an active subscription
+            // and slot would keep both databases from being dropped.
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+            // Detach the slot first so DROP SUBSCRIPTION doesn't try to drop it
+            // over the replication connection.
+            subscriber.batch_execute(&format!(
+                "ALTER SUBSCRIPTION {} DISABLE;",
+                quote_ident(name)
+            ))?;
+            subscriber.batch_execute(&format!(
+                "ALTER SUBSCRIPTION {} SET (slot_name = NONE);",
+                quote_ident(name)
+            ))?;
+            subscriber.batch_execute(&format!("DROP SUBSCRIPTION {};", quote_ident(name)))?;
+            result
+        });
+        wait_until_slot_inactive(publisher, name)?;
+        drop_replication_slot(publisher, name)?;
+        result?
+    });
+    publisher.batch_execute(&format!("DROP PUBLICATION {};", quote_ident(name)))?;
+    match result? {
+        Ok(result) => Ok(result),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+    use crate::with_temporary_database;
+    use postgres::params::{self, ConnectParams};
+    use postgres::TlsMode;
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query(&format!("SELECT count(*) FROM {}", table), &[])
+            .unwrap()
+            .get(0)
+            .get(0)
+    }
+
+    #[test]
+    fn lists_available_extensions() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            let extensions = available_extensions(&conn).unwrap();
+            assert!(extensions.contains(&"plpgsql".to_owned()));
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    #[ignore = "needs wal_level=logical"]
+    fn logical_replication_between_databases() {
+        let setup = "CREATE TABLE test (id int PRIMARY KEY)";
+        with_temporary_database(admin_params(), TlsMode::None, |publisher_params, _| {
+            with_temporary_database(admin_params(), TlsMode::None, |subscriber_params, _| {
+                let connect = |params: &ConnectParams| {
+                    let params = params.clone();
+                    let conn = Connection::connect(params, TlsMode::None).unwrap();
+                    conn.batch_execute(setup).unwrap();
+                    conn
+                };
+                let publisher_owner = connect(&publisher_params);
+                let subscriber_owner = connect(&subscriber_params);
+                // The subscription needs to connect as a user allowed to replicate.
+                let admin_params_for = |params: &ConnectParams| {
+                    crate::params_with_database(&admin_params(), params.database())
+                };
+                let admin_connect = |params: &ConnectParams| {
+                    Connection::connect(admin_params_for(params), TlsMode::None).unwrap()
+                };
+
+                publisher_owner
+                    .batch_execute("INSERT INTO test VALUES (1)")
+                    .unwrap();
+                with_logical_replication(
+                    &admin_connect(&publisher_params),
+                    &admin_connect(&subscriber_params),
+                    &conninfo(&admin_params_for(&publisher_params)),
+                    "kpg_fixture_test",
+                    || {
+                        publisher_owner
+                            .batch_execute("INSERT INTO test VALUES (2)")
+                            .unwrap();
+                        let deadline = Instant::now() + Duration::from_secs(10);
+                        while count(&subscriber_owner, "test") < 2 {
+                            assert!(Instant::now() < deadline, "Rows never replicated");
+                            std::thread::sleep(Duration::from_millis(50));
+                        }
+                    },
+                )
+                .unwrap();
+
+                // Still torn down, or dropping the databases would fail.
+                let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    with_logical_replication(
+                        &admin_connect(&publisher_params),
+                        &admin_connect(&subscriber_params),
+                        &conninfo(&admin_params_for(&publisher_params)),
+                        "kpg_fixture_test",
+                        || panic!("failed"),
+                    )
+                }));
+                assert!(panicked.is_err());
+            })
+            .expect("Failed to create subscriber database");
+        })
+        .expect("Failed to create publisher database");
+    }
+
+    #[test]
+    fn insert_returning_one_value() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id serial, name text)")
+                .unwrap();
+            let insert = "INSERT INTO test (name) VALUES ($1) RETURNING id";
+            let first: i32 = insert_returning(&conn, insert, &[&"a"]).unwrap();
+            let second: i32 = insert_returning(&conn, insert, &[&"b"]).unwrap();
+            assert_eq!((first, second), (1, 2));
+
+            let many = insert_returning::<i32>(
+                &conn,
+                "INSERT INTO test (name) VALUES ('c'), ('d') RETURNING id",
+                &[],
+            );
+            assert!(matches!(many, Err(Error::UnexpectedResult(_))));
+            let none = insert_returning::<i32>(&conn, "INSERT INTO test (name) VALUES ('e')", &[]);
+            assert!(matches!(none, Err(Error::UnexpectedResult(_))));
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn statement_savepoints() {
+        let config = crate::DatabaseConfig::new();
+        let rows = crate::with_temporary_database_conn_savepoints(
+            admin_params(),
+            TlsMode::None,
+            &config,
+            |conn| {
+                conn.batch_execute("CREATE TABLE test (id int PRIMARY KEY)")
+                    .unwrap();
+                conn.execute("INSERT INTO test VALUES (1)", &[]).unwrap();
+                assert!(conn.execute("INSERT INTO test VALUES (1)", &[]).is_err());
+                assert!(conn.query("SELECT nonsense", &[]).is_err());
+                conn.execute("INSERT INTO test VALUES (2)", &[]).unwrap();
+                count(conn.connection(), "test")
+            },
+        )
+        .expect("Failed to create temporary database");
+        assert_eq!(rows, 2);
+
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id int)").unwrap();
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                with_statement_savepoints(&conn, |conn| {
+                    conn.execute("INSERT INTO test VALUES (1)", &[]).unwrap();
+                    panic!("failed");
+                })
+            }));
+            assert!(panicked.is_err());
+            // Rolled back, and not left open for later statements.
+            assert_eq!(count(&conn, "test"), 0);
+            assert!(conn.is_active());
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn committing_transaction() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id int)").unwrap();
+            let insert = |transaction: &Transaction| {
+                transaction
+                    .execute("INSERT INTO test VALUES (1)", &[])
+                    .unwrap();
+            };
+
+            let committed = with_committing_transaction(&conn, |transaction| {
+                insert(transaction);
+                Ok::<_, ()>(())
+            });
+            assert!(matches!(committed, Ok(Ok(()))));
+            let rolled_back = with_committing_transaction(&conn, |transaction| {
+                insert(transaction);
+                Err("failed")
+            });
+            assert!(matches!(rolled_back, Ok(Err::<(), _>("failed"))));
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                with_committing_transaction(&conn, |transaction| -> std::result::Result<(), ()> {
+                    insert(transaction);
+                    panic!("failed");
+                })
+            }));
+            assert!(panicked.is_err());
+            assert_eq!(count(&conn, "test"), 1);
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn runs_each_case_in_a_transaction() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (name text UNIQUE)")
+                .unwrap();
+            let cases = vec![("a", 1), ("b", 1), ("a", 1)];
+            let counts = for_each_case(&conn, cases, |transaction, (name, expected)| {
+                // Would violate the unique constraint if the first "a" stayed.
+                transaction
+                    .execute("INSERT INTO test VALUES ($1)", &[&name])
+                    .unwrap();
+                let rows: i64 = transaction
+                    .query("SELECT count(*) FROM test", &[])
+                    .unwrap()
+                    .get(0)
+                    .get(0);
+                assert_eq!(rows, expected);
+                rows
+            })
+            .unwrap();
+            assert_eq!(counts, vec![1, 1, 1]);
+            assert_eq!(count(&conn, "test"), 0);
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn reports_spilling() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute(
+                "CREATE TABLE test AS SELECT i FROM generate_series(1, 100000) AS g (i);
+                SET work_mem = '64kB';",
+            )
+            .unwrap();
+            let sort = "SELECT count(*) FROM (SELECT i FROM test ORDER BY i DESC) sorted";
+            let ((), spilled) = with_spill_stats(&conn, || {
+                conn.query(sort, &[]).unwrap();
+            })
+            .unwrap();
+            assert!(spilled.spilled(), "{:?}", spilled);
+            assert!(spilled.bytes > 0);
+
+            conn.batch_execute("SET work_mem = '64MB'").unwrap();
+            let ((), spilled) = with_spill_stats(&conn, || {
+                conn.query(sort, &[]).unwrap();
+            })
+            .unwrap();
+            assert_eq!(spilled, TempFileStats::default());
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn reports_io() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute(
+                "CREATE TABLE test (id int PRIMARY KEY);
+                CREATE TABLE untouched (id int);
+                INSERT INTO test SELECT generate_series(1, 10000);",
+            )
+            .unwrap();
+            let ((), io) = with_io_stats(&conn, || {
+                conn.query("SELECT count(*) FROM test", &[]).unwrap();
+                conn.query("SELECT id FROM test WHERE id = 5", &[]).unwrap();
+            })
+            .unwrap();
+            let test = io.table("test").expect("No I/O on test");
+            assert!(test.heap_blocks_read + test.heap_blocks_hit > 0, "{:?}", io);
+            assert!(
+                test.index_blocks_read + test.index_blocks_hit > 0,
+                "{:?}",
+                io
+            );
+            assert!(io.table("untouched").is_none());
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn retries_deadlocks() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params.clone(), tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id int PRIMARY KEY, n int); INSERT INTO test VALUES (1, 0), (2, 0);")
+                .unwrap();
+            let barrier = std::sync::Barrier::new(2);
+            let attempts = std::sync::atomic::AtomicU32::new(0);
+            std::thread::scope(|scope| {
+                for (first, second) in [(1, 2), (2, 1)] {
+                    let (params, barrier, attempts) = (params.clone(), &barrier, &attempts);
+                    scope.spawn(move || {
+                        let conn = Connection::connect(params, TlsMode::None).unwrap();
+                        let mut attempt = 0;
+                        with_deadlock_retry(3, || {
+                            attempt += 1;
+                            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            let transaction = conn.transaction()?;
+                            let update = "UPDATE test SET n = n + 1 WHERE id = $1";
+                            transaction.execute(update, &[&first])?;
+                            // Both hold their first row, so the second updates deadlock.
+                            if attempt == 1 {
+                                barrier.wait();
+                            }
+                            transaction.execute(update, &[&second])?;
+                            transaction.commit()?;
+                            Ok(())
+                        })
+                        .unwrap();
+                    });
+                }
+            });
+            assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+            let rows = conn.query("SELECT n FROM test ORDER BY id", &[]).unwrap();
+            assert_eq!(rows.iter().map(|row| row.get(0)).collect::<Vec<i32>>(), vec![2, 2]);
+
+            // Other errors aren't retried, and neither is the last attempt.
+            let mut attempts = 0;
+            let err = with_deadlock_retry(3, || -> Result<()> {
+                attempts += 1;
+                conn.batch_execute("SELECT nonsense")?;
+                Ok(())
+            })
+            .unwrap_err();
+            assert_eq!((attempts, err.sqlstate()), (1, Some("42703")));
+            let mut attempts = 0;
+            let err = with_deadlock_retry(2, || -> Result<()> {
+                attempts += 1;
+                conn.batch_execute("DO $$ BEGIN RAISE deadlock_detected; END $$")?;
+                Ok(())
+            })
+            .unwrap_err();
+            assert_eq!((attempts, err.sqlstate()), (2, Some(DEADLOCK_DETECTED)));
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    #[ignore = "needs max_prepared_transactions > 0"]
+    fn two_phase_commit() {
+        let admin = Connection::connect(admin_params(), TlsMode::None).unwrap();
+        let database = crate::TemporaryDatabase::create(
+            admin_params(),
+            TlsMode::None,
+            &crate::DatabaseConfig::new(),
+        )
+        .unwrap();
+        let conn = Connection::connect(database.params().clone(), TlsMode::None).unwrap();
+        conn.batch_execute("CREATE TABLE test (id int)").unwrap();
+        for gid in &["committed", "rolled back", "left behind"] {
+            conn.batch_execute("BEGIN; INSERT INTO test VALUES (1);")
+                .unwrap();
+            prepare_transaction(&conn, gid).unwrap();
+        }
+        let gids: Vec<String> = prepared_transactions(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|prepared| prepared.gid)
+            .collect();
+        assert_eq!(gids, ["committed", "rolled back", "left behind"]);
+        commit_prepared(&conn, "committed").unwrap();
+        rollback_prepared(&conn, "rolled back").unwrap();
+        assert_eq!(count(&conn, "test"), 1);
+        assert_eq!(prepared_transactions(&conn).unwrap().len(), 1);
+
+        // The one left behind would keep the database from being dropped.
+        drop(conn);
+        database.drop_database().unwrap();
+        let rows = admin
+            .query(
+                "SELECT 1 FROM pg_prepared_xacts WHERE gid = 'left behind'",
+                &[],
+            )
+            .unwrap();
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn shows_settings() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute(
+                "SET timezone = 'UTC'; SET enable_seqscan = off; SET work_mem = '4MB';",
+            )
+            .unwrap();
+            assert_eq!(show(&conn, "timezone").unwrap(), "UTC");
+            assert_eq!(show(&conn, "work_mem").unwrap(), "4MB");
+            assert!(!show_bool(&conn, "enable_seqscan").unwrap());
+            assert!(show_as::<u32>(&conn, "max_connections").unwrap() > 0);
+            assert!(matches!(
+                show_as::<u32>(&conn, "work_mem"),
+                Err(Error::UnexpectedResult(_))
+            ));
+            assert!(matches!(
+                show_bool(&conn, "timezone"),
+                Err(Error::UnexpectedResult(_))
+            ));
+            assert!(matches!(
+                show(&conn, "no_such_setting"),
+                Err(Error::Postgres(_))
+            ));
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    #[ignore = "needs wal_level=logical"]
+    fn consumes_logical_slot_changes() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let database = params.database().unwrap().to_owned();
+            let admin = Connection::connect(
+                crate::params_with_database(&admin_params(), Some(&database)),
+                TlsMode::None,
+            )
+            .unwrap();
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id int PRIMARY KEY)")
+                .unwrap();
+            let slot = format!("{}_slot", database);
+            let changes = with_logical_slot(&admin, &slot, "test_decoding", || {
+                conn.execute("INSERT INTO test VALUES (1)", &[]).unwrap();
+                logical_slot_get_changes(&admin, &slot, &[("include-xids", "0")]).unwrap()
+            })
+            .unwrap();
+            let data: Vec<&str> = changes.iter().map(|change| change.data.as_str()).collect();
+            assert_eq!(
+                data,
+                vec![
+                    "BEGIN",
+                    "table public.test: INSERT: id[integer]:1",
+                    "COMMIT"
+                ]
+            );
+            assert!(changes.iter().all(|change| change.xid == changes[0].xid));
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn conninfo_quoting() {
+        let params = ConnectParams::builder()
+            .port(5433)
+            .user("it's", Some("back\\slash"))
+            .database("db name")
+            .build(params::Host::Tcp("localhost".to_owned()));
+        assert_eq!(
+            conninfo(&params),
+            "host='localhost' port=5433 user='it\\'s' password='back\\\\slash' dbname='db name'"
+        );
+    }
+
+    #[test]
+    fn truncate_all_partitioned_and_inherited() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute(
+                "CREATE TABLE measurement (id serial, logdate date NOT NULL)
+                    PARTITION BY RANGE (logdate);
+                CREATE TABLE measurement_2019 PARTITION OF measurement
+                    FOR VALUES FROM ('2019-01-01') TO ('2020-01-01');
+                CREATE TABLE measurement_2020 PARTITION OF measurement
+                    FOR VALUES FROM ('2020-01-01') TO ('2021-01-01');
+                CREATE TABLE cities (name text);
+                CREATE TABLE capitals (state text) INHERITS (cities);
+                CREATE TABLE \"Mixed Case\" (id int);
+                INSERT INTO measurement (logdate) VALUES ('2019-06-01'), ('2020-06-01');
+                INSERT INTO capitals VALUES ('Sacramento', 'CA');
+                INSERT INTO cities VALUES ('Oakland');
+                INSERT INTO \"Mixed Case\" VALUES (1);",
+            )
+            .unwrap();
+
+            truncate_all(&conn).unwrap();
+
+            for table in &[
+                "measurement",
+                "measurement_2019",
+                "measurement_2020",
+                "cities",
+                "capitals",
+                "\"Mixed Case\"",
+            ] {
+                assert_eq!(count(&conn, table), 0, "{} is not empty", table);
+            }
+            conn.batch_execute("INSERT INTO measurement (logdate) VALUES ('2019-06-01')")
+                .unwrap();
+            let id: i32 = conn
+                .query("SELECT id FROM measurement", &[])
+                .unwrap()
+                .get(0)
+                .get(0);
+            assert_eq!(id, 1);
+        })
+        .expect("Failed to create temporary database");
+    }
+}
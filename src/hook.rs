@@ -0,0 +1,80 @@
+use std::fmt;
+use std::sync::Arc;
+
+use postgres::params::ConnectParams;
+use postgres::{Connection, TlsMode};
+
+use crate::clone_tls_mode;
+
+/// Why the crate is opening a connection, as passed to connect hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPurpose {
+    /// Admin connection used for CREATE ROLE / DROP ROLE, and for the
+    /// database statements too unless they need a connection of their own.
+    RoleAdmin,
+    /// Admin connection used for CREATE DATABASE / DROP DATABASE.
+    DatabaseAdmin,
+    /// Admin connection to the temporary database, e.g. for CREATE EXTENSION.
+    TemporaryDatabaseAdmin,
+    /// Admin connection to a cached template while (re)building it.
+    CachedTemplate,
+    /// Connection as the temporary role to run the setup steps.
+    Setup,
+    /// Connection as the temporary role handed over to the closure.
+    User,
+    /// Connection as the temporary role in the pool of
+    /// [`TemporaryDatabase::pool`](crate::TemporaryDatabase::pool).
+    Pool,
+    /// Additional admin connection opened through
+    /// [`TemporaryDatabase::admin_connection`](crate::TemporaryDatabase::admin_connection).
+    AdditionalAdmin,
+    /// Polling a freshly started container until it is ready.
+    Readiness,
+    /// Admin connection to a container for the CHECKPOINT before snapshotting it.
+    Snapshot,
+    /// Admin connection opened through a [`PostgresContainer`](crate::PostgresContainer).
+    ContainerAdmin,
+}
+
+type HookFn = dyn Fn(ConnectionPurpose, &ConnectParams) + Send + Sync;
+
+/// User supplied function called right before the crate opens a connection.
+#[derive(Clone)]
+pub(crate) struct ConnectHook(pub(crate) Arc<HookFn>);
+
+impl fmt::Debug for ConnectHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ConnectHook")
+    }
+}
+
+/// Copy of `params` with the password (if any) replaced, safe to log.
+pub(crate) fn redact(params: &ConnectParams) -> ConnectParams {
+    let mut redacted = ConnectParams::builder();
+    redacted
+        .port(params.port())
+        .connect_timeout(params.connect_timeout());
+    if let Some(user) = params.user() {
+        redacted.user(user.name(), user.password().map(|_| "<redacted>"));
+    }
+    if let Some(database) = params.database() {
+        redacted.database(database);
+    }
+    for (key, value) in params.options() {
+        redacted.option(key, value);
+    }
+    redacted.build(params.host().clone())
+}
+
+/// `Connection::connect`, telling `hook` about it first.
+pub(crate) fn connect(
+    hook: Option<&ConnectHook>,
+    purpose: ConnectionPurpose,
+    params: &ConnectParams,
+    tls_mode: &TlsMode,
+) -> postgres::Result<Connection> {
+    if let Some(hook) = hook {
+        (hook.0)(purpose, &redact(params));
+    }
+    Connection::connect(params.clone(), clone_tls_mode(tls_mode))
+}
@@ -1,110 +1,109 @@
 #![warn(clippy::all)]
+// Lets the code generated by `#[kpostgres_fixture::test]` resolve inside this crate's own tests.
+extern crate self as kpostgres_fixture;
+
+use std::borrow::{Borrow, Cow};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use derive_more::From;
 use log::*;
-use postgres::params::{self, ConnectParams};
+use postgres::params::ConnectParams;
 use postgres::{Connection, TlsMode};
 use rand::{distributions, thread_rng, Rng};
 
+pub use kpostgres_fixture_macros::test;
+pub use postgres;
+
+// Defined before the modules so that they can use it too.
+macro_rules! try_ {
+    ($e:block) => {
+        (|| Ok($e))()
+    };
+}
+
+#[cfg(feature = "docker")]
+mod docker;
+mod generate;
+mod helpers;
+mod hook;
+mod loader;
+mod password;
+mod persistent;
+mod pool;
+mod queryable;
+mod record;
+mod schema;
+mod shared;
+mod statements;
+mod template;
+
+#[cfg(feature = "docker")]
+pub use docker::*;
+pub use generate::*;
+pub use helpers::*;
+pub use hook::ConnectionPurpose;
+pub use loader::*;
+pub use password::PasswordPolicy;
+pub use persistent::persistent_database;
+pub use pool::*;
+pub use queryable::Queryable;
+pub use schema::*;
+pub use shared::*;
+pub use statements::*;
+pub use template::drop_cached_template;
+
+use hook::ConnectHook;
+use password::{generate_password, PasswordGenerator};
+use record::SqlRecorder;
+
 #[derive(From, Debug)]
 pub enum Error {
     #[cfg(feature = "docker")]
     Docker(dockworker::errors::Error),
     #[cfg(feature = "docker")]
     DockerCreationFailed(&'static str),
+    /// The container outlived `PostgresConfig::max_lifetime` and was removed.
+    #[cfg(feature = "docker")]
+    LifetimeExceeded(Duration),
     Postgres(postgres::Error),
+    Io(std::io::Error),
+    /// The configuration can't be satisfied, with a description of why.
+    InvalidConfig(String),
+    /// A query didn't return what the helper running it expects, e.g. not
+    /// exactly one row.
+    UnexpectedResult(String),
+    /// CREATE EXTENSION was requested for an extension the server doesn't have.
+    ExtensionUnavailable {
+        extension: String,
+        available: Vec<String>,
+    },
+    /// [`validate_constraints`] found rows breaking these constraints.
+    ConstraintsViolated(Vec<ConstraintViolation>),
+    /// [`assert_only_queries`] found these statements, normalized, which none
+    /// of the allowed patterns match.
+    UnexpectedQueries(Vec<String>),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[cfg(feature = "docker")]
-/// Create a temporary postgres:11 docker instance and pass the params
-/// required to create a connection to the closure, along with the postgres
-/// connection used to create this instance if you don't want to create
-/// your own connection.
-///
-/// Takes the image used as a base as a parameter
-/// TODO allow passing a version via PostgresConfig
-pub fn with_temporary_postgres<T, F: FnOnce(ConnectParams, TlsMode, Connection) -> T>(
-    docker_image: &str,
-    f: F,
-) -> Result<T> {
-    use std::borrow::Borrow;
-    let docker = dockworker::Docker::connect_with_defaults()?;
-
-    let mut container_host_config = dockworker::ContainerHostConfig::new();
-    container_host_config.publish_all_ports(true);
-    let container_id = docker
-        .create_container(
-            None,
-            dockworker::ContainerCreateOptions::new(docker_image)
-                .host_config(container_host_config),
-        )?
-        .id;
-
-    let result = (|| -> Result<T> {
-        docker.start_container(&container_id)?;
-
-        let docker = docker.borrow();
-        let result = (|| -> Result<T> {
-            let mut filters = dockworker::container::ContainerFilters::new();
-            filters.id(&container_id);
-            let container = docker.list_containers(None, None, None, filters)?;
-
-            let container = container.first().unwrap();
-
-            let postgres_port = &container
-                .Ports
-                .iter()
-                .filter(|p| p.PrivatePort == 5432)
-                .flat_map(|p| p.PublicPort)
-                .next()
-                .ok_or_else(|| Error::DockerCreationFailed("Failed to find postgres port"))?;
-
-            info!("Postgres at port {} created", postgres_port);
-            let connect_params = ConnectParams::builder()
-                .port(*postgres_port as u16)
-                // .user("postgres", Some("postgres"))
-                .user("postgres", None)
-                .database("postgres")
-                .build(params::Host::Tcp("localhost".to_owned()));
-
-            let tls_mode = TlsMode::None;
-
-            let mut n = 0;
-            let connection = loop {
-                n += 1;
-                match Connection::connect(connect_params.clone(), clone_tls_mode(&tls_mode)) {
-                    Ok(conn) => break Ok(conn),
-                    // TODO timeouterror
-                    Err(err) => {
-                        if n >= 100 {
-                            break Err(err);
-                        }
-                    }
-                }
-
-                std::thread::sleep(Duration::from_millis(100));
-            };
-            // drop(connection);
-            // Ok(f(connect_params, tls_mode))
-            Ok(f(connect_params, tls_mode, connection?))
-        })();
-        docker.stop_container(&container_id, std::time::Duration::from_secs(5))?;
-        Ok(result?)
-    })();
-    docker.remove_container(&container_id, None, Some(true), None)?;
-    Ok(result?)
+impl Error {
+    /// The SQLSTATE of a postgres error reported by the server, e.g. `40P01`
+    /// for deadlock_detected.
+    pub fn sqlstate(&self) -> Option<&str> {
+        match self {
+            Error::Postgres(err) => err.code().map(|code| code.code()),
+            _ => None,
+        }
+    }
 }
 
-
 /// Helper function since TlsMode doesn't implement clone.
 pub fn clone_tls_mode<'a>(tls_mode: &TlsMode<'a>) -> TlsMode<'a> {
     match tls_mode {
         TlsMode::None => TlsMode::None,
-        TlsMode::Prefer(ref handshake) => TlsMode::Prefer(*handshake),
-        TlsMode::Require(ref handshake) => TlsMode::Require(*handshake),
+        TlsMode::Prefer(handshake) => TlsMode::Prefer(*handshake),
+        TlsMode::Require(handshake) => TlsMode::Require(*handshake),
     }
 }
 
@@ -120,150 +119,3460 @@ fn random_string(length: usize) -> String {
         .collect()
 }
 
-macro_rules! try_ {
-    ($e:block) => {
-        (|| Ok($e))()
+/// What [`DatabaseConfig::terminate_idle_transactions`] uses as the timeout.
+pub const DEFAULT_IDLE_IN_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+const IDLE_IN_TRANSACTION_SETTING: &str = "idle_in_transaction_session_timeout";
+
+/// How `bytea` values are formatted as text, for
+/// [`DatabaseConfig::bytea_output`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteaOutput {
+    /// `\x0102`, the default since postgres 9.0.
+    Hex,
+    /// `\001\002`, the traditional format.
+    Escape,
+}
+
+impl ByteaOutput {
+    fn as_str(self) -> &'static str {
+        match self {
+            ByteaOutput::Hex => "hex",
+            ByteaOutput::Escape => "escape",
+        }
+    }
+}
+
+/// How a role's password is hashed, for
+/// [`DatabaseConfig::password_encryption`] and
+/// [`DatabaseConfig::login_role`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordEncryption {
+    /// `md5`, the default before postgres 14 and deprecated since.
+    Md5,
+    /// `scram-sha-256`, the default since postgres 14.
+    ScramSha256,
+}
+
+impl PasswordEncryption {
+    fn as_str(self) -> &'static str {
+        match self {
+            PasswordEncryption::Md5 => "md5",
+            PasswordEncryption::ScramSha256 => "scram-sha-256",
+        }
+    }
+}
+
+/// `sql` creating or altering a role with a password, hashed with
+/// `encryption` instead of the server's `password_encryption` if given.
+fn with_password_encryption(encryption: Option<PasswordEncryption>, sql: String) -> String {
+    match encryption {
+        Some(encryption) => format!(
+            "SET password_encryption = {}; {} RESET password_encryption;",
+            quote_literal(encryption.as_str()),
+            sql
+        ),
+        None => sql,
+    }
+}
+
+/// The encodings postgres can store a database in. The others, like `SJIS`,
+/// are only usable as a `client_encoding`.
+const SERVER_ENCODINGS: &[&str] = &[
+    "SQL_ASCII",
+    "UTF8",
+    "EUC_JP",
+    "EUC_CN",
+    "EUC_KR",
+    "EUC_TW",
+    "EUC_JIS_2004",
+    "MULE_INTERNAL",
+    "LATIN1",
+    "LATIN2",
+    "LATIN3",
+    "LATIN4",
+    "LATIN5",
+    "LATIN6",
+    "LATIN7",
+    "LATIN8",
+    "LATIN9",
+    "LATIN10",
+    "ISO_8859_5",
+    "ISO_8859_6",
+    "ISO_8859_7",
+    "ISO_8859_8",
+    "WIN866",
+    "WIN874",
+    "WIN1250",
+    "WIN1251",
+    "WIN1252",
+    "WIN1253",
+    "WIN1254",
+    "WIN1255",
+    "WIN1256",
+    "WIN1257",
+    "WIN1258",
+    "KOI8R",
+    "KOI8U",
+];
+
+/// Reject a [`DatabaseConfig::encoding`] postgres can't create a database
+/// with, or can't copy from the template.
+fn validate_encoding(config: &DatabaseConfig) -> Result<()> {
+    let (encoding, locale) = match config.encoding {
+        Some(ref encoding) => encoding,
+        None => return Ok(()),
     };
+    if !SERVER_ENCODINGS.contains(&encoding.as_str()) {
+        return Err(Error::InvalidConfig(format!(
+            "{:?} isn't an encoding a database can have, use one of {}",
+            encoding,
+            SERVER_ENCODINGS.join(", ")
+        )));
+    }
+    if locale.is_empty() {
+        return Err(Error::InvalidConfig(format!(
+            "The locale for encoding {} can't be empty, use C if unsure",
+            encoding
+        )));
+    }
+    // Other templates may have data in their own encoding, which postgres
+    // doesn't convert.
+    if config.cached_template.is_some()
+        || config
+            .template
+            .as_ref()
+            .is_some_and(|template| template != "template0")
+    {
+        return Err(Error::InvalidConfig(
+            "encoding can only be combined with the template template0".to_owned(),
+        ));
+    }
+    Ok(())
 }
 
-/// Given the parameters to connect to an existing postgres database, create
-/// new credentials to a temporary database for isolated testing.
-///
-/// CREATE DATABASE inside an existing postgres instance with
-/// random credentials and a random database name and pass the params
-/// required to create a connection to the closure and the TlsMode.
+/// Reject values for settings which change how values are formatted that the
+/// server would only reject once the closure's connection is made.
+fn validate_session_settings(settings: &[(String, String)]) -> Result<()> {
+    for (name, value) in settings {
+        match name.as_str() {
+            "bytea_output" if value != "hex" && value != "escape" => {
+                return Err(Error::InvalidConfig(format!(
+                    "bytea_output must be hex or escape, not {:?}",
+                    value
+                )));
+            }
+            "extra_float_digits" => match value.parse::<i32>() {
+                Ok(digits) if (-15..=3).contains(&digits) => {}
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "extra_float_digits must be between -15 and 3, not {:?}",
+                        value
+                    )));
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Options for how [`with_temporary_database_config`] sets up the temporary
+/// database.
 ///
-/// We pass the parameters so that you can create a connection however you want,
-/// with whatever library you want.
+/// The defaults match [`with_temporary_database`]: a single admin connection
+/// using the params as given is used for every statement.
 ///
-/// Methodology taken from http://wiki.postgresql.org/wiki/Shared_Database_Hosting
-pub fn with_temporary_database<T, F: FnOnce(ConnectParams, TlsMode) -> T>(
-    params: ConnectParams,
-    tls_mode: TlsMode,
-    f: F,
-) -> Result<T> {
-    let dbname = format!("kpg_fixture_{}", random_string(20));
-    // I can skip escaping this since the value is alphanumeric
-    let dbmainuserpass = random_string(32);
-
-    debug!(
-        "Creating database {:?} with password {:?} and default user {:?}",
-        dbname, dbmainuserpass, dbname
-    );
-    let new_params = {
-        let mut new_params = ConnectParams::builder();
-        new_params
-            .port(params.port())
-            .user(&dbname, Some(&dbmainuserpass))
-            .database(&dbname)
-            .connect_timeout(params.connect_timeout());
-        for (key, value) in params.options() {
-            new_params.option(key, value);
-        }
-        new_params.build(params.host().clone())
-    };
+/// A base config can be cloned and tweaked per test. Clones share their hooks
+/// and the [`record_sql`](#method.record_sql) file, everything else is
+/// independent.
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    role_maintenance_database: Option<String>,
+    role_session_setup: Vec<String>,
+    database_maintenance_database: Option<String>,
+    database_session_setup: Vec<String>,
+    comment_database: bool,
+    comment_role: bool,
+    comment: Option<String>,
+    password_policy: Option<PasswordPolicy>,
+    password_generator: Option<PasswordGenerator>,
+    password_encryption: Option<PasswordEncryption>,
+    extensions: Vec<String>,
+    privilege_sql: Option<String>,
+    setup: Vec<SetupStep>,
+    default_statistics_target: Option<u32>,
+    database_settings: Vec<(String, String)>,
+    analyze_after_setup: bool,
+    setup_retry_sqlstates: Vec<String>,
+    setup_retries: u32,
+    session_settings: Vec<(String, String)>,
+    admin_options: Vec<(String, String)>,
+    user_options: Vec<(String, String)>,
+    inherit_admin_options: bool,
+    template: Option<String>,
+    tablespace: Option<String>,
+    terminate_template_connections: bool,
+    terminate_connections_on_drop: bool,
+    drop_wait: Duration,
+    cached_template: Option<String>,
+    refresh_collation_version: bool,
+    encoding: Option<(String, String)>,
+    database_name: Option<String>,
+    role_name: Option<String>,
+    extra_roles: Vec<String>,
+    login_roles: Vec<(String, PasswordEncryption)>,
+    connect_hook: Option<ConnectHook>,
+    sql_recorder: Option<SqlRecorder>,
+    audit_sql: bool,
+}
 
-    let conn = Connection::connect(params, clone_tls_mode(&tls_mode))?;
-
-    // Setup a new user
-    // These must be executed separately since CREATE/DROP DATABASE cannot be executed inside a
-    // transaction and multi-statement queries are implicitly wrapped in a transaction.
-    // Ref: https://www.postgresql.org/docs/current/protocol-flow.html#PROTOCOL-FLOW-MULTI-STATEMENT
-    debug!("Setting up database");
-    conn.batch_execute(&format!(
-        "CREATE ROLE {dbname:?}
-            NOSUPERUSER NOCREATEDB NOCREATEROLE NOINHERIT
-            LOGIN ENCRYPTED PASSWORD '{dbmainuserpass}';",
-        // Interpolating like this is safe since I use an Alphanumeric distribution
-        dbname = dbname,
-        dbmainuserpass = dbmainuserpass
-    ))?;
-    // Try block this so I can rollback incrementally.
-    let result = try_!({
-        conn.batch_execute(&format!(
-            "CREATE DATABASE {dbname:?} WITH OWNER={dbname:?};",
-            dbname = dbname
-        ))?;
-        let result: Result<T> = try_!({
-            conn.batch_execute(&format!(
-                "REVOKE ALL ON DATABASE {dbname:?} FROM public;",
-                dbname = dbname
-            ))?;
-            debug!("Finished setting up database");
-
-            f(new_params, tls_mode)
-        });
-        debug!("Starting cleanup");
-        conn.batch_execute(&format!("DROP DATABASE {dbname:?};", dbname = dbname))?;
-        result?
-    });
-    conn.batch_execute(&format!("DROP ROLE {dbname:?};", dbname = dbname))?;
-    debug!("Finished cleanup");
-    result
-}
-
-// /// Methodology taken from http://wiki.postgresql.org/wiki/Shared_Database_Hosting
-// pub fn with_temporary_database_conn<T, F: FnOnce(Connection) -> T>(
-//     params: ConnectParams,
-//     tls_mode: TlsMode,
-//     f: F,
-// ) -> Result<T> {
-// }
+/// SQL to run against the temporary database before handing it over.
+#[derive(Debug, Clone)]
+enum SetupStep {
+    Sql(String),
+    File(PathBuf),
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl SetupStep {
+    fn sql(&self) -> Result<String> {
+        match self {
+            SetupStep::Sql(sql) => Ok(sql.clone()),
+            SetupStep::File(path) => {
+                debug!("Reading setup file {:?}", path);
+                Ok(std::fs::read_to_string(path)?)
+            }
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        DatabaseConfig {
+            role_maintenance_database: None,
+            role_session_setup: Vec::new(),
+            database_maintenance_database: None,
+            database_session_setup: Vec::new(),
+            comment_database: true,
+            comment_role: true,
+            comment: None,
+            password_policy: None,
+            password_generator: None,
+            password_encryption: None,
+            extensions: Vec::new(),
+            privilege_sql: None,
+            setup: Vec::new(),
+            default_statistics_target: None,
+            database_settings: Vec::new(),
+            analyze_after_setup: false,
+            // deadlock_detected and serialization_failure
+            setup_retry_sqlstates: vec!["40P01".to_owned(), "40001".to_owned()],
+            setup_retries: 3,
+            session_settings: Vec::new(),
+            admin_options: Vec::new(),
+            user_options: Vec::new(),
+            inherit_admin_options: true,
+            template: None,
+            tablespace: None,
+            terminate_template_connections: false,
+            terminate_connections_on_drop: false,
+            drop_wait: Duration::from_millis(500),
+            cached_template: None,
+            refresh_collation_version: false,
+            encoding: None,
+            database_name: None,
+            role_name: None,
+            extra_roles: Vec::new(),
+            login_roles: Vec::new(),
+            connect_hook: None,
+            sql_recorder: None,
+            audit_sql: false,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn new() -> DatabaseConfig {
+        DatabaseConfig::default()
+    }
+
+    /// Connect to this database instead of the one in the admin params to run
+    /// CREATE ROLE / DROP ROLE.
+    pub fn role_maintenance_database(&mut self, database: &str) -> &mut DatabaseConfig {
+        self.role_maintenance_database = Some(database.to_owned());
+        self
+    }
+
+    /// Connect to this database instead of the one in the admin params to run
+    /// CREATE DATABASE / DROP DATABASE. This can't be `template1`, since
+    /// CREATE DATABASE refuses to copy a template that has open connections.
+    pub fn database_maintenance_database(&mut self, database: &str) -> &mut DatabaseConfig {
+        self.database_maintenance_database = Some(database.to_owned());
+        self
+    }
+
+    /// Run this (e.g. a `SET ...`) on the role management connection before
+    /// any role statements. Can be called multiple times.
+    pub fn role_session_setup(&mut self, sql: &str) -> &mut DatabaseConfig {
+        self.role_session_setup.push(sql.to_owned());
+        self
+    }
+
+    /// Run this (e.g. a `SET ...`) on the database management connection
+    /// before any database statements. Can be called multiple times.
+    pub fn database_session_setup(&mut self, sql: &str) -> &mut DatabaseConfig {
+        self.database_session_setup.push(sql.to_owned());
+        self
+    }
+
+    /// Whether to `COMMENT ON DATABASE` so that a leaked database can be traced
+    /// back to whatever created it.
+    ///
+    /// Defaults to true.
+    pub fn comment_database(&mut self, comment_database: bool) -> &mut DatabaseConfig {
+        self.comment_database = comment_database;
+        self
+    }
+
+    /// Whether to `COMMENT ON ROLE` the role and the extra roles like the
+    /// database, so that leaked roles can be traced back too.
+    ///
+    /// Defaults to true.
+    pub fn comment_role(&mut self, comment_role: bool) -> &mut DatabaseConfig {
+        self.comment_role = comment_role;
+        self
+    }
+
+    /// Use this as the database and role comment instead of the default, which
+    /// names the process, pid, thread (the test name under `cargo test`) and
+    /// creation time.
+    pub fn comment(&mut self, comment: &str) -> &mut DatabaseConfig {
+        self.comment = Some(comment.to_owned());
+        self
+    }
+
+    /// Make sure the role's password satisfies `policy`, e.g. to get past the
+    /// server's `passwordcheck`.
+    pub fn password_policy(&mut self, policy: PasswordPolicy) -> &mut DatabaseConfig {
+        self.password_policy = Some(policy);
+        self
+    }
+
+    /// Generate the role's password with `generator` instead of a random
+    /// [a-z0-9] string. If there is also a password policy, `generator` is
+    /// called again until its output satisfies it.
+    pub fn password_generator<G: Fn() -> String + Send + Sync + 'static>(
+        &mut self,
+        generator: G,
+    ) -> &mut DatabaseConfig {
+        self.password_generator = Some(PasswordGenerator(std::sync::Arc::new(generator)));
+        self
+    }
+
+    /// CREATE EXTENSION in the temporary database (as the admin user) before
+    /// handing it over. Can be called multiple times.
+    ///
+    /// Fails with [`Error::ExtensionUnavailable`], listing what the server does
+    /// have, if the extension isn't installed on the server.
+    pub fn extension(&mut self, name: &str) -> &mut DatabaseConfig {
+        self.extensions.push(name.to_owned());
+        self
+    }
+
+    /// Set up the temporary database's privileges with `sql` instead of the
+    /// default `REVOKE ALL ON DATABASE ... FROM public`, for permission models
+    /// that conflict with it. The crate still creates and drops the role and
+    /// the database.
+    ///
+    /// `sql` runs as the admin user, connected to the temporary database,
+    /// after the extensions are created and before the setup steps (e.g.
+    /// migrations), which run as the temporary role with whatever privileges
+    /// it ends up with. The temporary role's name is in the setting
+    /// `kpg_fixture.role`, e.g. for
+    /// `DO $$ BEGIN EXECUTE format('GRANT ... TO %I', current_setting('kpg_fixture.role')); END $$`.
+    pub fn privilege_sql(&mut self, sql: &str) -> &mut DatabaseConfig {
+        self.privilege_sql = Some(sql.to_owned());
+        self
+    }
+
+    /// Run `sql` (e.g. migrations or seed data) in the temporary database as
+    /// its owner before handing it over. Steps run in the order added.
+    pub fn setup_sql(&mut self, sql: &str) -> &mut DatabaseConfig {
+        self.setup.push(SetupStep::Sql(sql.to_owned()));
+        self
+    }
+
+    /// Like [`setup_sql`](#method.setup_sql), with the SQL read from `path`
+    /// during setup.
+    pub fn setup_file<P: AsRef<Path>>(&mut self, path: P) -> &mut DatabaseConfig {
+        self.setup.push(SetupStep::File(path.as_ref().to_owned()));
+        self
+    }
+
+    /// `ALTER DATABASE ... SET default_statistics_target` so the statistics
+    /// gathered by ANALYZE, and with them the plans, are the same every run.
+    pub fn default_statistics_target(&mut self, target: u32) -> &mut DatabaseConfig {
+        self.default_statistics_target = Some(target);
+        self
+    }
+
+    /// Run ANALYZE after the setup steps, so statistics are present without
+    /// depending on whether autovacuum got to the tables first.
+    ///
+    /// Defaults to false.
+    pub fn analyze_after_setup(&mut self, analyze: bool) -> &mut DatabaseConfig {
+        self.analyze_after_setup = analyze;
+        self
+    }
+
+    /// Rerun a setup step that fails with one of these SQLSTATEs, e.g. the
+    /// deadlocks or serialization failures parallel seeding can run into,
+    /// instead of failing the setup. Replaces the previous codes; pass none
+    /// to never retry.
+    ///
+    /// A step is rerun as a whole, after a ROLLBACK of whatever transaction
+    /// it left open, so a step which commits part of its work before failing
+    /// has to be safe to rerun.
+    ///
+    /// Defaults to `40P01` (deadlock_detected) and `40001`
+    /// (serialization_failure).
+    pub fn retry_setup_on(&mut self, sqlstates: &[&str]) -> &mut DatabaseConfig {
+        self.setup_retry_sqlstates = sqlstates.iter().map(|&code| code.to_owned()).collect();
+        self
+    }
+
+    /// How many times to rerun a setup step failing with one of the
+    /// [`retry_setup_on`](#method.retry_setup_on) SQLSTATEs before giving up.
+    ///
+    /// Defaults to 3.
+    pub fn setup_retries(&mut self, retries: u32) -> &mut DatabaseConfig {
+        self.setup_retries = retries;
+        self
+    }
+
+    /// Set `name` to `value` on the connection handed over by
+    /// [`with_temporary_database_conn_config`], before the closure runs.
+    /// Later settings win over earlier ones.
+    pub fn session_setting(&mut self, name: &str, value: &str) -> &mut DatabaseConfig {
+        self.session_settings
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Add the connection option `key=value`, e.g. `options` with
+    /// `-c search_path=admin,public` to find admin functions, to the params of
+    /// the admin connections only. Replaces an option of the same name in the
+    /// admin params.
+    pub fn admin_option(&mut self, key: &str, value: &str) -> &mut DatabaseConfig {
+        self.admin_options.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Add the connection option `key=value` to the params of the temporary
+    /// database only, i.e. the ones handed over to the closure. Replaces an
+    /// option of the same name copied from the admin params.
+    pub fn user_option(&mut self, key: &str, value: &str) -> &mut DatabaseConfig {
+        self.user_options.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Whether the params of the temporary database get the options of the
+    /// admin params, before the [`user_option`](#method.user_option)s. Turn
+    /// off when the admin params have options the restricted role shouldn't
+    /// get. The [`admin_option`](#method.admin_option)s are never copied.
+    ///
+    /// Defaults to true.
+    pub fn inherit_admin_options(&mut self, inherit: bool) -> &mut DatabaseConfig {
+        self.inherit_admin_options = inherit;
+        self
+    }
+
+    /// Pin the session `timezone`, so formatted timestamps don't depend on the
+    /// host or the server defaults.
+    pub fn timezone(&mut self, timezone: &str) -> &mut DatabaseConfig {
+        self.session_setting("timezone", timezone)
+    }
 
-    use std::sync::{Once, ONCE_INIT};
+    /// Pin the session `datestyle`, e.g. `ISO, MDY`.
+    pub fn datestyle(&mut self, datestyle: &str) -> &mut DatabaseConfig {
+        self.session_setting("datestyle", datestyle)
+    }
+
+    /// Pin the session `bytea_output`, so golden values of binary data read
+    /// as text don't depend on the server's configuration.
+    pub fn bytea_output(&mut self, output: ByteaOutput) -> &mut DatabaseConfig {
+        self.session_setting("bytea_output", output.as_str())
+    }
+
+    /// Pin the session `extra_float_digits`, which decides how many digits
+    /// floats are formatted with as text: 1 or more gives the shortest exact
+    /// representation on postgres 12+ (and 3 enough digits to round-trip
+    /// before that), 0 or less rounds them, like older servers' default did.
+    ///
+    /// Must be between -15 and 3, or creating the database fails with
+    /// [`Error::InvalidConfig`].
+    pub fn extra_float_digits(&mut self, digits: i32) -> &mut DatabaseConfig {
+        self.session_setting("extra_float_digits", &digits.to_string())
+    }
+
+    /// `ALTER DATABASE ... SET name = value`, so that every connection to the
+    /// temporary database gets it, including ones the closure opens itself.
+    /// Later settings win over earlier ones.
+    pub fn database_setting(&mut self, name: &str, value: &str) -> &mut DatabaseConfig {
+        self.database_settings
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Turn off JIT compilation in the temporary database, which otherwise
+    /// makes timings vary and can change plans once queries get expensive.
+    /// Needs postgres 11+, which introduced JIT.
+    ///
+    /// Defaults to false, leaving it to the server.
+    pub fn disable_jit(&mut self, disable: bool) -> &mut DatabaseConfig {
+        if disable {
+            self.database_setting("jit", "off")
+        } else {
+            self.database_settings.retain(|(name, _)| name != "jit");
+            self
+        }
+    }
+
+    /// Have the server terminate sessions of the temporary database that sit
+    /// idle inside a transaction for longer than `timeout`, via
+    /// `idle_in_transaction_session_timeout`. A test which forgets to commit
+    /// or roll back then fails where it does so, instead of keeping a
+    /// connection open and making the DROP DATABASE fail during cleanup.
+    pub fn idle_in_transaction_timeout(&mut self, timeout: Duration) -> &mut DatabaseConfig {
+        self.database_setting(
+            IDLE_IN_TRANSACTION_SETTING,
+            &format!("{}ms", timeout.as_millis()),
+        )
+    }
+
+    /// [`idle_in_transaction_timeout`](#method.idle_in_transaction_timeout)
+    /// of [`DEFAULT_IDLE_IN_TRANSACTION_TIMEOUT`], long enough for anything a
+    /// test does deliberately between statements.
+    ///
+    /// Defaults to false, leaving it to the server.
+    pub fn terminate_idle_transactions(&mut self, terminate: bool) -> &mut DatabaseConfig {
+        if terminate {
+            self.idle_in_transaction_timeout(DEFAULT_IDLE_IN_TRANSACTION_TIMEOUT)
+        } else {
+            self.database_settings
+                .retain(|(name, _)| name != IDLE_IN_TRANSACTION_SETTING);
+            self
+        }
+    }
+
+    /// Create the temporary database as a copy of `database`, which doesn't
+    /// need to be marked as a template.
+    ///
+    /// Postgres refuses to copy a database that has open connections, see
+    /// [`terminate_template_connections`](#method.terminate_template_connections).
+    /// The copied objects keep their original owners, so the temporary role
+    /// only gets whatever access they grant it.
+    pub fn template(&mut self, database: &str) -> &mut DatabaseConfig {
+        self.template = Some(database.to_owned());
+        self
+    }
+
+    /// Create the temporary database with `tablespace` as its default
+    /// tablespace, e.g. one on a RAM disk. Tables created without a
+    /// TABLESPACE clause end up there, without the role needing any privilege
+    /// on it.
+    pub fn tablespace(&mut self, tablespace: &str) -> &mut DatabaseConfig {
+        self.tablespace = Some(tablespace.to_owned());
+        self
+    }
+
+    /// Terminate other connections to the template database before copying it.
+    ///
+    /// Defaults to false.
+    pub fn terminate_template_connections(&mut self, terminate: bool) -> &mut DatabaseConfig {
+        self.terminate_template_connections = terminate;
+        self
+    }
+
+    /// Before dropping the temporary database, wait up to
+    /// [`drop_wait`](#method.drop_wait) for the connections to it to go away,
+    /// then terminate the ones that are left, e.g. a pool the test didn't
+    /// shut down. Without this the DROP DATABASE fails if anything is still
+    /// connected.
+    ///
+    /// Defaults to false.
+    pub fn terminate_connections_on_drop(&mut self, terminate: bool) -> &mut DatabaseConfig {
+        self.terminate_connections_on_drop = terminate;
+        self
+    }
+
+    /// How long [`terminate_connections_on_drop`](#method.terminate_connections_on_drop)
+    /// gives connections to close by themselves, so that one in the middle of
+    /// a commit isn't cut off.
+    ///
+    /// Defaults to 500ms.
+    pub fn drop_wait(&mut self, wait: Duration) -> &mut DatabaseConfig {
+        self.drop_wait = wait;
+        self
+    }
+
+    /// Run the extensions and setup steps once, in a database called `name`
+    /// which is kept around between runs, and create every temporary database
+    /// as a copy of it. Copying is much faster than rerunning migrations.
+    ///
+    /// The template records an md5 hash of the extensions and the contents of
+    /// the setup steps (files are read on every run) in its COMMENT. When the
+    /// hash doesn't match, e.g. because a migration was added or edited, the
+    /// template is dropped and rebuilt before copying it, so it is never
+    /// stale. Settings applied to each copy, like the comment or
+    /// [`analyze_after_setup`](#method.analyze_after_setup), are not part of
+    /// the hash. Checking, building and copying happen under an advisory lock,
+    /// so concurrent tests and processes can share a template.
+    ///
+    /// The setup steps run as a NOLOGIN role also called `name`, and what they
+    /// create is reassigned to each temporary role, so it owns its copy as
+    /// usual. Use [`drop_cached_template`] to get rid of both.
+    ///
+    /// Can't be combined with [`template`](#method.template).
+    pub fn cached_template(&mut self, name: &str) -> &mut DatabaseConfig {
+        self.cached_template = Some(name.to_owned());
+        self
+    }
+
+    /// Run `ALTER DATABASE ... REFRESH COLLATION VERSION` after creating the
+    /// database, so a collation version recorded in the template by an older
+    /// glibc doesn't make every connection warn about a mismatch. Skipped on
+    /// servers before postgres 15, which don't track it per database.
+    ///
+    /// Defaults to false.
+    pub fn refresh_collation_version(&mut self, refresh: bool) -> &mut DatabaseConfig {
+        self.refresh_collation_version = refresh;
+        self
+    }
+
+    /// Create the database with `encoding` (as postgres calls it, e.g.
+    /// `LATIN1`) and `locale` for both `LC_COLLATE` and `LC_CTYPE`, copied from
+    /// `template0` since the other templates can't change encoding, for
+    /// testing how legacy data in a non-UTF8 database round-trips.
+    ///
+    /// The locale has to use the same encoding, e.g. `en_US.iso88591` for
+    /// `LATIN1`, and be installed on the server; `C` works with any encoding.
+    /// Combinations postgres rejects fail with [`Error::InvalidConfig`].
+    ///
+    /// The connections keep the UTF8 `client_encoding` this crate's client
+    /// needs, and the server converts text from and to the database's
+    /// encoding. Storing characters the encoding doesn't have fails.
+    pub fn encoding(&mut self, encoding: &str, locale: &str) -> &mut DatabaseConfig {
+        self.encoding = Some((encoding.to_uppercase(), locale.to_owned()));
+        self
+    }
 
-    static INIT: Once = ONCE_INIT;
+    /// Hash the temporary role's password with `encryption` instead of the
+    /// server's `password_encryption`.
+    pub fn password_encryption(&mut self, encryption: PasswordEncryption) -> &mut DatabaseConfig {
+        self.password_encryption = Some(encryption);
+        self
+    }
+
+    /// Use this fixed name for the database instead of a random one. Any name
+    /// postgres accepts works, it is quoted wherever it is used.
+    pub fn database_name(&mut self, name: &str) -> &mut DatabaseConfig {
+        self.database_name = Some(name.to_owned());
+        self
+    }
+
+    /// Use this fixed name for the role instead of the database's name.
+    pub fn role_name(&mut self, name: &str) -> &mut DatabaseConfig {
+        self.role_name = Some(name.to_owned());
+        self
+    }
+
+    /// Also create a NOLOGIN role called `<role>_<name>`, which is subject to
+    /// row level security and which the temporary role is a member of, e.g. to
+    /// test policies as different principals with [`with_role`]. It needs to
+    /// be granted access to the tables like any other role.
+    ///
+    /// Its full name is in [`TempDbInfo::extra_role`].
+    pub fn extra_role(&mut self, name: &str) -> &mut DatabaseConfig {
+        self.extra_roles.push(name.to_owned());
+        self
+    }
+
+    /// Also create a role called `<role>_<name>` which can log in with a
+    /// password of its own, hashed with `encryption`, and which is a member of
+    /// the temporary role, so it can do everything the temporary role can.
+    ///
+    /// Its params are in [`TempDbInfo::login_role`].
+    pub fn login_role(
+        &mut self,
+        name: &str,
+        encryption: PasswordEncryption,
+    ) -> &mut DatabaseConfig {
+        self.login_roles.push((name.to_owned(), encryption));
+        self
+    }
+
+    /// Add the [`login_role`](#method.login_role)s `md5` and `scram`, with
+    /// their passwords hashed with md5 and scram-sha-256, e.g. for testing
+    /// code which has to handle both during a migration from one to the
+    /// other. The server's `pg_hba.conf` decides whether md5 passwords are
+    /// accepted; `password` and `md5` methods accept both.
+    pub fn md5_and_scram_roles(&mut self) -> &mut DatabaseConfig {
+        self.login_role("md5", PasswordEncryption::Md5)
+            .login_role("scram", PasswordEncryption::ScramSha256)
+    }
+
+    /// Call `hook` right before every connection the crate opens, with what
+    /// it is for and the params (password redacted), e.g. to start a tracing
+    /// span. This includes the connection handed over by the `_conn` helpers,
+    /// but not the ones the closure opens itself.
+    pub fn on_connect<H>(&mut self, hook: H) -> &mut DatabaseConfig
+    where
+        H: Fn(ConnectionPurpose, &ConnectParams) + Send + Sync + 'static,
+    {
+        self.connect_hook = Some(ConnectHook(std::sync::Arc::new(hook)));
+        self
+    }
+
+    /// Append every statement the crate runs to set up and drop the temporary
+    /// database (CREATE ROLE, CREATE DATABASE, extensions, setup steps, DROP
+    /// ...) to the file at `path`, in order, with `\connect` lines saying
+    /// where each one ran. Replaying the file with `psql` as a superuser
+    /// reproduces the setup outside the crate. Passwords are replaced by
+    /// `<redacted>`.
+    ///
+    /// Queries the crate only uses to look things up aren't included. Every
+    /// temporary database created with this config is appended to the same
+    /// file, which is created if needed.
+    pub fn record_sql<P: AsRef<Path>>(&mut self, path: P) -> &mut DatabaseConfig {
+        self.sql_recorder = Some(SqlRecorder::script(path.as_ref()));
+        self
+    }
+
+    /// Collect the statements the crate runs to create the role and the
+    /// database and grant or revoke privileges (CREATE ROLE, CREATE DATABASE,
+    /// REVOKE, CREATE EXTENSION, ALTER DATABASE, ... including the ones that
+    /// depend on the server version) in [`TempDbInfo::audit_sql`], e.g. to
+    /// keep as a test artifact. Passwords are replaced by `<redacted>`.
+    ///
+    /// Unlike [`record_sql`](#method.record_sql) this leaves out the setup
+    /// steps and session setup, which aren't about privileges.
+    ///
+    /// Defaults to false.
+    pub fn audit_sql(&mut self, audit: bool) -> &mut DatabaseConfig {
+        self.audit_sql = audit;
+        self
+    }
 
+    /// The files of the setup steps, in order.
     #[cfg(feature = "docker")]
-    #[test]
-    fn temp_pg() {
-        INIT.call_once(|| {
-            env_logger::init();
-        });
+    pub(crate) fn setup_files(&self) -> Vec<PathBuf> {
+        self.setup
+            .iter()
+            .filter_map(|step| match step {
+                SetupStep::File(path) => Some(path.clone()),
+                SetupStep::Sql(_) => None,
+            })
+            .collect()
+    }
 
-        let result = with_temporary_postgres("postgres:11", |params, tls_mode, _| -> Result<()> {
-            // let conn = Connection::connect(params.clone(), clone_tls_mode(&tls_mode))?;
-            with_temporary_database(params, tls_mode, |params, tls_mode| -> Result<()> {
-                let conn = Connection::connect(params, tls_mode)?;
-                conn.batch_execute("CREATE TABLE test()")?;
-                conn.execute("TABLE test", &[])?;
-                Ok(())
-            })?
+    /// A copy with the setup files in `read` replaced by their contents. The
+    /// others are still read during setup, failing there as they would have.
+    #[cfg(feature = "docker")]
+    pub(crate) fn with_setup_files_read(
+        &self,
+        mut read: std::collections::HashMap<PathBuf, String>,
+    ) -> DatabaseConfig {
+        let mut config = self.clone();
+        for step in &mut config.setup {
+            if let SetupStep::File(path) = step {
+                if let Some(sql) = read.remove(path) {
+                    *step = SetupStep::Sql(sql);
+                }
+            }
+        }
+        config
+    }
+}
+
+/// How the comments made by [`default_comment`] start.
+const DEFAULT_COMMENT_PREFIX: &str = "Created by kpostgres_fixture";
+
+/// A description of who is creating a database right now, for comments.
+fn default_comment() -> String {
+    let process = std::env::current_exe()
+        .ok()
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
         })
-        .expect("Failed to create temporary database");
-        println!("{:#?}", result);
-        result.expect("Inner result failed");
+        .unwrap_or_else(|| "unknown".to_owned());
+    let created_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let mut comment = format!(
+        "{} for {} (pid {})",
+        DEFAULT_COMMENT_PREFIX,
+        process,
+        std::process::id()
+    );
+    if let Some(name) = std::thread::current().name().filter(|name| *name != "main") {
+        comment.push_str(&format!(" in {}", name));
     }
+    comment.push_str(&format!(" at {} (unix time)", created_at));
+    comment
+}
 
-    #[test]
-    fn temp_db() {
-        INIT.call_once(|| {
-            env_logger::init();
-        });
+/// Quote a string as a SQL identifier.
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
 
-        let connect_params = ConnectParams::builder()
-            .port(5432)
-            // .user("postgres", Some("postgres"))
-            .user("postgres", None)
-            .database("postgres")
-            .build(params::Host::Tcp("localhost".to_owned()));
-        // let conn = Connection::connect(params.clone(), clone_tls_mode(&tls_mode))?;
-        let result = with_temporary_database(
-            connect_params,
-            TlsMode::None,
-            |params, tls_mode| -> Result<()> {
-                let conn = Connection::connect(params, tls_mode)?;
-                conn.batch_execute("CREATE TABLE test()")?;
-                conn.execute("TABLE test", &[])?;
-                Ok(())
-            },
-        )
-        .expect("Failed to create temporary database");
-        println!("{:#?}", result);
-        result.expect("Inner result failed");
+/// Reject names which postgres would refuse or silently truncate, since a
+/// truncated name wouldn't match what we later try to DROP.
+fn validate_identifier(kind: &str, name: &str) -> Result<()> {
+    // NAMEDATALEN - 1
+    const MAX_IDENTIFIER_LENGTH: usize = 63;
+    if name.is_empty() {
+        return Err(Error::InvalidConfig(format!("The {} name is empty", kind)));
+    }
+    if name.contains('\0') {
+        return Err(Error::InvalidConfig(format!(
+            "The {} name {:?} contains a NUL byte",
+            kind, name
+        )));
+    }
+    if name.len() > MAX_IDENTIFIER_LENGTH {
+        return Err(Error::InvalidConfig(format!(
+            "The {} name {:?} is longer than {} bytes",
+            kind, name, MAX_IDENTIFIER_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Quote a string as a SQL literal.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Copy of `params` with the database replaced, keeping everything else.
+fn params_with_database(params: &ConnectParams, database: Option<&str>) -> ConnectParams {
+    let mut new_params = ConnectParams::builder();
+    new_params
+        .port(params.port())
+        .connect_timeout(params.connect_timeout());
+    if let Some(user) = params.user() {
+        new_params.user(user.name(), user.password());
+    }
+    if let Some(database) = database.or_else(|| params.database()) {
+        new_params.database(database);
+    }
+    for (key, value) in params.options() {
+        new_params.option(key, value);
+    }
+    new_params.build(params.host().clone())
+}
+
+/// `base` with `options` added, replacing options of the same name.
+fn merge_options(base: &[(String, String)], options: &[(String, String)]) -> Vec<(String, String)> {
+    let mut merged: Vec<(String, String)> = base.to_vec();
+    for (key, value) in options {
+        merged.retain(|(name, _)| name != key);
+        merged.push((key.clone(), value.clone()));
+    }
+    merged
+}
+
+/// Copy of `params` with `options` added, replacing options of the same name.
+fn params_with_options(params: &ConnectParams, options: &[(String, String)]) -> ConnectParams {
+    let mut new_params = ConnectParams::builder();
+    new_params
+        .port(params.port())
+        .connect_timeout(params.connect_timeout());
+    if let Some(user) = params.user() {
+        new_params.user(user.name(), user.password());
+    }
+    if let Some(database) = params.database() {
+        new_params.database(database);
+    }
+    for (key, value) in merge_options(params.options(), options) {
+        new_params.option(&key, &value);
+    }
+    new_params.build(params.host().clone())
+}
+
+/// The ENCODING and locale options of CREATE DATABASE for
+/// [`DatabaseConfig::encoding`], if set.
+fn encoding_clause(config: &DatabaseConfig) -> String {
+    match config.encoding {
+        Some((ref encoding, ref locale)) => format!(
+            " ENCODING={} LC_COLLATE={locale} LC_CTYPE={locale}",
+            quote_literal(encoding),
+            locale = quote_literal(locale)
+        ),
+        None => String::new(),
+    }
+}
+
+/// `err` creating a database, as [`Error::InvalidConfig`] if it was the
+/// server rejecting the [`DatabaseConfig::encoding`] and locale.
+fn invalid_encoding(config: &DatabaseConfig, err: Error) -> Error {
+    let (encoding, locale) = match config.encoding {
+        Some(ref encoding) => encoding,
+        None => return err,
+    };
+    match err {
+        // invalid_parameter_value for an encoding not matching the locale,
+        // wrong_object_type for a locale the server doesn't have.
+        Error::Postgres(ref pg_err)
+            if pg_err
+                .code()
+                .is_some_and(|code| code.code() == "22023" || code.code() == "42809") =>
+        {
+            Error::InvalidConfig(format!(
+                "The server can't create a database with encoding {} and locale {:?}: {}",
+                encoding,
+                locale,
+                pg_err
+                    .as_db()
+                    .map(|db| db.message.clone())
+                    .unwrap_or_else(|| pg_err.to_string())
+            ))
+        }
+        err => err,
+    }
+}
+
+/// Copy of `params` connecting as `role` instead.
+fn params_with_user(params: &ConnectParams, role: &str, password: &str) -> ConnectParams {
+    let mut new_params = ConnectParams::builder();
+    new_params
+        .port(params.port())
+        .user(role, Some(password))
+        .connect_timeout(params.connect_timeout());
+    if let Some(database) = params.database() {
+        new_params.database(database);
+    }
+    for (key, value) in params.options() {
+        new_params.option(key, value);
+    }
+    new_params.build(params.host().clone())
+}
+
+/// Params to connect to `database` as `role`, on the server of the admin
+/// `params`, with the options `config` gives the user connections.
+fn user_params(
+    config: &DatabaseConfig,
+    params: &ConnectParams,
+    role: &str,
+    password: &str,
+    database: &str,
+) -> ConnectParams {
+    let mut new_params = ConnectParams::builder();
+    new_params
+        .port(params.port())
+        .user(role, Some(password))
+        .database(database)
+        .connect_timeout(params.connect_timeout());
+    let inherited = if config.inherit_admin_options {
+        params.options()
+    } else {
+        &[]
+    };
+    for (key, value) in merge_options(inherited, &config.user_options) {
+        new_params.option(&key, &value);
+    }
+    new_params.build(params.host().clone())
+}
+
+/// `config`, or with `audit_sql` a copy with a recorder collecting the
+/// audited statements of just one database.
+fn audited_config(config: &DatabaseConfig) -> Cow<'_, DatabaseConfig> {
+    if !config.audit_sql {
+        return Cow::Borrowed(config);
+    }
+    let mut audited = config.clone();
+    audited.sql_recorder = Some(config.sql_recorder.clone().unwrap_or_default().with_audit());
+    Cow::Owned(audited)
+}
+
+fn connect_admin(
+    config: &DatabaseConfig,
+    purpose: ConnectionPurpose,
+    params: &ConnectParams,
+    tls_mode: &TlsMode,
+    database: Option<&str>,
+    session_setup: &[String],
+) -> Result<Connection> {
+    let conn = hook::connect(
+        config.connect_hook.as_ref(),
+        purpose,
+        &params_with_database(params, database),
+        tls_mode,
+    )?;
+    for sql in session_setup {
+        if let Some(ref recorder) = config.sql_recorder {
+            recorder.record_script(&conn, sql)?;
+        }
+        conn.batch_execute(sql)?;
+    }
+    Ok(conn)
+}
+
+fn install_extensions(
+    recorder: Option<&SqlRecorder>,
+    conn: &Connection,
+    extensions: &[String],
+) -> Result<()> {
+    if extensions.is_empty() {
+        return Ok(());
+    }
+    let available = available_extensions(conn)?;
+    for extension in extensions {
+        if !available.contains(extension) {
+            return Err(Error::ExtensionUnavailable {
+                extension: extension.clone(),
+                available,
+            });
+        }
+        debug!("Creating extension {:?}", extension);
+        record::batch_execute(
+            recorder,
+            conn,
+            &format!("CREATE EXTENSION IF NOT EXISTS {};", quote_ident(extension)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Run the setup step `sql` on `conn`, rerunning it as configured by
+/// [`DatabaseConfig::retry_setup_on`] if it fails with a transient error.
+fn execute_setup_step(config: &DatabaseConfig, conn: &Connection, sql: &str) -> Result<()> {
+    // Recorded once, since a replay shouldn't run into the same failure.
+    if let Some(ref recorder) = config.sql_recorder {
+        recorder.record_script(conn, sql)?;
+    }
+    let mut attempt = 0;
+    loop {
+        let err = match conn.batch_execute(sql) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let retryable = err.code().is_some_and(|code| {
+            config
+                .setup_retry_sqlstates
+                .iter()
+                .any(|retry| retry == code.code())
+        });
+        if !retryable || attempt >= config.setup_retries {
+            return Err(err.into());
+        }
+        attempt += 1;
+        warn!(
+            "Retrying setup step after a transient error (attempt {}): {}",
+            attempt, err
+        );
+        // In case the step failed inside a transaction of its own.
+        conn.batch_execute("ROLLBACK;")?;
+        std::thread::sleep(Duration::from_millis(10 * u64::from(attempt)));
+    }
+}
+
+/// Terminate every other backend connected to `database`.
+fn terminate_connections(conn: &Connection, database: &str) -> Result<()> {
+    let rows = conn.query(
+        "SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+            WHERE datname = $1 AND pid <> pg_backend_pid()",
+        &[&database],
+    )?;
+    debug!("Terminated {} connections to {:?}", rows.len(), database);
+    Ok(())
+}
+
+/// Everything between CREATE DATABASE and handing the database over.
+fn setup_database(
+    config: &DatabaseConfig,
+    params: &ConnectParams,
+    tls_mode: &TlsMode,
+    database_conn: &Connection,
+    dbname: &str,
+    new_params: &ConnectParams,
+) -> Result<()> {
+    let recorder = config.sql_recorder.as_ref();
+    if config.privilege_sql.is_none() {
+        record::batch_execute(
+            recorder,
+            database_conn,
+            &format!(
+                "REVOKE ALL ON DATABASE {dbname} FROM public;",
+                dbname = quote_ident(dbname)
+            ),
+        )?;
+    }
+    if config.refresh_collation_version {
+        if server_version_num(database_conn)? >= 150_000 {
+            record::batch_execute(
+                recorder,
+                database_conn,
+                &format!(
+                    "ALTER DATABASE {dbname} REFRESH COLLATION VERSION;",
+                    dbname = quote_ident(dbname)
+                ),
+            )?;
+        } else {
+            debug!("Not refreshing the collation version before postgres 15");
+        }
+    }
+    if config.comment_database {
+        let comment = config.comment.clone().unwrap_or_else(default_comment);
+        record::batch_execute(
+            recorder,
+            database_conn,
+            &format!(
+                "COMMENT ON DATABASE {dbname} IS {comment};",
+                dbname = quote_ident(dbname),
+                comment = quote_literal(&comment)
+            ),
+        )?;
+    }
+    // With a cached template the extensions and setup steps were copied along
+    // with it, and just need to change owner.
+    let (extensions, setup): (&[String], &[SetupStep]) = match config.cached_template {
+        Some(_) => (&[], &[]),
+        None => (&config.extensions, &config.setup),
+    };
+    if !extensions.is_empty() || config.cached_template.is_some() || config.privilege_sql.is_some()
+    {
+        // Closed again before the DROP DATABASE during cleanup.
+        let conn = connect_admin(
+            config,
+            ConnectionPurpose::TemporaryDatabaseAdmin,
+            params,
+            tls_mode,
+            Some(dbname),
+            &[],
+        )?;
+        install_extensions(recorder, &conn, extensions)?;
+        if let Some(ref template) = config.cached_template {
+            let role = new_params
+                .user()
+                .map(|user| user.name())
+                .unwrap_or_default();
+            template::reassign_owned(recorder, &conn, template, role)?;
+        }
+        if let Some(ref sql) = config.privilege_sql {
+            let role = new_params
+                .user()
+                .map(|user| user.name())
+                .unwrap_or_default();
+            // Recorded too, so a replay of the script still finds it.
+            record::batch_execute(
+                recorder,
+                &conn,
+                &format!(
+                    "SELECT set_config('kpg_fixture.role', {}, false);",
+                    quote_literal(role)
+                ),
+            )?;
+            record::batch_execute(recorder, &conn, sql)?;
+        }
+    }
+    if let Some(target) = config.default_statistics_target {
+        record::batch_execute(
+            recorder,
+            database_conn,
+            &format!(
+                "ALTER DATABASE {dbname} SET default_statistics_target = {target};",
+                dbname = quote_ident(dbname),
+                target = target
+            ),
+        )?;
+    }
+    for (name, value) in &config.database_settings {
+        // Setting names can't be passed as parameters or quoted as a whole.
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            return Err(Error::InvalidConfig(format!(
+                "Invalid setting name {:?}",
+                name
+            )));
+        }
+        record::batch_execute(
+            recorder,
+            database_conn,
+            &format!(
+                "ALTER DATABASE {dbname} SET {name} = {value};",
+                dbname = quote_ident(dbname),
+                name = name,
+                value = quote_literal(value)
+            ),
+        )?;
+    }
+    if !setup.is_empty() || config.analyze_after_setup {
+        let conn = hook::connect(
+            config.connect_hook.as_ref(),
+            ConnectionPurpose::Setup,
+            new_params,
+            tls_mode,
+        )?;
+        for step in setup {
+            execute_setup_step(config, &conn, &step.sql()?)?;
+        }
+        if config.analyze_after_setup {
+            if let Some(recorder) = recorder {
+                recorder.record_script(&conn, "ANALYZE;")?;
+            }
+            conn.batch_execute("ANALYZE;")?;
+        }
+    }
+    Ok(())
+}
+
+/// Given the parameters to connect to an existing postgres database, create
+/// new credentials to a temporary database for isolated testing.
+///
+/// CREATE DATABASE inside an existing postgres instance with
+/// random credentials and a random database name and pass the params
+/// required to create a connection to the closure and the TlsMode.
+///
+/// We pass the parameters so that you can create a connection however you want,
+/// with whatever library you want.
+///
+/// The admin params and TlsMode can be passed by value or by reference, so the
+/// same ones can be reused to create many databases.
+///
+/// Methodology taken from http://wiki.postgresql.org/wiki/Shared_Database_Hosting
+pub fn with_temporary_database<'a, T, P, M, F>(params: P, tls_mode: M, f: F) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(ConnectParams, TlsMode<'a>) -> T,
+{
+    with_temporary_database_config(params, tls_mode, &DatabaseConfig::default(), f)
+}
+
+/// Like [`with_temporary_database`], but with the setup customized by `config`.
+pub fn with_temporary_database_config<'a, T, P, M, F>(
+    params: P,
+    tls_mode: M,
+    config: &DatabaseConfig,
+    f: F,
+) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(ConnectParams, TlsMode<'a>) -> T,
+{
+    with_temporary_database_info(params, tls_mode, config, |info, tls_mode| {
+        f(info.params, tls_mode)
+    })
+}
+
+/// What was created for a temporary database.
+///
+/// This owns everything in it, so it can be kept around after the closure it
+/// was passed to, e.g. in a field of a test context struct.
+#[derive(Debug, Clone)]
+pub struct TempDbInfo {
+    /// Params to connect to the temporary database as its role.
+    pub params: ConnectParams,
+    /// Name of the temporary database.
+    pub database: String,
+    /// Name of the role owning the temporary database, e.g. to GRANT it more
+    /// privileges or SET ROLE to it.
+    pub role: String,
+    /// The names passed to [`DatabaseConfig::extra_role`], with the full names
+    /// of the roles created for them.
+    pub extra_roles: Vec<(String, String)>,
+    /// The names passed to [`DatabaseConfig::login_role`], with the params to
+    /// connect to the temporary database as the roles created for them.
+    pub login_roles: Vec<(String, ConnectParams)>,
+    /// The statements which set up the role, the database and their
+    /// privileges, in order, if [`DatabaseConfig::audit_sql`] is set.
+    pub audit_sql: Vec<String>,
+}
+
+impl TempDbInfo {
+    /// Full name of the role created for [`DatabaseConfig::extra_role`]
+    /// `name`.
+    pub fn extra_role(&self, name: &str) -> Option<&str> {
+        self.extra_roles
+            .iter()
+            .find(|(extra, _)| extra == name)
+            .map(|(_, role)| role.as_str())
+    }
+
+    /// Params to connect as the role created for
+    /// [`DatabaseConfig::login_role`] `name`, with its password.
+    pub fn login_role(&self, name: &str) -> Option<&ConnectParams> {
+        self.login_roles
+            .iter()
+            .find(|(login, _)| login == name)
+            .map(|(_, params)| params)
+    }
+
+    /// [`audit_sql`](#structfield.audit_sql) with what differs between runs
+    /// replaced by stable placeholders, for comparing against a golden file
+    /// to catch changes in how databases are provisioned: `<database>`,
+    /// `<role>`, `<role>_reader` for the extra or login role `reader`, and
+    /// `<comment>` for the default comments. The password is `<redacted>`
+    /// already. When the role is named after the database, as by default, it
+    /// is `<database>` too.
+    pub fn templated_audit_sql(&self) -> Vec<String> {
+        let role = if self.role == self.database {
+            "<database>"
+        } else {
+            "<role>"
+        };
+        let mut names: Vec<(&str, String)> = self
+            .extra_roles
+            .iter()
+            .map(|(extra, full)| (full.as_str(), format!("{}_{}", role, extra)))
+            .collect();
+        for (login, params) in &self.login_roles {
+            if let Some(user) = params.user() {
+                names.push((user.name(), format!("{}_{}", role, login)));
+            }
+        }
+        names.push((&self.role, role.to_owned()));
+        names.push((&self.database, "<database>".to_owned()));
+        self.audit_sql
+            .iter()
+            .map(|sql| {
+                let mut sql = sql.clone();
+                if let Some(start) = sql.find(&format!(" IS '{}", DEFAULT_COMMENT_PREFIX)) {
+                    if sql.starts_with("COMMENT ON ") {
+                        sql = format!("{} IS '<comment>';", &sql[..start]);
+                    }
+                }
+                for (name, placeholder) in &names {
+                    sql = sql
+                        .replace(&quote_ident(name), &quote_ident(placeholder))
+                        .replace(&quote_literal(name), &quote_literal(placeholder));
+                }
+                sql
+            })
+            .collect()
+    }
+}
+
+/// Like [`with_temporary_database_config`], but passes a [`TempDbInfo`]
+/// describing what was created instead of just the params.
+pub fn with_temporary_database_info<'a, T, P, M, F>(
+    params: P,
+    tls_mode: M,
+    config: &DatabaseConfig,
+    f: F,
+) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(TempDbInfo, TlsMode<'a>) -> T,
+{
+    let database = TemporaryDatabase::create(params, tls_mode, config)?;
+    let result = f(database.info.clone(), clone_tls_mode(&database.tls_mode));
+    database.drop_database()?;
+    Ok(result)
+}
+
+/// A `timestamptz` as sent in binary, microseconds since 2000-01-01 UTC, to
+/// hand one the server returned back to it exactly, whatever the session's
+/// DateStyle and TimeZone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timestamptz(i64);
+
+impl postgres::types::FromSql for Timestamptz {
+    fn from_sql(
+        _: &postgres::types::Type,
+        raw: &[u8],
+    ) -> std::result::Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let mut bytes = [0; 8];
+        if raw.len() != bytes.len() {
+            return Err("invalid timestamptz length".into());
+        }
+        bytes.copy_from_slice(raw);
+        Ok(Timestamptz(i64::from_be_bytes(bytes)))
+    }
+
+    fn accepts(ty: &postgres::types::Type) -> bool {
+        *ty == postgres::types::TIMESTAMPTZ
+    }
+}
+
+impl postgres::types::ToSql for Timestamptz {
+    fn to_sql(
+        &self,
+        _: &postgres::types::Type,
+        out: &mut Vec<u8>,
+    ) -> std::result::Result<postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
+    {
+        out.extend_from_slice(&self.0.to_be_bytes());
+        Ok(postgres::types::IsNull::No)
+    }
+
+    fn accepts(ty: &postgres::types::Type) -> bool {
+        *ty == postgres::types::TIMESTAMPTZ
+    }
+
+    fn to_sql_checked(
+        &self,
+        ty: &postgres::types::Type,
+        out: &mut Vec<u8>,
+    ) -> std::result::Result<postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
+    {
+        if !<Self as postgres::types::ToSql>::accepts(ty) {
+            return Err(format!("can't send a timestamptz as {}", ty).into());
+        }
+        self.to_sql(ty, out)
+    }
+}
+
+/// A temporary database and role, which are dropped again when this is
+/// dropped.
+///
+/// This is what the `with_temporary_database` functions use underneath, for
+/// when the database has to outlive a closure. Dropping only logs errors, use
+/// [`drop_database`](#method.drop_database) to get them.
+///
+/// The lifetime is the TLS handshake's: with `TlsMode::None` or a `'static`
+/// handshake this is a `TemporaryDatabase<'static>`, which can be stored in a
+/// struct field, e.g. of a test context built in a setup function.
+pub struct TemporaryDatabase<'a> {
+    info: TempDbInfo,
+    tls_mode: TlsMode<'a>,
+    /// Runs CREATE ROLE / DROP ROLE.
+    role_conn: Connection,
+    /// Runs CREATE DATABASE / DROP DATABASE, if that can't be `role_conn`.
+    database_conn: Option<Connection>,
+    recorder: Option<SqlRecorder>,
+    /// For opening more admin connections.
+    admin_params: ConnectParams,
+    connect_hook: Option<ConnectHook>,
+    /// The pid and backend_start of each connection handed out by
+    /// `admin_connection`, to terminate them before dropping the database.
+    admin_backends: std::sync::Mutex<Vec<(i32, Timestamptz)>>,
+    /// How long to wait before terminating connections to the database when
+    /// dropping it, if it should.
+    terminate_on_drop: Option<Duration>,
+    /// Made by the first `pool` call.
+    pool: std::cell::OnceCell<Pool>,
+    dropped: bool,
+}
+
+impl<'a> TemporaryDatabase<'a> {
+    /// Create a temporary database as described by `config`, using the admin
+    /// `params`.
+    pub fn create<P, M>(params: P, tls_mode: M, config: &DatabaseConfig) -> Result<Self>
+    where
+        P: Borrow<ConnectParams>,
+        M: Borrow<TlsMode<'a>>,
+    {
+        let params = params.borrow();
+        let tls_mode = tls_mode.borrow();
+        if let Some(ref name) = config.cached_template {
+            validate_identifier("cached template", name)?;
+            if config.template.is_some() {
+                return Err(Error::InvalidConfig(
+                    "template and cached_template can't both be set".to_owned(),
+                ));
+            }
+        }
+        validate_session_settings(&config.session_settings)?;
+        validate_encoding(config)?;
+        let config = &*audited_config(config);
+        let dbname = match config.database_name {
+            Some(ref name) => {
+                validate_identifier("database", name)?;
+                name.clone()
+            }
+            None => format!("kpg_fixture_{}", random_string(20)),
+        };
+        let rolename = match config.role_name {
+            Some(ref name) => {
+                validate_identifier("role", name)?;
+                name.clone()
+            }
+            None => dbname.clone(),
+        };
+        let dbmainuserpass = generate_password(
+            config.password_policy.as_ref(),
+            config.password_generator.as_ref(),
+        )?;
+
+        debug!(
+            "Creating database {:?} with password {:?} and default user {:?}",
+            dbname, dbmainuserpass, rolename
+        );
+        let new_params = user_params(config, params, &rolename, &dbmainuserpass, &dbname);
+        let admin_params = params_with_options(params, &config.admin_options);
+        let params = &admin_params;
+
+        let role_conn = connect_admin(
+            config,
+            ConnectionPurpose::RoleAdmin,
+            params,
+            tls_mode,
+            config.role_maintenance_database.as_deref(),
+            &config.role_session_setup,
+        )?;
+        // Only open a second admin connection if the two kinds of statements
+        // actually need to run in different places.
+        let separate_database_conn = config.database_maintenance_database
+            != config.role_maintenance_database
+            || config.database_session_setup != config.role_session_setup;
+        let database_conn = if separate_database_conn {
+            Some(connect_admin(
+                config,
+                ConnectionPurpose::DatabaseAdmin,
+                params,
+                tls_mode,
+                config.database_maintenance_database.as_deref(),
+                &config.database_session_setup,
+            )?)
+        } else {
+            None
+        };
+        let database_conn_ref = database_conn.as_ref().unwrap_or(&role_conn);
+        let recorder = config.sql_recorder.as_ref();
+        let extra_roles = config
+            .extra_roles
+            .iter()
+            .map(|name| {
+                let role = format!("{}_{}", rolename, name);
+                validate_identifier("role", &role)?;
+                Ok((name.clone(), role))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let login_roles = config
+            .login_roles
+            .iter()
+            .map(|(name, encryption)| {
+                let role = format!("{}_{}", rolename, name);
+                validate_identifier("role", &role)?;
+                let password = generate_password(
+                    config.password_policy.as_ref(),
+                    config.password_generator.as_ref(),
+                )?;
+                Ok((name.clone(), role, password, *encryption))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Setup a new user
+        // These must be executed separately since CREATE/DROP DATABASE cannot be executed inside a
+        // transaction and multi-statement queries are implicitly wrapped in a transaction.
+        // Ref: https://www.postgresql.org/docs/current/protocol-flow.html#PROTOCOL-FLOW-MULTI-STATEMENT
+        //
+        // INHERIT is required on postgres 15+, where the public schema belongs to pg_database_owner and
+        // the owner only gets to CREATE in it through inheriting that membership.
+        debug!("Setting up database");
+        let create_role = |password: &str| {
+            with_password_encryption(
+                config.password_encryption,
+                format!(
+                    "CREATE ROLE {rolename}
+                NOSUPERUSER NOCREATEDB NOCREATEROLE INHERIT
+                LOGIN ENCRYPTED PASSWORD {password};",
+                    rolename = quote_ident(&rolename),
+                    password = quote_literal(password)
+                ),
+            )
+        };
+        if let Some(recorder) = recorder {
+            recorder.record(&role_conn, &create_role("<redacted>"))?;
+        }
+        role_conn.batch_execute(&create_role(&dbmainuserpass))?;
+        // Try block this so I can rollback incrementally.
+        let created: Result<()> = try_!({
+            let role_comment = if config.comment_role {
+                Some(config.comment.clone().unwrap_or_else(default_comment))
+            } else {
+                None
+            };
+            let comment_on_role = |role: &str| -> Result<()> {
+                if let Some(ref comment) = role_comment {
+                    record::batch_execute(
+                        recorder,
+                        &role_conn,
+                        &format!(
+                            "COMMENT ON ROLE {role} IS {comment};",
+                            role = quote_ident(role),
+                            comment = quote_literal(comment)
+                        ),
+                    )?;
+                }
+                Ok(())
+            };
+            comment_on_role(&rolename)?;
+            for (_, role) in &extra_roles {
+                record::batch_execute(
+                    recorder,
+                    &role_conn,
+                    &format!(
+                        "CREATE ROLE {role} NOLOGIN NOBYPASSRLS; GRANT {role} TO {rolename};",
+                        role = quote_ident(role),
+                        rolename = quote_ident(&rolename)
+                    ),
+                )?;
+                comment_on_role(role)?;
+            }
+            // Each one hashed as asked, whatever the server's default is.
+            for (_, role, password, encryption) in &login_roles {
+                let create_login_role = |password: &str| {
+                    with_password_encryption(
+                        Some(*encryption),
+                        format!(
+                            "CREATE ROLE {role} NOSUPERUSER NOCREATEDB NOCREATEROLE INHERIT
+                            LOGIN ENCRYPTED PASSWORD {password}; GRANT {rolename} TO {role};",
+                            role = quote_ident(role),
+                            password = quote_literal(password),
+                            rolename = quote_ident(&rolename)
+                        ),
+                    )
+                };
+                if let Some(recorder) = recorder {
+                    recorder.record(&role_conn, &create_login_role("<redacted>"))?;
+                }
+                role_conn.batch_execute(&create_login_role(password))?;
+                comment_on_role(role)?;
+            }
+            let template = match (&config.template, &config.cached_template) {
+                (Some(template), _) => {
+                    if config.terminate_template_connections {
+                        terminate_connections(database_conn_ref, template)?;
+                    }
+                    format!(" TEMPLATE={}", quote_ident(template))
+                }
+                (None, Some(template)) => format!(" TEMPLATE={}", quote_ident(template)),
+                (None, None) if config.encoding.is_some() => " TEMPLATE=template0".to_owned(),
+                (None, None) => String::new(),
+            };
+            let tablespace = match config.tablespace {
+                Some(ref tablespace) => format!(" TABLESPACE={}", quote_ident(tablespace)),
+                None => String::new(),
+            };
+            let create_database = || -> Result<()> {
+                record::batch_execute(
+                    recorder,
+                    database_conn_ref,
+                    &format!(
+                        "CREATE DATABASE {dbname} WITH OWNER={rolename}{template}{encoding}{tablespace};",
+                        dbname = quote_ident(&dbname),
+                        rolename = quote_ident(&rolename),
+                        template = template,
+                        encoding = encoding_clause(config),
+                        tablespace = tablespace
+                    ),
+                )
+                .map_err(|err| invalid_encoding(config, err))
+            };
+            match config.cached_template {
+                // Held until the copy is made, so nobody rebuilds the template or
+                // is connected to it in the meantime.
+                Some(ref name) => {
+                    template::lock(database_conn_ref, name)?;
+                    let created =
+                        template::ensure(config, params, tls_mode, database_conn_ref, name)
+                            .and_then(|()| create_database());
+                    template::unlock(database_conn_ref, name)?;
+                    created?;
+                }
+                None => create_database()?,
+            }
+            let setup = setup_database(
+                config,
+                params,
+                tls_mode,
+                database_conn_ref,
+                &dbname,
+                &new_params,
+            );
+            if setup.is_err() {
+                record::batch_execute(
+                    recorder,
+                    database_conn_ref,
+                    &format!("DROP DATABASE {dbname};", dbname = quote_ident(&dbname)),
+                )?;
+            }
+            setup?
+        });
+        if created.is_err() {
+            let login_role_names = login_roles.iter().map(|(_, role, _, _)| role);
+            for role in extra_roles
+                .iter()
+                .map(|(_, role)| role)
+                .chain(login_role_names)
+            {
+                record::batch_execute(
+                    recorder,
+                    &role_conn,
+                    &format!("DROP ROLE IF EXISTS {};", quote_ident(role)),
+                )?;
+            }
+            record::batch_execute(
+                recorder,
+                &role_conn,
+                &format!("DROP ROLE {rolename};", rolename = quote_ident(&rolename)),
+            )?;
+        }
+        created?;
+        debug!("Finished setting up database");
+        let login_roles = login_roles
+            .into_iter()
+            .map(|(name, role, password, _)| {
+                (name, params_with_user(&new_params, &role, &password))
+            })
+            .collect();
+
+        Ok(TemporaryDatabase {
+            info: TempDbInfo {
+                params: new_params,
+                database: dbname,
+                role: rolename,
+                extra_roles,
+                login_roles,
+                audit_sql: recorder.map(SqlRecorder::audited).unwrap_or_default(),
+            },
+            tls_mode: clone_tls_mode(tls_mode),
+            role_conn,
+            database_conn,
+            recorder: recorder.cloned(),
+            admin_params: params.clone(),
+            connect_hook: config.connect_hook.clone(),
+            admin_backends: std::sync::Mutex::new(Vec::new()),
+            terminate_on_drop: if config.terminate_connections_on_drop {
+                Some(config.drop_wait)
+            } else {
+                None
+            },
+            pool: std::cell::OnceCell::new(),
+            dropped: false,
+        })
+    }
+
+    /// What was created.
+    pub fn info(&self) -> &TempDbInfo {
+        &self.info
+    }
+
+    /// Params to connect to the temporary database as its role.
+    pub fn params(&self) -> &ConnectParams {
+        &self.info.params
+    }
+
+    /// Open another connection with the admin params and TLS mode the database
+    /// was created with, e.g. to hold an advisory lock on one connection while
+    /// creating databases on another.
+    ///
+    /// Any of these still open when the database is dropped are terminated
+    /// first, so they can't keep the cleanup from succeeding.
+    pub fn admin_connection(&self) -> Result<Connection> {
+        let conn = hook::connect(
+            self.connect_hook.as_ref(),
+            ConnectionPurpose::AdditionalAdmin,
+            &self.admin_params,
+            &self.tls_mode,
+        )?;
+        let rows = conn.query(
+            "SELECT pid, backend_start FROM pg_stat_activity WHERE pid = pg_backend_pid()",
+            &[],
+        )?;
+        let row = rows.get(0);
+        self.admin_backends
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push((row.get(0), row.get(1)));
+        Ok(conn)
+    }
+
+    /// A [`Pool`] of `size` connections to the temporary database as its role,
+    /// opened by the first call and the same for the later ones, which fail if
+    /// they ask for another size. The pool is closed before the database is
+    /// dropped.
+    pub fn pool(&self, size: usize) -> Result<&Pool> {
+        if self.pool.get().is_none() {
+            let pool = Pool::connect(
+                self.connect_hook.as_ref(),
+                &self.info.params,
+                &self.tls_mode,
+                size,
+            )?;
+            let _ = self.pool.set(pool);
+        }
+        let pool = self.pool.get().unwrap();
+        if pool.size() != size {
+            return Err(Error::InvalidConfig(format!(
+                "The pool was already opened with {} connections, not {}",
+                pool.size(),
+                size
+            )));
+        }
+        Ok(pool)
+    }
+
+    /// Drop the database and the role, reporting errors that dropping this
+    /// would only log. Fails if anything is still connected to the database.
+    pub fn drop_database(mut self) -> Result<()> {
+        self.cleanup()
+    }
+
+    /// ROLLBACK PREPARED whatever prepared transactions were left in the
+    /// database, which would keep it and the role from being dropped. This
+    /// has to run on a connection to the database itself.
+    fn rollback_prepared_transactions(&self) -> Result<()> {
+        let prepared = self.role_conn.query(
+            "SELECT gid FROM pg_prepared_xacts WHERE database = $1",
+            &[&self.info.database],
+        )?;
+        if prepared.is_empty() {
+            return Ok(());
+        }
+        let conn = hook::connect(
+            self.connect_hook.as_ref(),
+            ConnectionPurpose::TemporaryDatabaseAdmin,
+            &params_with_database(&self.admin_params, Some(&self.info.database)),
+            &self.tls_mode,
+        )?;
+        for row in prepared.iter() {
+            let gid: String = row.get(0);
+            warn!(
+                "Rolling back prepared transaction {:?} left in {:?}",
+                gid, self.info.database
+            );
+            record::batch_execute(
+                self.recorder.as_ref(),
+                &conn,
+                &format!("ROLLBACK PREPARED {};", quote_literal(&gid)),
+            )?;
+        }
+        conn.finish()?;
+        Ok(())
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        self.dropped = true;
+        debug!("Starting cleanup");
+        let database_conn = self.database_conn.as_ref().unwrap_or(&self.role_conn);
+        let recorder = self.recorder.as_ref();
+        // The first failure, returned once everything else has been tried, so
+        // one failed step doesn't leak the database and roles.
+        let mut failed = None;
+        let mut keep = |result: Result<()>| {
+            if let Err(err) = result {
+                if failed.is_none() {
+                    failed = Some(err);
+                } else {
+                    warn!("Cleanup failed again: {:?}", err);
+                }
+            }
+        };
+        let admin_backends = std::mem::take(
+            &mut *self
+                .admin_backends
+                .lock()
+                .unwrap_or_else(|err| err.into_inner()),
+        );
+        for (pid, backend_start) in &admin_backends {
+            // Matching on backend_start too, in case a pid has been reused.
+            keep(
+                self.role_conn
+                    .execute(
+                        "SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+                        WHERE pid = $1 AND backend_start = $2",
+                        &[pid, backend_start],
+                    )
+                    .map(|_| ())
+                    .map_err(Error::from),
+            );
+        }
+        if let Some(pool) = self.pool.take() {
+            // Closed either way, which is all that matters for the DROP.
+            if let Err(err) = pool.close() {
+                warn!("Failed to close the pool cleanly: {:?}", err);
+            }
+        }
+        keep(self.rollback_prepared_transactions());
+        if let Some(wait) = self.terminate_on_drop {
+            let database = &self.info.database;
+            let deadline = std::time::Instant::now() + wait;
+            loop {
+                let connected = self.role_conn.query(
+                    "SELECT 1 FROM pg_stat_activity WHERE datname = $1 AND pid <> pg_backend_pid()",
+                    &[database],
+                )?;
+                if connected.is_empty() {
+                    break;
+                }
+                if std::time::Instant::now() >= deadline {
+                    terminate_connections(&self.role_conn, database)?;
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        keep(record::batch_execute(
+            recorder,
+            database_conn,
+            &format!(
+                "DROP DATABASE {dbname};",
+                dbname = quote_ident(&self.info.database)
+            ),
+        ));
+        let login_roles = self.info.login_roles.iter().map(|(_, params)| {
+            params
+                .user()
+                .map(|user| user.name())
+                .expect("login role params have a user")
+        });
+        for role in self
+            .info
+            .extra_roles
+            .iter()
+            .map(|(_, role)| role.as_str())
+            .chain(login_roles)
+        {
+            keep(record::batch_execute(
+                recorder,
+                &self.role_conn,
+                &format!("DROP ROLE {};", quote_ident(role)),
+            ));
+        }
+        keep(record::batch_execute(
+            recorder,
+            &self.role_conn,
+            &format!(
+                "DROP ROLE {rolename};",
+                rolename = quote_ident(&self.info.role)
+            ),
+        ));
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        debug!("Finished cleanup");
+        Ok(())
+    }
+}
+
+impl<'a> Drop for TemporaryDatabase<'a> {
+    fn drop(&mut self) {
+        if !self.dropped {
+            if let Err(err) = self.cleanup() {
+                warn!(
+                    "Failed to drop temporary database {:?}: {:?}",
+                    self.info.database, err
+                );
+            }
+        }
+    }
+}
+
+impl<'a> std::fmt::Debug for TemporaryDatabase<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TemporaryDatabase")
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+/// Entry points for the code generated by `#[kpostgres_fixture::test]`.
+#[doc(hidden)]
+pub mod __macro_support {
+    use super::*;
+    use postgres::params::IntoConnectParams;
+
+    fn connect_and_run<T, F: FnOnce(Connection) -> T>(
+        params: ConnectParams,
+        tls_mode: TlsMode,
+        f: F,
+    ) -> Result<T> {
+        with_temporary_database_conn(params, tls_mode, f)
+    }
+
+    pub fn run_with_database<T, F: FnOnce(Connection) -> T>(url: &str, f: F) -> T {
+        let params = url
+            .into_connect_params()
+            .unwrap_or_else(|err| panic!("Invalid database url {:?}: {}", url, err));
+        connect_and_run(params, TlsMode::None, f).expect("Failed to create temporary database")
+    }
+
+    #[cfg(feature = "docker")]
+    pub fn run_with_postgres<T, F: FnOnce(Connection) -> T>(docker_image: &str, f: F) -> T {
+        with_temporary_postgres(docker_image, |params, tls_mode, _| {
+            connect_and_run(params, tls_mode, f)
+        })
+        .expect("Failed to create temporary postgres")
+        .expect("Failed to create temporary database")
+    }
+}
+
+/// Like [`with_temporary_database`], but connects to the temporary database
+/// and passes the connection instead of the params.
+pub fn with_temporary_database_conn<'a, T, P, M, F>(params: P, tls_mode: M, f: F) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(Connection) -> T,
+{
+    with_temporary_database_conn_config(params, tls_mode, &DatabaseConfig::default(), f)
+}
+
+/// Like [`with_temporary_database_conn`], but with the setup customized by
+/// `config`. The connection has the config's session settings applied.
+pub fn with_temporary_database_conn_config<'a, T, P, M, F>(
+    params: P,
+    tls_mode: M,
+    config: &DatabaseConfig,
+    f: F,
+) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(Connection) -> T,
+{
+    with_temporary_database_config(params, tls_mode, config, |params, tls_mode| {
+        Ok(f(connect_user(config, &params, &tls_mode)?))
+    })?
+}
+
+/// Like [`with_temporary_database_conn_config`], but runs the closure in a
+/// transaction where every statement gets a savepoint of its own, see
+/// [`with_statement_savepoints`].
+pub fn with_temporary_database_conn_savepoints<'a, T, P, M, F>(
+    params: P,
+    tls_mode: M,
+    config: &DatabaseConfig,
+    f: F,
+) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(&StatementSavepoints) -> T,
+{
+    with_temporary_database_conn_config(params, tls_mode, config, |conn| {
+        with_statement_savepoints(&conn, f)
+    })?
+}
+
+/// Connect to the temporary database as its role, with the session settings
+/// of `config` applied.
+fn connect_user(
+    config: &DatabaseConfig,
+    params: &ConnectParams,
+    tls_mode: &TlsMode,
+) -> Result<Connection> {
+    let conn = hook::connect(
+        config.connect_hook.as_ref(),
+        ConnectionPurpose::User,
+        params,
+        tls_mode,
+    )?;
+    for (name, value) in &config.session_settings {
+        if let Some(ref recorder) = config.sql_recorder {
+            recorder.record_script(
+                &conn,
+                &format!(
+                    "SELECT set_config({}, {}, false);",
+                    quote_literal(name),
+                    quote_literal(value)
+                ),
+            )?;
+        }
+        conn.execute("SELECT set_config($1, $2, false)", &[name, value])?;
+    }
+    Ok(conn)
+}
+
+/// Like [`with_temporary_database_conn_config`], but instead of dropping the
+/// connection and the database after the closure, returns them along with its
+/// value in a [`TempDbConnection`], which drops them when it is dropped.
+///
+/// Lets a closure do the setup while the caller keeps using the connection,
+/// e.g. when moving from the closure API to [`TemporaryDatabase`].
+pub fn with_temporary_database_conn_kept<'a, T, P, M, F>(
+    params: P,
+    tls_mode: M,
+    config: &DatabaseConfig,
+    f: F,
+) -> Result<(T, TempDbConnection<'a>)>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(&Connection) -> T,
+{
+    let database = TemporaryDatabase::create(params, tls_mode, config)?;
+    let conn = connect_user(config, database.params(), &database.tls_mode)?;
+    let result = f(&conn);
+    Ok((result, TempDbConnection { conn, database }))
+}
+
+/// A connection to a temporary database, kept together with the database so
+/// that it is closed before the database is dropped.
+#[derive(Debug)]
+pub struct TempDbConnection<'a> {
+    // Declared first so it is dropped first.
+    conn: Connection,
+    database: TemporaryDatabase<'a>,
+}
+
+impl<'a> TempDbConnection<'a> {
+    /// What was created.
+    pub fn info(&self) -> &TempDbInfo {
+        self.database.info()
+    }
+
+    /// Close the connection and drop the database, reporting errors that
+    /// dropping this would only log.
+    pub fn close(self) -> Result<()> {
+        let TempDbConnection { conn, database } = self;
+        conn.finish()?;
+        database.drop_database()
+    }
+}
+
+impl<'a> std::ops::Deref for TempDbConnection<'a> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // The glob above also pulls in `#[kpostgres_fixture::test]`.
+    use core::prelude::v1::test;
+    use postgres::params;
+
+    use std::sync::Once;
+
+    static INIT: Once = Once::new();
+
+    /// Superuser params for the local server the tests run against.
+    pub(crate) fn admin_params() -> ConnectParams {
+        ConnectParams::builder()
+            .port(5432)
+            .user("postgres", None)
+            .database("postgres")
+            .build(params::Host::Tcp("localhost".to_owned()))
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn temp_pg() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let result = with_temporary_postgres("postgres:11", |params, tls_mode, _| -> Result<()> {
+            // let conn = Connection::connect(params.clone(), clone_tls_mode(&tls_mode))?;
+            with_temporary_database(params, tls_mode, |params, tls_mode| -> Result<()> {
+                let conn = Connection::connect(params, tls_mode)?;
+                conn.batch_execute("CREATE TABLE test()")?;
+                conn.execute("TABLE test", &[])?;
+                Ok(())
+            })?
+        })
+        .expect("Failed to create temporary database");
+        println!("{:#?}", result);
+        result.expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        // let conn = Connection::connect(params.clone(), clone_tls_mode(&tls_mode))?;
+        let result = with_temporary_database(
+            connect_params,
+            TlsMode::None,
+            |params, tls_mode| -> Result<()> {
+                let conn = Connection::connect(params, tls_mode)?;
+                conn.batch_execute("CREATE TABLE test()")?;
+                conn.execute("TABLE test", &[])?;
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database");
+        println!("{:#?}", result);
+        result.expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_separate_maintenance_databases() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config
+            .role_maintenance_database("postgres")
+            .role_session_setup("SET application_name = 'kpg_fixture_roles'")
+            .database_session_setup("SET application_name = 'kpg_fixture_databases'");
+        let result = with_temporary_database_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |params, tls_mode| -> Result<()> {
+                let conn = Connection::connect(params, tls_mode)?;
+                conn.batch_execute("CREATE TABLE test()")?;
+                conn.execute("TABLE test", &[])?;
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database");
+        result.expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_reuse_params() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let tls_mode = TlsMode::None;
+        let mut dbnames = Vec::new();
+        for _ in 0..3 {
+            let dbname = with_temporary_database(&connect_params, &tls_mode, |params, _| {
+                params.database().unwrap().to_owned()
+            })
+            .expect("Failed to create temporary database");
+            dbnames.push(dbname);
+        }
+        dbnames.dedup();
+        assert_eq!(dbnames.len(), 3);
+    }
+
+    #[test]
+    fn temp_db_comment() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        fn comment_of_current_database(params: ConnectParams, tls_mode: TlsMode) -> Result<String> {
+            let conn = Connection::connect(params, tls_mode)?;
+            let rows = conn.query(
+                "SELECT shobj_description(oid, 'pg_database') FROM pg_database
+                    WHERE datname = current_database()",
+                &[],
+            )?;
+            Ok(rows.get(0).get(0))
+        }
+
+        let comment = with_temporary_database(
+            connect_params.clone(),
+            TlsMode::None,
+            comment_of_current_database,
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert!(comment.starts_with("Created by kpostgres_fixture"));
+        assert!(comment.contains("temp_db_comment"));
+
+        let mut config = DatabaseConfig::new();
+        config.comment("it's mine");
+        let comment = with_temporary_database_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            comment_of_current_database,
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert_eq!(comment, "it's mine");
+    }
+
+    #[test]
+    fn temp_db_role_comment() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        fn role_comments(params: ConnectParams, tls_mode: TlsMode) -> Result<Vec<Option<String>>> {
+            let conn = Connection::connect(params, tls_mode)?;
+            let rows = conn.query(
+                "SELECT shobj_description(oid, 'pg_authid') FROM pg_roles
+                    WHERE rolname IN (session_user, session_user || '_reader')
+                    ORDER BY rolname",
+                &[],
+            )?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        }
+
+        let mut config = DatabaseConfig::new();
+        config.extra_role("reader");
+        let comments = with_temporary_database_config(
+            connect_params.clone(),
+            TlsMode::None,
+            &config,
+            role_comments,
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert_eq!(comments.len(), 2);
+        for comment in comments {
+            let comment = comment.unwrap();
+            assert!(comment.starts_with("Created by kpostgres_fixture"));
+            assert!(comment.contains("temp_db_role_comment"));
+        }
+
+        config.comment_role(false);
+        let comments =
+            with_temporary_database_config(connect_params, TlsMode::None, &config, role_comments)
+                .expect("Failed to create temporary database")
+                .expect("Inner result failed");
+        assert_eq!(comments, vec![None, None]);
+    }
+
+    #[test]
+    fn temp_db_md5_and_scram_roles() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let admin = Connection::connect(connect_params.clone(), TlsMode::None).unwrap();
+        let hash = |role: &str| -> String {
+            admin
+                .query(
+                    "SELECT rolpassword FROM pg_authid WHERE rolname = $1",
+                    &[&role],
+                )
+                .unwrap()
+                .get(0)
+                .get(0)
+        };
+        let mut config = DatabaseConfig::new();
+        config
+            .password_encryption(PasswordEncryption::Md5)
+            .md5_and_scram_roles();
+        let roles = with_temporary_database_info(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |info, tls_mode| {
+                assert!(hash(&info.role).starts_with("md5"));
+                let md5 = info.login_role("md5").unwrap();
+                let scram = info.login_role("scram").unwrap();
+                assert!(info.login_role("other").is_none());
+                let mut roles = Vec::new();
+                for (params, prefix) in [(md5, "md5"), (scram, "SCRAM-SHA-256$")] {
+                    let role = params.user().unwrap().name().to_owned();
+                    assert!(
+                        hash(&role).starts_with(prefix),
+                        "{} is not {}",
+                        role,
+                        prefix
+                    );
+                    let conn =
+                        Connection::connect(params.clone(), clone_tls_mode(&tls_mode)).unwrap();
+                    // Can use what the temporary role owns.
+                    conn.batch_execute("CREATE TABLE test (id int); DROP TABLE test;")
+                        .unwrap();
+                    conn.finish().unwrap();
+                    roles.push(role);
+                }
+                roles
+            },
+        )
+        .expect("Failed to create temporary database");
+        let left = admin
+            .query("SELECT 1 FROM pg_roles WHERE rolname = ANY($1)", &[&roles])
+            .unwrap();
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn temp_db_password_policy() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut policy = PasswordPolicy::new();
+        policy
+            .min_length(40)
+            .require_uppercase(true)
+            .require_digit(true)
+            .require_special(true);
+        let mut config = DatabaseConfig::new();
+        config.password_policy(policy.clone());
+        let result = with_temporary_database_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |params, tls_mode| -> Result<()> {
+                let password = params.user().and_then(|user| user.password()).unwrap();
+                assert_eq!(policy.check(password), Ok(()));
+                Connection::connect(params, tls_mode)?;
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database");
+        result.expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_extensions() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.extension("citext");
+        with_temporary_database_config(
+            &connect_params,
+            TlsMode::None,
+            &config,
+            |params, tls_mode| -> Result<()> {
+                let conn = Connection::connect(params, tls_mode)?;
+                conn.batch_execute("SELECT 'A'::citext = 'a'::citext")?;
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+
+        config.extension("kpg_fixture_no_such_extension");
+        match with_temporary_database_config(&connect_params, TlsMode::None, &config, |_, _| ()) {
+            Err(Error::ExtensionUnavailable {
+                extension,
+                available,
+            }) => {
+                assert_eq!(extension, "kpg_fixture_no_such_extension");
+                assert!(available.contains(&"citext".to_owned()));
+            }
+            other => panic!("Expected ExtensionUnavailable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn temp_db_setup_and_analyze() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config
+            .setup_sql("CREATE TABLE test (id int)")
+            .setup_sql("INSERT INTO test SELECT generate_series(1, 1000)")
+            .default_statistics_target(500)
+            .analyze_after_setup(true);
+        with_temporary_database_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |params, tls_mode| -> Result<()> {
+                let conn = Connection::connect(params, tls_mode)?;
+                let target: String = conn
+                    .query("SHOW default_statistics_target", &[])?
+                    .get(0)
+                    .get(0);
+                assert_eq!(target, "500");
+                let analyzed: i64 = conn
+                    .query(
+                        "SELECT count(*) FROM pg_stats WHERE tablename = 'test'",
+                        &[],
+                    )?
+                    .get(0)
+                    .get(0);
+                assert_eq!(analyzed, 1);
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_conn_session_settings() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.timezone("Pacific/Auckland").datestyle("SQL, DMY");
+        let formatted = with_temporary_database_conn_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |conn| -> Result<String> {
+                let rows = conn.query("SELECT '2019-07-31 00:00:00+00'::timestamptz::text", &[])?;
+                Ok(rows.get(0).get(0))
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert_eq!(formatted, "31/07/2019 12:00:00 NZST");
+    }
+
+    #[test]
+    fn temp_db_conn_output_settings() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config
+            .bytea_output(ByteaOutput::Escape)
+            .extra_float_digits(-1);
+        let formatted = with_temporary_database_conn_config(
+            &connect_params,
+            TlsMode::None,
+            &config,
+            |conn| -> Result<(String, String)> {
+                let rows = conn.query(
+                    "SELECT '\\x41ff'::bytea::text, (1.0::float8 / 3)::text",
+                    &[],
+                )?;
+                Ok((rows.get(0).get(0), rows.get(0).get(1)))
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert_eq!(
+            formatted,
+            ("A\\377".to_owned(), "0.33333333333333".to_owned())
+        );
+
+        let mut config = DatabaseConfig::new();
+        config.extra_float_digits(4);
+        match with_temporary_database_conn_config(&connect_params, TlsMode::None, &config, |_| ()) {
+            Err(Error::InvalidConfig(_)) => {}
+            other => panic!("Expected InvalidConfig, got {:?}", other),
+        }
+        let mut config = DatabaseConfig::new();
+        config.session_setting("bytea_output", "base64");
+        match with_temporary_database_conn_config(&connect_params, TlsMode::None, &config, |_| ()) {
+            Err(Error::InvalidConfig(_)) => {}
+            other => panic!("Expected InvalidConfig, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn temp_db_latin1_encoding() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.encoding("latin1", "C");
+        with_temporary_database_conn_config(
+            &connect_params,
+            TlsMode::None,
+            &config,
+            |conn| -> Result<()> {
+                assert_eq!(show(&conn, "server_encoding")?, "LATIN1");
+                assert_eq!(show(&conn, "client_encoding")?, "UTF8");
+                assert_eq!(show(&conn, "lc_collate")?, "C");
+                conn.batch_execute("CREATE TABLE test (t text)")?;
+                conn.execute("INSERT INTO test VALUES ($1)", &[&"\u{e9}"])?;
+                let rows = conn.query("SELECT t, octet_length(t) FROM test", &[])?;
+                assert_eq!(rows.get(0).get::<_, String>(0), "\u{e9}");
+                // Stored as the single LATIN1 byte.
+                assert_eq!(rows.get(0).get::<_, i32>(1), 1);
+                // Not in LATIN1.
+                assert!(conn
+                    .execute("INSERT INTO test VALUES ($1)", &[&"\u{20ac}"])
+                    .is_err());
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+
+        for (encoding, locale, template) in [
+            ("SJIS", "C", None),
+            ("LATIN1", "", None),
+            ("LATIN1", "C", Some("template1")),
+            ("LATIN1", "C.UTF-8", None),
+            ("LATIN1", "kpg_fixture_no_such_locale", None),
+        ] {
+            let mut config = DatabaseConfig::new();
+            config.encoding(encoding, locale);
+            if let Some(template) = template {
+                config.template(template);
+            }
+            match with_temporary_database_config(&connect_params, TlsMode::None, &config, |_, _| ())
+            {
+                Err(Error::InvalidConfig(message)) => debug!("{}", message),
+                other => panic!(
+                    "Expected InvalidConfig for {} {:?}, got {:?}",
+                    encoding, locale, other
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn temp_db_from_template() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let golden = format!("kpg_fixture_golden_{}", random_string(20));
+        let admin = Connection::connect(connect_params.clone(), TlsMode::None).unwrap();
+        admin
+            .batch_execute(&format!("CREATE DATABASE {}", quote_ident(&golden)))
+            .unwrap();
+        let result = (|| -> Result<i32> {
+            // Left open, so the copy only works because it is terminated.
+            let golden_conn = Connection::connect(
+                params_with_database(&connect_params, Some(&golden)),
+                TlsMode::None,
+            )?;
+            golden_conn.batch_execute(
+                "CREATE TABLE test (id int);
+                INSERT INTO test VALUES (42);
+                GRANT SELECT ON test TO public;",
+            )?;
+
+            let mut config = DatabaseConfig::new();
+            config
+                .template(&golden)
+                .terminate_template_connections(true);
+            with_temporary_database_conn_config(
+                &connect_params,
+                TlsMode::None,
+                &config,
+                |conn| -> Result<i32> { Ok(conn.query("TABLE test", &[])?.get(0).get(0)) },
+            )?
+        })();
+        admin
+            .batch_execute(&format!("DROP DATABASE {}", quote_ident(&golden)))
+            .unwrap();
+        assert_eq!(result.expect("Failed to copy template"), 42);
+    }
+
+    #[test]
+    fn temp_db_cached_template() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let name = format!("kpg_fixture_cached_{}", random_string(20));
+        let admin = Connection::connect(connect_params.clone(), TlsMode::None).unwrap();
+        let template_oid = || -> u32 {
+            admin
+                .query("SELECT oid FROM pg_database WHERE datname = $1", &[&name])
+                .unwrap()
+                .get(0)
+                .get(0)
+        };
+        let run = |config: &DatabaseConfig| -> Result<i64> {
+            with_temporary_database_conn_config(&connect_params, TlsMode::None, config, |conn| {
+                // Owned by the temporary role, so it can do anything with it.
+                conn.batch_execute("INSERT INTO test VALUES (2); ALTER TABLE test ADD b int;")?;
+                Ok(conn.query("SELECT count(*) FROM test", &[])?.get(0).get(0))
+            })?
+        };
+
+        let result = (|| -> Result<()> {
+            let mut config = DatabaseConfig::new();
+            config
+                .cached_template(&name)
+                .extension("citext")
+                .setup_sql("CREATE TABLE test (a citext); INSERT INTO test VALUES ('1');");
+            assert_eq!(run(&config)?, 2);
+            let built = template_oid();
+            assert_eq!(run(&config)?, 2);
+            assert_eq!(
+                template_oid(),
+                built,
+                "Template was rebuilt for the same inputs"
+            );
+
+            config.setup_sql("INSERT INTO test VALUES ('3');");
+            assert_eq!(run(&config)?, 3);
+            assert_ne!(
+                template_oid(),
+                built,
+                "Template wasn't rebuilt for new inputs"
+            );
+            Ok(())
+        })();
+        drop_cached_template(&admin, &name).unwrap();
+        result.expect("Failed to use cached template");
+    }
+
+    #[test]
+    fn temp_db_refresh_collation_version() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.refresh_collation_version(true);
+        with_temporary_database_conn_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |conn| -> Result<()> {
+                if server_version_num(&conn)? < 150_000 {
+                    return Ok(());
+                }
+                let rows = conn.query(
+                    "SELECT datcollversion IS NOT DISTINCT FROM
+                    pg_database_collation_actual_version(oid)
+                    FROM pg_database WHERE datname = current_database()",
+                    &[],
+                )?;
+                assert!(rows.get(0).get::<_, bool>(0));
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_cloned_config() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut base = DatabaseConfig::new();
+        base.setup_sql("CREATE TABLE base ()");
+        let mut variant = base.clone();
+        variant.setup_sql("CREATE TABLE variant ()");
+
+        let tables = |config: &DatabaseConfig| {
+            with_temporary_database_conn_config(&connect_params, TlsMode::None, config, |conn| {
+                let rows = conn
+                    .query(
+                        "SELECT tablename::text FROM pg_tables WHERE schemaname = 'public'
+                        ORDER BY tablename",
+                        &[],
+                    )
+                    .unwrap();
+                rows.iter().map(|row| row.get(0)).collect::<Vec<String>>()
+            })
+            .expect("Failed to create temporary database")
+        };
+        assert_eq!(tables(&base), vec!["base"]);
+        assert_eq!(tables(&variant), vec!["base", "variant"]);
+    }
+
+    #[test]
+    fn temp_db_retry_setup() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        // Sequences aren't rolled back, so only the first attempt fails.
+        let mut config = DatabaseConfig::new();
+        config.setup_sql("CREATE SEQUENCE attempts").setup_sql(
+            "DO $$ BEGIN
+                IF nextval('attempts') = 1 THEN
+                    RAISE EXCEPTION 'transient' USING ERRCODE = '40001';
+                END IF;
+            END $$;
+            CREATE TABLE test ();",
+        );
+        with_temporary_database_conn_config(&connect_params, TlsMode::None, &config, |conn| {
+            conn.execute("TABLE test", &[]).unwrap();
+        })
+        .expect("Failed to create temporary database");
+
+        config.retry_setup_on(&[]);
+        let failed =
+            with_temporary_database_config(&connect_params, TlsMode::None, &config, |_, _| ());
+        assert!(matches!(failed, Err(Error::Postgres(_))));
+    }
+
+    #[test]
+    fn temp_db_record_sql() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let path =
+            std::env::temp_dir().join(format!("kpg_fixture_{}_record.sql", random_string(8)));
+        let mut config = DatabaseConfig::new();
+        config
+            .record_sql(&path)
+            .setup_sql("CREATE TABLE test (id int)")
+            .session_setting("timezone", "UTC");
+        let info =
+            with_temporary_database_info(&connect_params, TlsMode::None, &config, |info, _| info)
+                .expect("Failed to create temporary database");
+        let script = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let role = quote_ident(&info.role);
+        let database = quote_ident(&info.database);
+        let create_role = script.find(&format!("CREATE ROLE {}", role)).unwrap();
+        let create_database = script
+            .find(&format!("CREATE DATABASE {}", database))
+            .unwrap();
+        let setup = script
+            .find(&format!(
+                "\\connect {} {}\nCREATE TABLE test (id int);\n",
+                database, role
+            ))
+            .unwrap();
+        let drop_database = script
+            .find(&format!("DROP DATABASE {};", database))
+            .unwrap();
+        let drop_role = script.find(&format!("DROP ROLE {};", role)).unwrap();
+        assert!(create_role < create_database && create_database < setup);
+        assert!(setup < drop_database && drop_database < drop_role);
+        assert!(script.contains("PASSWORD '<redacted>'"));
+        assert!(!script.contains(info.params.user().unwrap().password().unwrap()));
+    }
+
+    #[test]
+    fn temp_db_idle_in_transaction_timeout() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.idle_in_transaction_timeout(Duration::from_millis(100));
+        with_temporary_database_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |params, tls_mode| {
+                // Left open, which would make the DROP DATABASE fail without the
+                // timeout.
+                let conn = Connection::connect(params, tls_mode).unwrap();
+                conn.batch_execute("BEGIN; SELECT 1;").unwrap();
+                std::thread::sleep(Duration::from_millis(500));
+                assert!(conn.batch_execute("SELECT 1").is_err());
+                std::mem::forget(conn);
+            },
+        )
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn temp_db_admin_connection() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        // The admin connections still show backend_start the default way.
+        config.role_session_setup("SET TimeZone = 'Pacific/Chatham'; SET DateStyle = 'SQL, DMY'");
+        let database = TemporaryDatabase::create(&connect_params, TlsMode::None, &config)
+            .expect("Failed to create temporary database");
+        let first = database.admin_connection().unwrap();
+        let second = database.admin_connection().unwrap();
+        first.execute("SELECT pg_advisory_lock(42)", &[]).unwrap();
+        let locked: bool = second
+            .query("SELECT pg_try_advisory_lock(42)", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert!(!locked);
+        let user: String = second
+            .query("SELECT session_user::text", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert_eq!(user, "postgres");
+
+        database.drop_database().unwrap();
+        assert!(first.batch_execute("SELECT 1").is_err());
+        assert!(second.batch_execute("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn temp_db_cleanup_goes_on_after_a_failure() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.extra_role("alice");
+        let database = TemporaryDatabase::create(&connect_params, TlsMode::None, &config)
+            .expect("Failed to create temporary database");
+        let info = database.info.clone();
+        let alice = quote_ident(info.extra_role("alice").unwrap());
+        let admin = Connection::connect(connect_params.clone(), TlsMode::None).unwrap();
+        // A privilege outside the temporary database keeps DROP ROLE from working.
+        admin
+            .batch_execute(&format!("GRANT CONNECT ON DATABASE postgres TO {}", alice))
+            .unwrap();
+
+        assert!(database.drop_database().is_err());
+        let exists = |sql: &str, name: &str| !admin.query(sql, &[&name]).unwrap().is_empty();
+        // Everything else was still dropped.
+        assert!(!exists(
+            "SELECT 1 FROM pg_database WHERE datname = $1",
+            &info.database
+        ));
+        assert!(!exists(
+            "SELECT 1 FROM pg_roles WHERE rolname = $1",
+            &info.role
+        ));
+
+        admin
+            .batch_execute(&format!(
+                "REVOKE CONNECT ON DATABASE postgres FROM {alice}; DROP ROLE {alice};",
+                alice = alice
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn temp_db_pool() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let database =
+            TemporaryDatabase::create(&connect_params, TlsMode::None, &DatabaseConfig::new())
+                .expect("Failed to create temporary database");
+        database
+            .pool(2)
+            .unwrap()
+            .get()
+            .unwrap()
+            .batch_execute("CREATE TABLE test (id int)")
+            .unwrap();
+        let pool = database.pool(2).unwrap();
+        // More threads than connections, which wait their turn.
+        std::thread::scope(|scope| {
+            for id in 0..4 {
+                scope.spawn(move || {
+                    let conn = pool.get().unwrap();
+                    conn.execute("INSERT INTO test VALUES ($1)", &[&id])
+                        .unwrap();
+                });
+            }
+        });
+        let conn = pool.get().unwrap();
+        let count: i64 = conn
+            .query("SELECT count(*) FROM test", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert_eq!(count, 4);
+        let user: String = conn
+            .query("SELECT session_user::text", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert_eq!(user, database.info().role);
+        drop(conn);
+        assert!(database.pool(3).is_err());
+
+        // Doesn't need terminate_connections_on_drop to get rid of them.
+        database.drop_database().unwrap();
+    }
+
+    #[test]
+    fn temp_db_admin_and_user_options() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = ConnectParams::builder()
+            .port(5432)
+            .user("postgres", None)
+            .database("postgres")
+            .option("application_name", "from_params")
+            .option("options", "-c kpg_fixture.side=params")
+            .build(params::Host::Tcp("localhost".to_owned()));
+        let setting = |conn: &Connection| -> (String, String) {
+            let rows = conn
+                .query(
+                    "SELECT current_setting('application_name'),
+                        coalesce(current_setting('kpg_fixture.side', true), '')",
+                    &[],
+                )
+                .unwrap();
+            (rows.get(0).get(0), rows.get(0).get(1))
+        };
+
+        let mut config = DatabaseConfig::new();
+        config
+            .admin_option("options", "-c kpg_fixture.side=admin")
+            .user_option("application_name", "user");
+        let database = TemporaryDatabase::create(&connect_params, TlsMode::None, &config)
+            .expect("Failed to create temporary database");
+        let admin = database.admin_connection().unwrap();
+        assert_eq!(
+            setting(&admin),
+            ("from_params".to_owned(), "admin".to_owned())
+        );
+        let user = Connection::connect(database.params().clone(), TlsMode::None).unwrap();
+        assert_eq!(setting(&user), ("user".to_owned(), "params".to_owned()));
+        drop((admin, user));
+        database.drop_database().unwrap();
+
+        config.inherit_admin_options(false);
+        let database = TemporaryDatabase::create(&connect_params, TlsMode::None, &config)
+            .expect("Failed to create temporary database");
+        let user = Connection::connect(database.params().clone(), TlsMode::None).unwrap();
+        assert_eq!(setting(&user), ("user".to_owned(), "".to_owned()));
+        drop(user);
+        database.drop_database().unwrap();
+    }
+
+    #[test]
+    fn temp_db_audit_sql() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config
+            .audit_sql(true)
+            .disable_jit(true)
+            .setup_sql("CREATE TABLE test ()");
+        let info =
+            with_temporary_database_info(&connect_params, TlsMode::None, &config, |info, _| info)
+                .expect("Failed to create temporary database");
+        let role = quote_ident(&info.role);
+        let database = quote_ident(&info.database);
+        assert!(info.audit_sql[0].starts_with(&format!("CREATE ROLE {}", role)));
+        assert!(info.audit_sql[0].ends_with("PASSWORD '<redacted>';"));
+        assert!(info.audit_sql[1].starts_with(&format!(
+            "COMMENT ON ROLE {} IS 'Created by kpostgres_fixture",
+            role
+        )));
+        assert_eq!(
+            info.audit_sql[2..4],
+            [
+                format!("CREATE DATABASE {} WITH OWNER={};", database, role),
+                format!("REVOKE ALL ON DATABASE {} FROM public;", database),
+            ]
+        );
+        assert!(info
+            .audit_sql
+            .contains(&format!("ALTER DATABASE {} SET jit = 'off';", database)));
+        assert!(!info
+            .audit_sql
+            .iter()
+            .any(|sql| sql.contains("CREATE TABLE")));
+
+        // Nothing is collected by default.
+        let info = with_temporary_database_info(
+            &connect_params,
+            TlsMode::None,
+            &DatabaseConfig::new(),
+            |info, _| info,
+        )
+        .expect("Failed to create temporary database");
+        assert!(info.audit_sql.is_empty());
+    }
+
+    #[test]
+    fn temp_db_templated_audit_sql() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.audit_sql(true).disable_jit(true);
+        let info =
+            with_temporary_database_info(&connect_params, TlsMode::None, &config, |info, _| info)
+                .expect("Failed to create temporary database");
+        assert_eq!(
+            info.templated_audit_sql(),
+            [
+                "CREATE ROLE \"<database>\"
+                NOSUPERUSER NOCREATEDB NOCREATEROLE INHERIT
+                LOGIN ENCRYPTED PASSWORD '<redacted>';",
+                "COMMENT ON ROLE \"<database>\" IS '<comment>';",
+                "CREATE DATABASE \"<database>\" WITH OWNER=\"<database>\";",
+                "REVOKE ALL ON DATABASE \"<database>\" FROM public;",
+                "COMMENT ON DATABASE \"<database>\" IS '<comment>';",
+                "ALTER DATABASE \"<database>\" SET jit = 'off';",
+            ]
+        );
+
+        config
+            .role_name(&format!("kpg_fixture_role_{}", random_string(8)))
+            .extra_role("reader")
+            .comment("stable");
+        let info =
+            with_temporary_database_info(&connect_params, TlsMode::None, &config, |info, _| info)
+                .expect("Failed to create temporary database");
+        let templated = info.templated_audit_sql();
+        assert_eq!(
+            templated[1..5],
+            [
+                "COMMENT ON ROLE \"<role>\" IS 'stable';",
+                "CREATE ROLE \"<role>_reader\" NOLOGIN NOBYPASSRLS; GRANT \"<role>_reader\" TO \"<role>\";",
+                "COMMENT ON ROLE \"<role>_reader\" IS 'stable';",
+                "CREATE DATABASE \"<database>\" WITH OWNER=\"<role>\";",
+            ]
+        );
+    }
+
+    #[test]
+    fn temp_db_terminate_connections_on_drop() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config
+            .terminate_connections_on_drop(true)
+            .drop_wait(Duration::from_millis(100));
+        let database = TemporaryDatabase::create(&connect_params, TlsMode::None, &config)
+            .expect("Failed to create temporary database");
+        let leaked = Connection::connect(database.params().clone(), TlsMode::None).unwrap();
+        let closed = Connection::connect(database.params().clone(), TlsMode::None).unwrap();
+        let closer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            closed.finish().unwrap();
+        });
+
+        database.drop_database().unwrap();
+        closer.join().unwrap();
+        assert!(leaked.batch_execute("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn temp_db_privilege_sql() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config
+            .privilege_sql(
+                "DO $$ BEGIN
+                    EXECUTE format('CREATE SCHEMA app AUTHORIZATION %I',
+                        current_setting('kpg_fixture.role'));
+                END $$;
+                REVOKE CREATE ON SCHEMA public FROM PUBLIC;",
+            )
+            .setup_sql("CREATE TABLE app.test ()");
+        with_temporary_database_conn_config(
+            &connect_params,
+            TlsMode::None,
+            &config,
+            |conn| -> Result<()> {
+                let rows = conn.query(
+                    "SELECT has_database_privilege('public', current_database(), 'CONNECT'),
+                    (SELECT nspowner::regrole::text FROM pg_namespace WHERE nspname = 'app')
+                        = quote_ident(current_user)",
+                    &[],
+                )?;
+                // Not revoked, since the script replaces the default.
+                assert!(rows.get(0).get::<_, bool>(0));
+                assert!(rows.get(0).get::<_, bool>(1));
+                conn.execute("TABLE app.test", &[])?;
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_disable_jit() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.disable_jit(true);
+        let jit = with_temporary_database_config(
+            connect_params,
+            TlsMode::None,
+            &config,
+            |params, tls_mode| -> Result<String> {
+                // A connection of its own, which still gets the setting.
+                let conn = Connection::connect(params, tls_mode)?;
+                Ok(conn.query("SHOW jit", &[])?.get(0).get(0))
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert_eq!(jit, "off");
+    }
+
+    #[test]
+    fn temp_db_row_level_security() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.extra_role("alice").extra_role("bob");
+        with_temporary_database_info(
+            &connect_params,
+            TlsMode::None,
+            &config,
+            |info, tls_mode| -> Result<()> {
+                let alice = info.extra_role("alice").unwrap();
+                let bob = info.extra_role("bob").unwrap();
+                assert_eq!(alice, format!("{}_alice", info.role));
+                let conn = Connection::connect(info.params.clone(), tls_mode)?;
+                conn.batch_execute(&format!(
+                    "CREATE TABLE notes (owner name, body text);
+                    INSERT INTO notes VALUES ('{alice}', 'a'), ('{bob}', 'b'), ('{bob}', 'c');
+                    ALTER TABLE notes ENABLE ROW LEVEL SECURITY;
+                    CREATE POLICY own_notes ON notes USING (owner = current_user);
+                    GRANT SELECT ON notes TO {qalice}, {qbob};",
+                    alice = alice,
+                    bob = bob,
+                    qalice = quote_ident(alice),
+                    qbob = quote_ident(bob),
+                ))?;
+                let count = |conn: &Connection| -> Result<i64> {
+                    Ok(conn.query("SELECT count(*) FROM notes", &[])?.get(0).get(0))
+                };
+                assert_eq!(with_role(&conn, alice, || count(&conn))??, 1);
+                assert_eq!(with_role(&conn, bob, || count(&conn))??, 2);
+                // The owner isn't subject to the policy.
+                assert_eq!(count(&conn)?, 3);
+
+                conn.batch_execute("SET row_security = off")?;
+                assert_eq!(with_role(&conn, alice, || count(&conn))??, 1);
+                assert_eq!(show(&conn, "row_security")?, "off");
+                let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    with_role(&conn, alice, || -> i64 { panic!("failed") })
+                }));
+                assert!(panicked.is_err());
+                assert_eq!(count(&conn)?, 3);
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+    }
+
+    #[test]
+    fn temp_db_in_struct_field() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        struct TestContext {
+            info: TempDbInfo,
+            dropped_info: TempDbInfo,
+            conn: Connection,
+            // Declared last so the connection is closed before the database
+            // is dropped.
+            _database: TemporaryDatabase<'static>,
+        }
+
+        fn setup() -> TestContext {
+            let connect_params = admin_params();
+            let config = DatabaseConfig::new();
+            let database = TemporaryDatabase::create(&connect_params, TlsMode::None, &config)
+                .expect("Failed to create temporary database");
+            let dropped_info =
+                with_temporary_database_info(&connect_params, TlsMode::None, &config, |info, _| {
+                    info
+                })
+                .expect("Failed to create temporary database");
+            let conn = Connection::connect(database.params().clone(), TlsMode::None).unwrap();
+            TestContext {
+                info: database.info().clone(),
+                dropped_info,
+                conn,
+                _database: database,
+            }
+        }
+
+        let context = setup();
+        let database: String = context
+            .conn
+            .query("SELECT current_database()::text", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert_eq!(database, context.info.database);
+        // Dropped when its closure returned, but still usable as a value.
+        assert!(Connection::connect(context.dropped_info.params.clone(), TlsMode::None).is_err());
+    }
+
+    #[test]
+    fn temp_db_conn_kept() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let admin = Connection::connect(connect_params.clone(), TlsMode::None).unwrap();
+        let exists = |database: &str| {
+            !admin
+                .query("SELECT 1 FROM pg_database WHERE datname = $1", &[&database])
+                .unwrap()
+                .is_empty()
+        };
+
+        let (value, conn) = with_temporary_database_conn_kept(
+            &connect_params,
+            TlsMode::None,
+            &DatabaseConfig::new(),
+            |conn| {
+                conn.batch_execute("CREATE TABLE test (id int); INSERT INTO test VALUES (42);")
+                    .unwrap();
+                "set up"
+            },
+        )
+        .expect("Failed to create temporary database");
+        assert_eq!(value, "set up");
+        let id: i32 = conn.query("TABLE test", &[]).unwrap().get(0).get(0);
+        assert_eq!(id, 42);
+        let database = conn.info().database.clone();
+        conn.close().unwrap();
+        assert!(!exists(&database));
+
+        // Dropping cleans up too.
+        let database =
+            TemporaryDatabase::create(&connect_params, TlsMode::None, &DatabaseConfig::new())
+                .expect("Failed to create temporary database");
+        let name = database.info().database.clone();
+        assert!(exists(&name));
+        drop(database);
+        assert!(!exists(&name));
+    }
+
+    #[test]
+    fn temp_db_info() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let info = with_temporary_database_info(
+            connect_params,
+            TlsMode::None,
+            &DatabaseConfig::new(),
+            |info, tls_mode| -> Result<TempDbInfo> {
+                let conn = Connection::connect(info.params.clone(), tls_mode)?;
+                let rows = conn.query("SELECT current_database(), current_user", &[])?;
+                assert_eq!(rows.get(0).get::<_, String>(0), info.database);
+                assert_eq!(rows.get(0).get::<_, String>(1), info.role);
+                conn.batch_execute(&format!("SET ROLE {}", quote_ident(&info.role)))?;
+                Ok(info)
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+        assert!(info.database.starts_with("kpg_fixture_"));
+    }
+
+    #[test]
+    fn temp_db_fixed_names_are_quoted() {
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let suffix = random_string(8);
+        let database = format!("Kpg \"Fixture\"; DROP DATABASE postgres; -- {}", suffix);
+        let role = format!("Kpg Role's {}", suffix);
+        let mut config = DatabaseConfig::new();
+        config.database_name(&database).role_name(&role);
+        with_temporary_database_info(
+            &connect_params,
+            TlsMode::None,
+            &config,
+            |info, tls_mode| -> Result<()> {
+                let conn = Connection::connect(info.params, tls_mode)?;
+                let rows = conn.query("SELECT current_database(), current_user", &[])?;
+                assert_eq!(rows.get(0).get::<_, String>(0), database);
+                assert_eq!(rows.get(0).get::<_, String>(1), role);
+                Ok(())
+            },
+        )
+        .expect("Failed to create temporary database")
+        .expect("Inner result failed");
+
+        for name in &["", "nul\0byte", &"x".repeat(64)] {
+            let mut config = DatabaseConfig::new();
+            config.database_name(name);
+            match with_temporary_database_config(&connect_params, TlsMode::None, &config, |_, _| ())
+            {
+                Err(Error::InvalidConfig(_)) => {}
+                other => panic!("Expected {:?} to be rejected, got {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn temp_db_connect_hook() {
+        use std::sync::{Arc, Mutex};
+
+        INIT.call_once(|| {
+            env_logger::init();
+        });
+
+        let connect_params = admin_params();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut config = DatabaseConfig::new();
+        {
+            let seen = seen.clone();
+            config.on_connect(move |purpose, params| {
+                let password = params.user().and_then(|user| user.password());
+                seen.lock()
+                    .unwrap()
+                    .push((purpose, password.map(str::to_owned)));
+            });
+        }
+        config.extension("citext").setup_sql("SELECT 1");
+        with_temporary_database_conn_config(connect_params, TlsMode::None, &config, |_| ())
+            .expect("Failed to create temporary database");
+
+        let redacted = Some("<redacted>".to_owned());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (ConnectionPurpose::RoleAdmin, None),
+                (ConnectionPurpose::TemporaryDatabaseAdmin, None),
+                (ConnectionPurpose::Setup, redacted.clone()),
+                (ConnectionPurpose::User, redacted),
+            ]
+        );
+    }
+
+    mod macros {
+        use crate::Connection;
+
+        #[crate::test]
+        fn temp_db_macro(conn: Connection) {
+            conn.batch_execute("CREATE TABLE test()").unwrap();
+            conn.execute("TABLE test", &[]).unwrap();
+        }
+
+        #[crate::test(url = "postgres://postgres@localhost:5432/postgres")]
+        fn temp_db_macro_url(conn: Connection) -> crate::Result<()> {
+            conn.batch_execute("CREATE TABLE test()")?;
+            conn.execute("TABLE test", &[])?;
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,364 @@
+use std::io::{BufRead, BufReader, Read};
+
+use log::*;
+
+use crate::{Queryable, Result};
+
+/// How far [`batch_execute_from_reader_with_progress`] has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadProgress {
+    /// Statements executed so far.
+    pub statements: u64,
+    /// Bytes of input consumed by those statements.
+    pub bytes: u64,
+}
+
+/// Where the splitter is inside the current statement.
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    Normal,
+    /// Inside '...', with backslash escapes for E'...'.
+    SingleQuote {
+        backslash_escapes: bool,
+    },
+    DoubleQuote,
+    LineComment,
+    /// Block comments nest in postgres.
+    BlockComment {
+        depth: u32,
+    },
+    /// Inside $tag$...$tag$, holding the whole delimiter.
+    DollarQuote {
+        tag: String,
+    },
+}
+
+/// Splits SQL fed to it line by line into statements on the semicolons that
+/// aren't inside a string, quoted identifier, comment or dollar quote.
+struct Splitter {
+    state: State,
+    statement: String,
+    /// Whether the statement has anything apart from whitespace and comments.
+    has_content: bool,
+    /// The previous character was a backslash in an E'...' string.
+    escaped: bool,
+}
+
+/// Whether `c` may appear in an unquoted identifier or a dollar quote tag.
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Splitter {
+    fn new() -> Splitter {
+        Splitter {
+            state: State::Normal,
+            statement: String::new(),
+            has_content: false,
+            escaped: false,
+        }
+    }
+
+    /// Feed the next line (including its newline) and return the statements it
+    /// completed.
+    fn push_line(&mut self, line: &str) -> Vec<String> {
+        let mut statements = Vec::new();
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            let next = rest[c.len_utf8()..].chars().next();
+            let mut len = c.len_utf8();
+            match self.state {
+                State::Normal => match (c, next) {
+                    (';', _) => {
+                        self.statement.push(';');
+                        if self.has_content {
+                            statements.push(std::mem::take(&mut self.statement));
+                        } else {
+                            self.statement.clear();
+                        }
+                        self.has_content = false;
+                        rest = &rest[len..];
+                        continue;
+                    }
+                    ('-', Some('-')) => {
+                        self.state = State::LineComment;
+                        len += 1;
+                    }
+                    ('/', Some('*')) => {
+                        self.state = State::BlockComment { depth: 1 };
+                        len += 1;
+                    }
+                    ('\'', _) => {
+                        let prefix = self.statement.trim_end_matches(is_ident_char);
+                        let word = &self.statement[prefix.len()..];
+                        self.state = State::SingleQuote {
+                            backslash_escapes: word.eq_ignore_ascii_case("e"),
+                        };
+                        self.has_content = true;
+                    }
+                    ('"', _) => {
+                        self.state = State::DoubleQuote;
+                        self.has_content = true;
+                    }
+                    ('$', _) => {
+                        // $tag$ or $$, but not $1 or a $ inside an identifier.
+                        let after = &rest[1..];
+                        let tag_len = after.find(|c| !is_ident_char(c)).unwrap_or(after.len());
+                        let starts_tag =
+                            !after[..tag_len].starts_with(|c: char| c.is_ascii_digit());
+                        let in_ident = self.statement.ends_with(is_ident_char);
+                        if starts_tag && !in_ident && after[tag_len..].starts_with('$') {
+                            len = tag_len + 2;
+                            self.state = State::DollarQuote {
+                                tag: rest[..len].to_owned(),
+                            };
+                        }
+                        self.has_content = true;
+                    }
+                    (c, _) if !c.is_whitespace() => self.has_content = true,
+                    _ => {}
+                },
+                State::SingleQuote { backslash_escapes } => {
+                    if self.escaped {
+                        self.escaped = false;
+                    } else if backslash_escapes && c == '\\' {
+                        self.escaped = true;
+                    } else if c == '\'' {
+                        if next == Some('\'') {
+                            len += 1;
+                        } else {
+                            self.state = State::Normal;
+                        }
+                    }
+                }
+                State::DoubleQuote => {
+                    if c == '"' {
+                        if next == Some('"') {
+                            len += 1;
+                        } else {
+                            self.state = State::Normal;
+                        }
+                    }
+                }
+                State::LineComment => {
+                    if c == '\n' {
+                        self.state = State::Normal;
+                    }
+                }
+                State::BlockComment { depth } => match (c, next) {
+                    ('*', Some('/')) => {
+                        len += 1;
+                        self.state = if depth == 1 {
+                            State::Normal
+                        } else {
+                            State::BlockComment { depth: depth - 1 }
+                        };
+                    }
+                    ('/', Some('*')) => {
+                        len += 1;
+                        self.state = State::BlockComment { depth: depth + 1 };
+                    }
+                    _ => {}
+                },
+                State::DollarQuote { ref tag } => {
+                    if rest.starts_with(tag.as_str()) {
+                        len = tag.len();
+                        self.state = State::Normal;
+                    }
+                }
+            }
+            self.statement.push_str(&rest[..len]);
+            rest = &rest[len..];
+        }
+        statements
+    }
+
+    /// The last statement, if it wasn't terminated by a semicolon.
+    fn finish(self) -> Option<String> {
+        if self.has_content {
+            Some(self.statement)
+        } else {
+            None
+        }
+    }
+}
+
+/// Execute the SQL read from `reader` (e.g. a large seed file) statement by
+/// statement, without reading all of it into memory first.
+///
+/// Statements are split on semicolons outside of strings, quoted identifiers,
+/// comments and dollar quoted bodies, so memory use is bounded by the largest
+/// single statement. psql meta-commands and `COPY ... FROM stdin` data are
+/// not supported.
+pub fn batch_execute_from_reader<R: BufRead>(
+    conn: &(impl Queryable + ?Sized),
+    reader: R,
+) -> Result<LoadProgress> {
+    batch_execute_from_reader_with_progress(conn, reader, |_| ())
+}
+
+/// Like [`batch_execute_from_reader`], calling `progress` after every
+/// statement, e.g. to show how much of a file has been loaded.
+pub fn batch_execute_from_reader_with_progress<R, F>(
+    conn: &(impl Queryable + ?Sized),
+    mut reader: R,
+    mut progress: F,
+) -> Result<LoadProgress>
+where
+    R: BufRead,
+    F: FnMut(&LoadProgress),
+{
+    let mut splitter = Splitter::new();
+    let mut done = LoadProgress::default();
+    let mut line = String::new();
+    let mut execute = |statement: &str, done: &mut LoadProgress| -> Result<()> {
+        conn.batch_execute(statement)?;
+        done.statements += 1;
+        done.bytes += statement.len() as u64;
+        progress(done);
+        Ok(())
+    };
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        for statement in splitter.push_line(&line) {
+            execute(&statement, &mut done)?;
+        }
+    }
+    if let Some(statement) = splitter.finish() {
+        execute(&statement, &mut done)?;
+    }
+    debug!("Executed {} statements", done.statements);
+    Ok(done)
+}
+
+/// How much [`copy_in_from_reader`] reads from its reader at a time.
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Run `copy_statement`, a `COPY ... FROM STDIN` (e.g. `COPY users FROM STDIN
+/// WITH (FORMAT csv, HEADER)`), streaming its data from `reader`. Returns the
+/// number of rows copied.
+///
+/// `reader` is read [`DEFAULT_COPY_BUFFER_SIZE`] bytes at a time; use
+/// [`copy_in_from_reader_with_buffer_size`] to tune that.
+pub fn copy_in_from_reader<R: Read>(
+    conn: &(impl Queryable + ?Sized),
+    copy_statement: &str,
+    reader: R,
+) -> Result<u64> {
+    copy_in_from_reader_with_buffer_size(conn, copy_statement, reader, DEFAULT_COPY_BUFFER_SIZE)
+}
+
+/// Like [`copy_in_from_reader`], reading `buffer_size` bytes from `reader` at
+/// a time: bigger buffers mean fewer reads for large seed files, smaller ones
+/// less memory on constrained machines. The postgres client still sends the
+/// data to the server in messages of up to 16KiB.
+pub fn copy_in_from_reader_with_buffer_size<R: Read>(
+    conn: &(impl Queryable + ?Sized),
+    copy_statement: &str,
+    reader: R,
+    buffer_size: usize,
+) -> Result<u64> {
+    let mut reader = BufReader::with_capacity(buffer_size.max(1), reader);
+    let rows = conn.copy_in(copy_statement, &mut reader)?;
+    debug!("Copied {} rows", rows);
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+    use crate::with_temporary_database;
+    use postgres::{Connection, TlsMode};
+
+    fn split(sql: &str) -> Vec<String> {
+        let mut splitter = Splitter::new();
+        let mut statements: Vec<String> = sql
+            .split_inclusive('\n')
+            .flat_map(|line| splitter.push_line(line))
+            .collect();
+        statements.extend(splitter.finish());
+        statements.iter().map(|s| s.trim().to_owned()).collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons() {
+        assert_eq!(
+            split("SELECT 1;\nSELECT 2; SELECT\n3"),
+            vec!["SELECT 1;", "SELECT 2;", "SELECT\n3"]
+        );
+        assert_eq!(split("  ;\n-- nothing\n;"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn ignores_quoted_and_commented_semicolons() {
+        let sql = "SELECT 'a;''b', E'c\\';d', \"e;\"\"f\";
+            -- g;
+            /* h; /* nested; */ i; */ SELECT 2;
+            CREATE FUNCTION f() RETURNS int AS $body$
+            BEGIN
+                RETURN 1; -- $$;
+            END
+            $body$ LANGUAGE plpgsql;
+            SELECT $$;$$, $1;";
+        let statements = split(sql);
+        assert_eq!(statements.len(), 4, "{:#?}", statements);
+        assert!(statements[0].ends_with("\"e;\"\"f\";"));
+        assert!(statements[1].ends_with("SELECT 2;"));
+        assert!(statements[2].ends_with("LANGUAGE plpgsql;"));
+        assert_eq!(statements[3], "SELECT $$;$$, $1;");
+    }
+
+    #[test]
+    fn loads_from_reader() {
+        let admin_params = admin_params();
+        let sql = "CREATE TABLE test (id int, body text);
+            CREATE FUNCTION add(a int) RETURNS void AS $$
+                INSERT INTO test VALUES (a, 'x;y');
+            $$ LANGUAGE sql;
+            SELECT add(1); SELECT add(2)";
+        with_temporary_database(admin_params, TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            let mut seen = Vec::new();
+            let done = batch_execute_from_reader_with_progress(&conn, sql.as_bytes(), |p| {
+                seen.push(p.statements)
+            })
+            .unwrap();
+            assert_eq!(seen, vec![1, 2, 3, 4]);
+            assert_eq!(done.statements, 4);
+            let count: i64 = conn
+                .query("SELECT count(*) FROM test WHERE body = 'x;y'", &[])
+                .unwrap()
+                .get(0)
+                .get(0);
+            assert_eq!(count, 2);
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn copies_from_reader() {
+        let admin_params = admin_params();
+        let csv = "id,body\n1,a\n2,\"b,c\"\n3,d\n";
+        with_temporary_database(admin_params, TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id int, body text)")
+                .unwrap();
+            let copy = "COPY test FROM STDIN WITH (FORMAT csv, HEADER)";
+            assert_eq!(copy_in_from_reader(&conn, copy, csv.as_bytes()).unwrap(), 3);
+            let rows =
+                copy_in_from_reader_with_buffer_size(&conn, copy, csv.as_bytes(), 4).unwrap();
+            assert_eq!(rows, 3);
+            let count: i64 = conn
+                .query("SELECT count(*) FROM test WHERE body = 'b,c'", &[])
+                .unwrap()
+                .get(0)
+                .get(0);
+            assert_eq!(count, 2);
+        })
+        .expect("Failed to create temporary database");
+    }
+}
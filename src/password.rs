@@ -0,0 +1,205 @@
+use std::fmt;
+use std::sync::Arc;
+
+use log::*;
+use rand::{thread_rng, Rng};
+
+use crate::{random_string, Error, Result};
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+/// Nothing that needs escaping in a SQL literal or a connection string.
+const SPECIAL: &str = "!#%*+-.=?@^_~";
+
+/// How many passwords to generate before giving up on satisfying the policy.
+const MAX_ATTEMPTS: usize = 100;
+
+/// Requirements for the password of the temporary role, e.g. to match what
+/// the server's `passwordcheck` enforces.
+///
+/// Passwords are always checked against the policy before CREATE ROLE, and
+/// regenerated if they don't satisfy it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    require_lowercase: bool,
+    require_uppercase: bool,
+    require_digit: bool,
+    require_special: bool,
+}
+
+impl PasswordPolicy {
+    pub fn new() -> PasswordPolicy {
+        PasswordPolicy::default()
+    }
+
+    pub fn min_length(&mut self, min_length: usize) -> &mut PasswordPolicy {
+        self.min_length = min_length;
+        self
+    }
+
+    pub fn require_lowercase(&mut self, require: bool) -> &mut PasswordPolicy {
+        self.require_lowercase = require;
+        self
+    }
+
+    pub fn require_uppercase(&mut self, require: bool) -> &mut PasswordPolicy {
+        self.require_uppercase = require;
+        self
+    }
+
+    pub fn require_digit(&mut self, require: bool) -> &mut PasswordPolicy {
+        self.require_digit = require;
+        self
+    }
+
+    /// Require something that is neither a letter nor a digit.
+    pub fn require_special(&mut self, require: bool) -> &mut PasswordPolicy {
+        self.require_special = require;
+        self
+    }
+
+    /// Check `password` against the policy, describing the first requirement
+    /// it fails.
+    pub fn check(&self, password: &str) -> std::result::Result<(), String> {
+        if password.chars().count() < self.min_length {
+            return Err(format!("shorter than {} characters", self.min_length));
+        }
+        let has = |matches: fn(&char) -> bool| password.chars().any(|c| matches(&c));
+        if self.require_lowercase && !has(|c| c.is_lowercase()) {
+            return Err("missing a lowercase letter".to_owned());
+        }
+        if self.require_uppercase && !has(|c| c.is_uppercase()) {
+            return Err("missing an uppercase letter".to_owned());
+        }
+        if self.require_digit && !has(|c| c.is_ascii_digit()) {
+            return Err("missing a digit".to_owned());
+        }
+        if self.require_special && !has(|c| !c.is_alphanumeric()) {
+            return Err("missing a special character".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Random password drawn from the character classes the policy asks for.
+    fn generate(&self) -> String {
+        let mut charset = String::from(LOWERCASE);
+        charset.push_str(DIGITS);
+        if self.require_uppercase {
+            charset.push_str(UPPERCASE);
+        }
+        if self.require_special {
+            charset.push_str(SPECIAL);
+        }
+        let charset: Vec<char> = charset.chars().collect();
+        let mut rng = thread_rng();
+        std::iter::repeat(())
+            .map(|()| charset[rng.gen_range(0, charset.len())])
+            .take(self.min_length.max(32))
+            .collect()
+    }
+}
+
+/// User supplied function to generate role passwords.
+#[derive(Clone)]
+pub(crate) struct PasswordGenerator(pub(crate) Arc<dyn Fn() -> String + Send + Sync>);
+
+impl fmt::Debug for PasswordGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PasswordGenerator")
+    }
+}
+
+/// Generate a password for the temporary role which satisfies `policy`.
+///
+/// Without a policy or a generator this is the same [a-z0-9] string we always
+/// used.
+pub(crate) fn generate_password(
+    policy: Option<&PasswordPolicy>,
+    generator: Option<&PasswordGenerator>,
+) -> Result<String> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => {
+            return Ok(match generator {
+                Some(generator) => (generator.0)(),
+                None => random_string(32),
+            })
+        }
+    };
+    for _ in 0..MAX_ATTEMPTS {
+        let password = match generator {
+            Some(generator) => (generator.0)(),
+            None => policy.generate(),
+        };
+        match policy.check(&password) {
+            Ok(()) => return Ok(password),
+            Err(reason) => debug!("Regenerating password since it is {}", reason),
+        }
+    }
+    Err(Error::InvalidConfig(format!(
+        "Failed to generate a password satisfying {:?} after {} attempts",
+        policy, MAX_ATTEMPTS
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_check() {
+        let mut policy = PasswordPolicy::new();
+        policy
+            .min_length(8)
+            .require_uppercase(true)
+            .require_digit(true)
+            .require_special(true);
+        assert!(policy.check("Abcdef1!").is_ok());
+        assert!(policy.check("Abcde1!").is_err());
+        assert!(policy.check("abcdef1!").is_err());
+        assert!(policy.check("Abcdefg!").is_err());
+        assert!(policy.check("Abcdefg1").is_err());
+    }
+
+    #[test]
+    fn generated_passwords_satisfy_policy() {
+        let mut policy = PasswordPolicy::new();
+        policy
+            .min_length(40)
+            .require_lowercase(true)
+            .require_uppercase(true)
+            .require_digit(true)
+            .require_special(true);
+        for _ in 0..20 {
+            let password = generate_password(Some(&policy), None).unwrap();
+            assert_eq!(policy.check(&password), Ok(()));
+        }
+    }
+
+    #[test]
+    fn generator_is_retried_until_valid() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let generator = {
+            let calls = calls.clone();
+            PasswordGenerator(Arc::new(move || {
+                if calls.fetch_add(1, Ordering::SeqCst) < 3 {
+                    "short".to_owned()
+                } else {
+                    "long enough".to_owned()
+                }
+            }))
+        };
+        let mut policy = PasswordPolicy::new();
+        policy.min_length(8);
+        let password = generate_password(Some(&policy), Some(&generator)).unwrap();
+        assert_eq!(password, "long enough");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let never_valid = PasswordGenerator(Arc::new(|| "short".to_owned()));
+        assert!(generate_password(Some(&policy), Some(&never_valid)).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::borrow::Borrow;
+
+use log::*;
+use postgres::params::ConnectParams;
+use postgres::{Connection, TlsMode};
+
+use crate::record::{self, SqlRecorder};
+use crate::{
+    audited_config, connect_admin, encoding_clause, invalid_encoding, params_with_options,
+    params_with_user, quote_ident, quote_literal, setup_database, user_params, validate_encoding,
+    validate_identifier, with_password_encryption, ConnectionPurpose, DatabaseConfig, Result,
+    TempDbInfo,
+};
+
+/// Prefix of the advisory lock held while checking and creating a persistent
+/// database.
+const LOCK_PREFIX: &str = "kpg_fixture persistent ";
+
+fn exists(conn: &Connection, catalog_query: &str, name: &str) -> Result<bool> {
+    Ok(!conn.query(catalog_query, &[&name])?.is_empty())
+}
+
+/// Make sure the database `name` and its role exist, creating them as
+/// described by `config` if they don't, and return how to connect to it.
+/// Unlike the temporary databases, nothing is ever dropped, so the data
+/// survives between runs, for iterating on a local database that is only set
+/// up once.
+///
+/// The role is named `name` too unless `config` gives it another name. It and
+/// the login roles are created with `password`, which the returned params
+/// use, so pass the same one every call: roles that already exist are left
+/// as they are, password included, so earlier params keep working and a role
+/// which happens to have the name isn't changed. The extensions, setup steps
+/// and database settings are only applied when the database is created; drop
+/// the database (e.g. with `dropdb`) to start over. The random name, template
+/// and password options of `config` don't apply.
+pub fn persistent_database<'a, P, M>(
+    params: P,
+    tls_mode: M,
+    name: &str,
+    password: &str,
+    config: &DatabaseConfig,
+) -> Result<TempDbInfo>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+{
+    let params = params.borrow();
+    let tls_mode = tls_mode.borrow();
+    validate_identifier("database", name)?;
+    validate_encoding(config)?;
+    let config = &*audited_config(config);
+    let recorder = config.sql_recorder.as_ref();
+    let rolename = config.role_name.clone().unwrap_or_else(|| name.to_owned());
+    validate_identifier("role", &rolename)?;
+    let new_params = user_params(config, params, &rolename, password, name);
+    let admin_params = params_with_options(params, &config.admin_options);
+    let params = &admin_params;
+
+    let conn = connect_admin(
+        config,
+        ConnectionPurpose::RoleAdmin,
+        params,
+        tls_mode,
+        config.role_maintenance_database.as_deref(),
+        &config.role_session_setup,
+    )?;
+    // Another process could be setting up the same database right now.
+    let lock = format!("{}{}", LOCK_PREFIX, name);
+    conn.execute("SELECT pg_advisory_lock(hashtext($1))", &[&lock])?;
+    let mut extra_roles = Vec::new();
+    let mut login_roles = Vec::new();
+    let result = (|| -> Result<()> {
+        if !exists(
+            &conn,
+            "SELECT 1 FROM pg_roles WHERE rolname = $1",
+            &rolename,
+        )? {
+            let create_role = |password: &str| {
+                with_password_encryption(
+                    config.password_encryption,
+                    format!(
+                        "CREATE ROLE {rolename}
+                        NOSUPERUSER NOCREATEDB NOCREATEROLE INHERIT
+                        LOGIN ENCRYPTED PASSWORD {password};",
+                        rolename = quote_ident(&rolename),
+                        password = quote_literal(password)
+                    ),
+                )
+            };
+            if let Some(recorder) = recorder {
+                recorder.record(&conn, &create_role("<redacted>"))?;
+            }
+            conn.batch_execute(&create_role(password))?;
+        }
+        for extra in &config.extra_roles {
+            let role = format!("{}_{}", rolename, extra);
+            validate_identifier("role", &role)?;
+            if !exists(&conn, "SELECT 1 FROM pg_roles WHERE rolname = $1", &role)? {
+                record::batch_execute(
+                    recorder,
+                    &conn,
+                    &format!(
+                        "CREATE ROLE {role} NOLOGIN NOBYPASSRLS; GRANT {role} TO {rolename};",
+                        role = quote_ident(&role),
+                        rolename = quote_ident(&rolename)
+                    ),
+                )?;
+            }
+            extra_roles.push((extra.clone(), role));
+        }
+        for (login, encryption) in &config.login_roles {
+            let role = format!("{}_{}", rolename, login);
+            validate_identifier("role", &role)?;
+            if !exists(&conn, "SELECT 1 FROM pg_roles WHERE rolname = $1", &role)? {
+                let create_login_role = |password: &str| {
+                    with_password_encryption(
+                        Some(*encryption),
+                        format!(
+                            "CREATE ROLE {role} NOSUPERUSER NOCREATEDB NOCREATEROLE INHERIT
+                            LOGIN ENCRYPTED PASSWORD {password}; GRANT {rolename} TO {role};",
+                            role = quote_ident(&role),
+                            password = quote_literal(password),
+                            rolename = quote_ident(&rolename)
+                        ),
+                    )
+                };
+                if let Some(recorder) = recorder {
+                    recorder.record(&conn, &create_login_role("<redacted>"))?;
+                }
+                conn.batch_execute(&create_login_role(password))?;
+            }
+            login_roles.push((
+                login.clone(),
+                params_with_user(&new_params, &role, password),
+            ));
+        }
+
+        if exists(&conn, "SELECT 1 FROM pg_database WHERE datname = $1", name)? {
+            debug!("Reusing persistent database {:?}", name);
+            return Ok(());
+        }
+        info!("Creating persistent database {:?}", name);
+        let encoding = match config.encoding {
+            Some(_) => format!(" TEMPLATE=template0{}", encoding_clause(config)),
+            None => String::new(),
+        };
+        record::batch_execute(
+            recorder,
+            &conn,
+            &format!(
+                "CREATE DATABASE {dbname} WITH OWNER={rolename}{encoding};",
+                dbname = quote_ident(name),
+                rolename = quote_ident(&rolename),
+                encoding = encoding
+            ),
+        )
+        .map_err(|err| invalid_encoding(config, err))?;
+        if let Err(err) = setup_database(config, params, tls_mode, &conn, name, &new_params) {
+            // Otherwise the next run would take the half set up database as is.
+            record::batch_execute(
+                recorder,
+                &conn,
+                &format!("DROP DATABASE {};", quote_ident(name)),
+            )?;
+            return Err(err);
+        }
+        Ok(())
+    })();
+    conn.execute("SELECT pg_advisory_unlock(hashtext($1))", &[&lock])?;
+
+    result?;
+    Ok(TempDbInfo {
+        params: new_params,
+        database: name.to_owned(),
+        role: rolename,
+        extra_roles,
+        login_roles,
+        audit_sql: recorder.map(SqlRecorder::audited).unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+
+    #[test]
+    fn persistent_database_is_reused() {
+        let admin_params = admin_params();
+        let name = format!("kpg_fixture_persistent_{}", crate::random_string(8));
+        let mut config = DatabaseConfig::new();
+        config
+            .setup_sql("CREATE TABLE test (id int)")
+            .audit_sql(true);
+        let admin = Connection::connect(admin_params.clone(), TlsMode::None).unwrap();
+        let role = |name: &str| -> (Option<String>, bool) {
+            let rows = admin
+                .query(
+                    "SELECT rolpassword, rolcreatedb FROM pg_authid WHERE rolname = $1",
+                    &[&name],
+                )
+                .unwrap();
+            (rows.get(0).get(0), rows.get(0).get(1))
+        };
+        // Already there, and left alone.
+        let other = format!("{}_other", name);
+        admin
+            .batch_execute(&format!("CREATE ROLE {} CREATEDB;", quote_ident(&other)))
+            .unwrap();
+
+        let first =
+            persistent_database(&admin_params, TlsMode::None, &name, "dev", &config).unwrap();
+        let created = role(&name);
+        assert!(first.audit_sql[0].contains("PASSWORD '<redacted>'"));
+        assert!(first
+            .audit_sql
+            .iter()
+            .any(|sql| sql.starts_with("CREATE DATABASE")));
+        let conn = Connection::connect(first.params.clone(), TlsMode::None).unwrap();
+        conn.execute("INSERT INTO test VALUES (1)", &[]).unwrap();
+        conn.finish().unwrap();
+
+        // The setup isn't run again, or CREATE TABLE would fail.
+        let second =
+            persistent_database(&admin_params, TlsMode::None, &name, "dev", &config).unwrap();
+        assert_eq!((&second.database, &second.role), (&name, &name));
+        assert_eq!(role(&name), created);
+        // Nothing was created this time.
+        assert!(second.audit_sql.is_empty());
+        let conn = Connection::connect(second.params.clone(), TlsMode::None).unwrap();
+        let count: i64 = conn
+            .query("SELECT count(*) FROM test", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert_eq!(count, 1);
+        conn.finish().unwrap();
+        // The params of the first call still work.
+        Connection::connect(first.params.clone(), TlsMode::None)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let mut other_config = DatabaseConfig::new();
+        other_config.role_name(&other);
+        let third = format!("{}_third", name);
+        persistent_database(&admin_params, TlsMode::None, &third, "dev", &other_config).unwrap();
+        assert_eq!(role(&other), (None, true));
+
+        admin
+            .batch_execute(&format!("DROP DATABASE {};", quote_ident(&third)))
+            .unwrap();
+        admin
+            .batch_execute(&format!("DROP ROLE {};", quote_ident(&other)))
+            .unwrap();
+        admin
+            .batch_execute(&format!("DROP DATABASE {};", quote_ident(&name)))
+            .unwrap();
+        admin
+            .batch_execute(&format!("DROP ROLE {};", quote_ident(&name)))
+            .unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex};
+
+use log::*;
+use postgres::params::ConnectParams;
+use postgres::{Connection, TlsMode};
+
+use crate::hook::{self, ConnectHook};
+use crate::{ConnectionPurpose, Error, Result};
+
+/// A fixed number of connections to a temporary database as its role, made by
+/// [`TemporaryDatabase::pool`](crate::TemporaryDatabase::pool), for code that
+/// wants several connections at once, e.g. from scoped threads.
+///
+/// The pool borrows the [`TemporaryDatabase`](crate::TemporaryDatabase), so
+/// it and the connections taken from it are gone before the database is
+/// dropped, which closes the pooled connections first.
+pub struct Pool {
+    size: usize,
+    connections: Mutex<Connections>,
+    returned: Condvar,
+}
+
+struct Connections {
+    idle: Vec<Connection>,
+    /// The idle ones and the ones taken, which are returned unless they broke.
+    open: usize,
+}
+
+impl Pool {
+    /// Open all `size` connections up front, so taking one never connects.
+    pub(crate) fn connect(
+        connect_hook: Option<&ConnectHook>,
+        params: &ConnectParams,
+        tls_mode: &TlsMode,
+        size: usize,
+    ) -> Result<Pool> {
+        let idle = (0..size)
+            .map(|_| hook::connect(connect_hook, ConnectionPurpose::Pool, params, tls_mode))
+            .collect::<postgres::Result<Vec<_>>>()?;
+        debug!("Opened a pool of {} connections", size);
+        Ok(Pool {
+            size,
+            connections: Mutex::new(Connections { idle, open: size }),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// How many connections the pool was created with.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take a connection, waiting for one to be returned if they are all in
+    /// use, so a thread holding all of them waits forever. It goes back to the
+    /// pool when the [`PooledConnection`] is dropped, as it was left: a
+    /// transaction started with `BEGIN` and not finished is still open for
+    /// the next user.
+    ///
+    /// Desynchronized connections, e.g. after a panic in the middle of a
+    /// query, aren't returned. Fails once none are left.
+    pub fn get(&self) -> Result<PooledConnection<'_>> {
+        let mut connections = self
+            .connections
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        loop {
+            if let Some(conn) = connections.idle.pop() {
+                return Ok(PooledConnection {
+                    pool: self,
+                    conn: Some(conn),
+                });
+            }
+            if connections.open == 0 {
+                return Err(Error::UnexpectedResult(
+                    "Every connection of the pool broke".to_owned(),
+                ));
+            }
+            connections = self
+                .returned
+                .wait(connections)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    /// Close the connections, reporting errors that dropping them would only
+    /// log.
+    pub(crate) fn close(self) -> Result<()> {
+        let connections = self
+            .connections
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner());
+        for conn in connections.idle {
+            conn.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Pool").field("size", &self.size).finish()
+    }
+}
+
+/// A connection taken from a [`Pool`], returned to it when dropped.
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut connections = self
+                .pool
+                .connections
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            if conn.is_desynchronized() {
+                // Unusable, so the pool is one connection smaller from now on.
+                warn!("Dropping a desynchronized connection from the pool");
+                connections.open -= 1;
+                // Waiters may have to give up now.
+                self.pool.returned.notify_all();
+                return;
+            }
+            connections.idle.push(conn);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+impl<'a> std::fmt::Debug for PooledConnection<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PooledConnection").finish()
+    }
+}
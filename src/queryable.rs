@@ -0,0 +1,143 @@
+use std::io::Read;
+
+use postgres::rows::Rows;
+use postgres::transaction::Transaction;
+use postgres::types::ToSql;
+use postgres::Connection;
+
+use crate::StatementSavepoints;
+
+/// Something statements can be run on: a [`Connection`], a [`Transaction`]
+/// (including one from
+/// [`with_committing_transaction`](crate::with_committing_transaction)) or
+/// [`StatementSavepoints`]. The helpers taking one work the same on all of
+/// them, so they can be used inside a test's transaction as well as outside.
+pub trait Queryable {
+    /// Run a statement, returning the number of rows modified.
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<u64>;
+
+    /// Run a statement, returning the rows it produced.
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<Rows>;
+
+    /// Run a batch of statements separated by semicolons, without parameters.
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()>;
+
+    /// Run a `COPY ... FROM STDIN` with its data read from `reader`,
+    /// returning the number of rows copied.
+    fn copy_in(&self, sql: &str, reader: &mut dyn Read) -> postgres::Result<u64>;
+}
+
+impl Queryable for Connection {
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<u64> {
+        Connection::execute(self, sql, params)
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<Rows> {
+        Connection::query(self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        Connection::batch_execute(self, sql)
+    }
+
+    fn copy_in(&self, sql: &str, mut reader: &mut dyn Read) -> postgres::Result<u64> {
+        Connection::prepare(self, sql)?.copy_in(&[], &mut reader)
+    }
+}
+
+impl<'a> Queryable for Transaction<'a> {
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<u64> {
+        Transaction::execute(self, sql, params)
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<Rows> {
+        Transaction::query(self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        Transaction::batch_execute(self, sql)
+    }
+
+    fn copy_in(&self, sql: &str, mut reader: &mut dyn Read) -> postgres::Result<u64> {
+        Transaction::prepare(self, sql)?.copy_in(&[], &mut reader)
+    }
+}
+
+impl<'a> Queryable for StatementSavepoints<'a> {
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<u64> {
+        StatementSavepoints::execute(self, sql, params)
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> postgres::Result<Rows> {
+        StatementSavepoints::query(self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        StatementSavepoints::batch_execute(self, sql)
+    }
+
+    fn copy_in(&self, sql: &str, reader: &mut dyn Read) -> postgres::Result<u64> {
+        StatementSavepoints::copy_in(self, sql, reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+    use crate::{
+        copy_in_from_reader, insert_returning, show, truncate_all, with_committing_transaction,
+        with_statement_savepoints, with_temporary_database,
+    };
+    use postgres::TlsMode;
+
+    fn count<C: Queryable + ?Sized>(conn: &C) -> i64 {
+        conn.query("SELECT count(*) FROM test", &[])
+            .unwrap()
+            .get(0)
+            .get(0)
+    }
+
+    #[test]
+    fn helpers_take_connections_and_transactions() {
+        let admin_params = admin_params();
+        with_temporary_database(admin_params, TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute("CREATE TABLE test (id serial PRIMARY KEY)")
+                .unwrap();
+            let id: i32 =
+                insert_returning(&conn, "INSERT INTO test DEFAULT VALUES RETURNING id", &[])
+                    .unwrap();
+            assert_eq!(id, 1);
+
+            with_committing_transaction(&conn, |transaction| -> crate::Result<()> {
+                transaction.batch_execute("SET LOCAL work_mem = '1MB'")?;
+                assert_eq!(show(transaction, "work_mem")?, "1MB");
+                truncate_all(transaction)?;
+                assert_eq!(count(transaction), 0);
+                copy_in_from_reader(transaction, "COPY test FROM STDIN", "2\n".as_bytes())?;
+                assert_eq!(count(transaction), 1);
+                Err(crate::Error::UnexpectedResult("roll back".to_owned()))
+            })
+            .unwrap()
+            .unwrap_err();
+            assert_eq!(count(&conn), 1);
+
+            with_statement_savepoints(&conn, |savepoints| {
+                truncate_all(savepoints).unwrap();
+                assert!(insert_returning::<i32>(savepoints, "SELECT nonsense", &[]).is_err());
+                let copy = "COPY test FROM STDIN";
+                assert!(copy_in_from_reader(savepoints, copy, "nonsense\n".as_bytes()).is_err());
+                assert_eq!(
+                    copy_in_from_reader(savepoints, copy, "3\n".as_bytes()).unwrap(),
+                    1
+                );
+            })
+            .unwrap();
+            // Both the dyn and the concrete types work.
+            let dyn_conn: &dyn Queryable = &conn;
+            assert_eq!(count(dyn_conn), 1);
+        })
+        .expect("Failed to create temporary database");
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use postgres::Connection;
+
+use crate::{quote_ident, Result};
+
+/// Keeps track of the SQL the crate runs: appended to a file as a script
+/// that can be replayed with `psql`, and/or collected in memory for
+/// [`TempDbInfo::audit_sql`](crate::TempDbInfo::audit_sql). Clones write to
+/// the same places.
+#[derive(Clone, Default)]
+pub(crate) struct SqlRecorder {
+    script: Option<Arc<Mutex<ScriptState>>>,
+    audit: Option<Arc<Mutex<Vec<String>>>>,
+}
+
+struct ScriptState {
+    path: PathBuf,
+    /// Opened on the first statement, so configuring it can't fail.
+    file: Option<File>,
+    /// The `\connect` target of the previous statement.
+    connection: Option<(String, String)>,
+}
+
+impl fmt::Debug for SqlRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.script.as_ref().map(|script| {
+            let state = script.lock().unwrap_or_else(|err| err.into_inner());
+            state.path.clone()
+        });
+        f.debug_struct("SqlRecorder")
+            .field("script", &path)
+            .field("audit", &self.audit.is_some())
+            .finish()
+    }
+}
+
+impl SqlRecorder {
+    /// Record to a script at `path`.
+    pub(crate) fn script(path: &Path) -> SqlRecorder {
+        SqlRecorder {
+            script: Some(Arc::new(Mutex::new(ScriptState {
+                path: path.to_owned(),
+                file: None,
+                connection: None,
+            }))),
+            audit: None,
+        }
+    }
+
+    /// A copy which also collects the audited statements in a fresh list.
+    pub(crate) fn with_audit(&self) -> SqlRecorder {
+        SqlRecorder {
+            script: self.script.clone(),
+            audit: Some(Arc::new(Mutex::new(Vec::new()))),
+        }
+    }
+
+    /// The audited statements so far.
+    pub(crate) fn audited(&self) -> Vec<String> {
+        match self.audit {
+            Some(ref audit) => audit.lock().unwrap_or_else(|err| err.into_inner()).clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Record `sql`, a statement the crate generated to manage roles,
+    /// databases and their privileges, as run on `conn`.
+    pub(crate) fn record(&self, conn: &Connection, sql: &str) -> Result<()> {
+        if let Some(ref audit) = self.audit {
+            audit
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .push(sql.trim().to_owned());
+        }
+        self.record_script(conn, sql)
+    }
+
+    /// Append `sql`, as run on `conn`, to the script only, preceded by a
+    /// `\connect` whenever it runs somewhere else than the previous statement.
+    /// For SQL which isn't about privileges, like the setup steps.
+    pub(crate) fn record_script(&self, conn: &Connection, sql: &str) -> Result<()> {
+        let script = match self.script {
+            Some(ref script) => script,
+            None => return Ok(()),
+        };
+        let rows = conn.query("SELECT current_database()::text, session_user::text", &[])?;
+        let connection: (String, String) = (rows.get(0).get(0), rows.get(0).get(1));
+
+        let mut state = script.lock().unwrap_or_else(|err| err.into_inner());
+        let mut text = String::new();
+        if state.connection.as_ref() != Some(&connection) {
+            text.push_str(&format!(
+                "\\connect {} {}\n",
+                quote_ident(&connection.0),
+                quote_ident(&connection.1)
+            ));
+            state.connection = Some(connection);
+        }
+        let sql = sql.trim();
+        text.push_str(sql);
+        text.push_str(if sql.ends_with(';') { "\n" } else { ";\n" });
+
+        if state.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&state.path)?;
+            state.file = Some(file);
+        }
+        state.file.as_mut().unwrap().write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// `conn.batch_execute(sql)`, recording it first if there is a recorder.
+pub(crate) fn batch_execute(
+    recorder: Option<&SqlRecorder>,
+    conn: &Connection,
+    sql: &str,
+) -> Result<()> {
+    if let Some(recorder) = recorder {
+        recorder.record(conn, sql)?;
+    }
+    conn.batch_execute(sql)?;
+    Ok(())
+}
@@ -0,0 +1,377 @@
+use std::cmp::Ordering;
+
+use crate::{Error, Queryable, Result};
+use log::*;
+
+/// Schemas left out of [`schema_dump`], like in [`truncate_all`](crate::truncate_all).
+const USER_SCHEMAS: &str = "n.nspname NOT IN ('pg_catalog', 'information_schema')
+    AND n.nspname NOT LIKE 'pg\\_toast%'
+    AND n.nspname NOT LIKE 'pg\\_temp\\_%'";
+
+/// Objects that belong to an extension, which are described by the extension
+/// itself.
+const NOT_FROM_EXTENSION: &str = "NOT EXISTS (
+    SELECT 1 FROM pg_depend dep WHERE dep.objid = {oid} AND dep.deptype = 'e'
+)";
+
+/// A description of the schema of the database `conn` is connected to, built
+/// from the catalogs: one line per schema, extension, relation, column,
+/// constraint, index, view, function, trigger and enum type, sorted by bytes
+/// rather than in the database's collation, like [`schema_diff`] compares
+/// them.
+///
+/// Two databases with the same schema get the same dump, regardless of the
+/// order things were created or altered in (other than column order) or the
+/// data in them, which makes it useful for checking that migrations applied
+/// up then down get back to where they started.
+pub fn schema_dump(conn: &(impl Queryable + ?Sized)) -> Result<Vec<String>> {
+    let not_from_extension = |oid: &str| NOT_FROM_EXTENSION.replace("{oid}", oid);
+    let sql = format!(
+        "SELECT format('schema %I', n.nspname) FROM pg_namespace n WHERE {user_schemas}
+        UNION ALL
+        SELECT format('extension %I', extname) FROM pg_extension
+        UNION ALL
+        SELECT format('relation %I.%I kind %s', n.nspname, c.relname, c.relkind)
+            FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE c.relkind IN ('r', 'p', 'v', 'm', 'f', 'S') AND {user_schemas}
+                AND {class_not_from_extension}
+        UNION ALL
+        SELECT format('column %I.%I %s %I %s%s%s', n.nspname, c.relname, a.attnum, a.attname,
+                format_type(a.atttypid, a.atttypmod),
+                CASE WHEN a.attnotnull THEN ' NOT NULL' ELSE '' END,
+                coalesce(' DEFAULT ' || pg_get_expr(d.adbin, d.adrelid), ''))
+            FROM pg_attribute a
+            JOIN pg_class c ON c.oid = a.attrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+            WHERE a.attnum > 0 AND NOT a.attisdropped
+                AND c.relkind IN ('r', 'p', 'v', 'm', 'f') AND {user_schemas}
+                AND {class_not_from_extension}
+        UNION ALL
+        SELECT format('constraint %I.%I %I %s', n.nspname, c.relname, con.conname,
+                pg_get_constraintdef(con.oid))
+            FROM pg_constraint con
+            JOIN pg_class c ON c.oid = con.conrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE {user_schemas}
+        UNION ALL
+        SELECT format('index %s', pg_get_indexdef(i.indexrelid))
+            FROM pg_index i
+            JOIN pg_class c ON c.oid = i.indexrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE {user_schemas} AND {index_not_from_extension}
+        UNION ALL
+        SELECT format('view %I.%I %s', n.nspname, c.relname, pg_get_viewdef(c.oid))
+            FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE c.relkind IN ('v', 'm') AND {user_schemas} AND {class_not_from_extension}
+        UNION ALL
+        SELECT format('function %s', pg_get_functiondef(p.oid))
+            FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace
+            WHERE p.prokind IN ('f', 'p') AND {user_schemas} AND {proc_not_from_extension}
+        UNION ALL
+        SELECT format('trigger %s', pg_get_triggerdef(t.oid))
+            FROM pg_trigger t
+            JOIN pg_class c ON c.oid = t.tgrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE NOT t.tgisinternal AND {user_schemas}
+        UNION ALL
+        SELECT format('enum %I.%I (%s)', n.nspname, t.typname,
+                (SELECT string_agg(quote_literal(e.enumlabel), ', ' ORDER BY e.enumsortorder)
+                    FROM pg_enum e WHERE e.enumtypid = t.oid))
+            FROM pg_type t JOIN pg_namespace n ON n.oid = t.typnamespace
+            WHERE t.typtype = 'e' AND {user_schemas} AND {type_not_from_extension}",
+        user_schemas = USER_SCHEMAS,
+        class_not_from_extension = not_from_extension("c.oid"),
+        index_not_from_extension = not_from_extension("i.indrelid"),
+        proc_not_from_extension = not_from_extension("p.oid"),
+        type_not_from_extension = not_from_extension("t.oid"),
+    );
+    let rows = conn.query(&sql, &[])?;
+    let mut dump: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    dump.sort();
+    Ok(dump)
+}
+
+/// The differences between the [`schema_dump`]s of the databases `a` and `b`
+/// are connected to: lines only in `a`'s prefixed with `- `, lines only in
+/// `b`'s with `+ `. Empty if the schemas are the same.
+pub fn schema_diff(
+    a: &(impl Queryable + ?Sized),
+    b: &(impl Queryable + ?Sized),
+) -> Result<Vec<String>> {
+    let a = schema_dump(a)?;
+    let b = schema_dump(b)?;
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let order = match (a.get(i), b.get(j)) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, _) => Ordering::Greater,
+        };
+        match order {
+            Ordering::Equal => {
+                i += 1;
+                j += 1;
+            }
+            Ordering::Less => {
+                diff.push(format!("- {}", a[i]));
+                i += 1;
+            }
+            Ordering::Greater => {
+                diff.push(format!("+ {}", b[j]));
+                j += 1;
+            }
+        }
+    }
+    Ok(diff)
+}
+
+/// Whether the databases `a` and `b` are connected to have the same schema,
+/// e.g. one with the migrations applied up then down and a fresh one. The
+/// differences, if any, are logged; use [`schema_diff`] to get them.
+pub fn schemas_equal(a: &(impl Queryable + ?Sized), b: &(impl Queryable + ?Sized)) -> Result<bool> {
+    let diff = schema_diff(a, b)?;
+    if !diff.is_empty() {
+        info!("Schemas differ:\n{}", diff.join("\n"));
+    }
+    Ok(diff.is_empty())
+}
+
+/// A constraint with rows breaking it, found by [`validate_constraints`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstraintViolation {
+    /// The table, schema qualified unless it is on the `search_path`.
+    pub table: String,
+    pub constraint: String,
+    /// As in `\d`, e.g. `FOREIGN KEY (user_id) REFERENCES users(id)`.
+    pub definition: String,
+    /// How many rows break it.
+    pub rows: i64,
+}
+
+/// Check that the data in the database `conn` is connected to satisfies its
+/// constraints, e.g. after loading seed data with triggers disabled or with
+/// `session_replication_role = replica`, which skip the foreign key checks,
+/// or constraints added `NOT VALID`. Fails with
+/// [`Error::ConstraintsViolated`] listing the constraints with rows breaking
+/// them.
+///
+/// First runs `SET CONSTRAINTS ALL IMMEDIATE`, so in a transaction the
+/// deferred checks pending in it fail right away, then checks every foreign
+/// key (with `MATCH SIMPLE` semantics: rows with a null in the key are fine)
+/// and every check constraint that hasn't been validated. Outside of a
+/// transaction the server warns that the `SET CONSTRAINTS` does nothing.
+pub fn validate_constraints(conn: &(impl Queryable + ?Sized)) -> Result<()> {
+    conn.batch_execute("SET CONSTRAINTS ALL IMMEDIATE")?;
+    let columns = |keys: &str, relation: &str| {
+        format!(
+            "ARRAY(SELECT quote_ident(a.attname)
+                FROM unnest(con.{keys}) WITH ORDINALITY AS k (attnum, position)
+                JOIN pg_attribute a ON a.attrelid = con.{relation} AND a.attnum = k.attnum
+                ORDER BY k.position)",
+            keys = keys,
+            relation = relation
+        )
+    };
+    // Partitions inherit the foreign keys of their parent, whose check covers
+    // them. Inheritance children get copies of the check constraints only.
+    let constraints = conn.query(
+        &format!(
+            "SELECT con.conrelid::regclass::text, con.conname, pg_get_constraintdef(con.oid),
+                con.contype::text, con.confrelid::regclass::text,
+                {columns}, {referenced_columns}, pg_get_expr(con.conbin, con.conrelid),
+                c.relkind = 'p'
+            FROM pg_constraint con
+            JOIN pg_class c ON c.oid = con.conrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE {user_schemas} AND con.conparentid = 0
+                AND (con.contype = 'f' OR (con.contype = 'c' AND NOT con.convalidated))
+            ORDER BY 1, 2",
+            columns = columns("conkey", "conrelid"),
+            referenced_columns = columns("confkey", "confrelid"),
+            user_schemas = USER_SCHEMAS,
+        ),
+        &[],
+    )?;
+    let mut violations = Vec::new();
+    for row in constraints.iter() {
+        let table: String = row.get(0);
+        let kind: String = row.get(3);
+        let condition = if kind == "f" {
+            let columns: Vec<String> = row.get(5);
+            let referenced_columns: Vec<String> = row.get(6);
+            format!(
+                "{not_null} AND NOT EXISTS (SELECT 1 FROM {referenced} r WHERE {matches})",
+                not_null = columns
+                    .iter()
+                    .map(|column| format!("t.{} IS NOT NULL", column))
+                    .collect::<Vec<_>>()
+                    .join(" AND "),
+                referenced = row.get::<_, String>(4),
+                matches = columns
+                    .iter()
+                    .zip(&referenced_columns)
+                    .map(|(column, referenced)| format!("r.{} = t.{}", referenced, column))
+                    .collect::<Vec<_>>()
+                    .join(" AND ")
+            )
+        } else {
+            // Unknown (null) counts as passing, like for the constraint itself.
+            format!("NOT ({})", row.get::<_, String>(7))
+        };
+        let rows: i64 = conn
+            .query(
+                &format!(
+                    "SELECT count(*) FROM {only}{table} t WHERE {condition}",
+                    only = if row.get(8) { "" } else { "ONLY " },
+                    table = table,
+                    condition = condition
+                ),
+                &[],
+            )?
+            .get(0)
+            .get(0);
+        if rows > 0 {
+            violations.push(ConstraintViolation {
+                table,
+                constraint: row.get(1),
+                definition: row.get(2),
+                rows,
+            });
+        }
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        info!("Constraints violated: {:?}", violations);
+        Err(Error::ConstraintsViolated(violations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+    use crate::with_temporary_database;
+    use postgres::{Connection, TlsMode};
+
+    #[test]
+    fn compares_schemas() {
+        let schema = "CREATE TYPE mood AS ENUM ('sad', 'happy');
+            CREATE TABLE test (id serial PRIMARY KEY, name text NOT NULL DEFAULT '', m mood);
+            CREATE INDEX test_name ON test (name);
+            CREATE VIEW names AS SELECT name FROM test;
+            CREATE FUNCTION one() RETURNS int LANGUAGE sql AS 'SELECT 1';";
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let migrated = Connection::connect(params, tls_mode).unwrap();
+            migrated.batch_execute(schema).unwrap();
+            // Up then down again.
+            migrated
+                .batch_execute(
+                    "ALTER TABLE test ADD extra int UNIQUE;
+                    CREATE TABLE other ();
+                    INSERT INTO test (name) VALUES ('data doesn''t matter');
+                    DROP TABLE other;
+                    ALTER TABLE test DROP extra;",
+                )
+                .unwrap();
+            with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+                let fresh = Connection::connect(params, tls_mode).unwrap();
+                fresh.batch_execute(schema).unwrap();
+                assert!(schemas_equal(&migrated, &fresh).unwrap());
+
+                fresh
+                    .batch_execute("CREATE INDEX test_m ON test (m)")
+                    .unwrap();
+                assert_eq!(
+                    schema_diff(&migrated, &fresh).unwrap(),
+                    vec!["+ index CREATE INDEX test_m ON public.test USING btree (m)"]
+                );
+                assert!(!schemas_equal(&migrated, &fresh).unwrap());
+            })
+            .expect("Failed to create temporary database");
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn diffs_mixed_case_names() {
+        // Sorted differently by most collations than by bytes.
+        let schema = r#"CREATE TABLE "Beta" (); CREATE TABLE alpha (); CREATE TABLE _gamma ();
+            CREATE TABLE "alpha_B" (); CREATE TABLE "ALPHA" ();"#;
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let a = Connection::connect(params, tls_mode).unwrap();
+            a.batch_execute(schema).unwrap();
+            let dump = schema_dump(&a).unwrap();
+            let mut sorted = dump.clone();
+            sorted.sort();
+            assert_eq!(dump, sorted);
+            with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+                let b = Connection::connect(params, tls_mode).unwrap();
+                b.batch_execute(schema).unwrap();
+                b.batch_execute(r#"CREATE TABLE "Alpha_" ()"#).unwrap();
+                assert_eq!(
+                    schema_diff(&a, &b).unwrap(),
+                    vec![r#"+ relation public."Alpha_" kind r"#]
+                );
+            })
+            .expect("Failed to create temporary database");
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    fn validates_constraints() {
+        with_temporary_database(admin_params(), TlsMode::None, |params, tls_mode| {
+            let conn = Connection::connect(params, tls_mode).unwrap();
+            conn.batch_execute(
+                "CREATE TABLE users (id int PRIMARY KEY);
+                CREATE TABLE orders (id int, user_id int, amount int);
+                INSERT INTO users VALUES (1);
+                INSERT INTO orders VALUES (1, 1, 5), (2, NULL, 5), (3, 2, -1), (4, 3, 5);
+                CREATE TABLE deferred (user_id int REFERENCES users DEFERRABLE INITIALLY DEFERRED);",
+            )
+            .unwrap();
+            validate_constraints(&conn).unwrap();
+
+            // Row 2 is fine, with a null user_id.
+            conn.batch_execute(
+                "ALTER TABLE orders ADD CONSTRAINT orders_user_id_fkey
+                    FOREIGN KEY (user_id) REFERENCES users NOT VALID;
+                ALTER TABLE orders ADD CONSTRAINT positive CHECK (amount > 0) NOT VALID;",
+            )
+            .unwrap();
+            match validate_constraints(&conn) {
+                Err(Error::ConstraintsViolated(violations)) => assert_eq!(
+                    violations,
+                    vec![
+                        ConstraintViolation {
+                            table: "orders".to_owned(),
+                            constraint: "orders_user_id_fkey".to_owned(),
+                            definition: "FOREIGN KEY (user_id) REFERENCES users(id) NOT VALID"
+                                .to_owned(),
+                            rows: 2,
+                        },
+                        ConstraintViolation {
+                            table: "orders".to_owned(),
+                            constraint: "positive".to_owned(),
+                            definition: "CHECK ((amount > 0)) NOT VALID".to_owned(),
+                            rows: 1,
+                        },
+                    ]
+                ),
+                other => panic!("Unexpected result {:?}", other),
+            }
+            conn.batch_execute("DELETE FROM orders WHERE id > 2").unwrap();
+            validate_constraints(&conn).unwrap();
+
+            let transaction = conn.transaction().unwrap();
+            transaction
+                .batch_execute("INSERT INTO deferred VALUES (2)")
+                .unwrap();
+            assert!(validate_constraints(&transaction).is_err());
+        })
+        .expect("Failed to create temporary database");
+    }
+}
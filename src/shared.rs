@@ -0,0 +1,144 @@
+use std::borrow::Borrow;
+
+use log::*;
+use postgres::params::ConnectParams;
+use postgres::{Connection, TlsMode};
+
+use crate::{clone_tls_mode, quote_ident, random_string, Result};
+
+/// A schema created for one test in an existing database, dropped with
+/// everything in it when dropped. The cheaper alternative to a
+/// [`TemporaryDatabase`](crate::TemporaryDatabase) for suites with thousands
+/// of small tests: creating and dropping a schema takes a fraction of the time
+/// of a database, and many tests can share a server and database this way.
+///
+/// The isolation is weaker than with a database per test:
+/// - The connection's `search_path` is just the schema, so unqualified names
+///   are isolated, but nothing stops a test from using another test's schema
+///   or `public` by qualifying names.
+/// - Everything outside of schemas is shared: roles, extensions, database and
+///   role settings, and `LISTEN`/`NOTIFY` channels. Extensions have to be
+///   installed once beforehand, e.g. with
+///   [`PostgresContainer::once_per_container`](crate::PostgresContainer::once_per_container).
+/// - The connection uses the role of the params, usually a superuser, instead
+///   of a role of its own with only the privileges of an ordinary user.
+/// - Tests looking at database-wide state, like `pg_stat_*` views or
+///   advisory locks, see each other.
+///
+/// Tests which need more than that should use a temporary database.
+pub struct TemporarySchema {
+    conn: Connection,
+    name: String,
+    dropped: bool,
+}
+
+impl TemporarySchema {
+    /// Create a schema with a random name on `conn`, and set the connection's
+    /// `search_path` to it.
+    pub fn create(conn: Connection) -> Result<TemporarySchema> {
+        let name = format!("kpg_fixture_{}", random_string(20));
+        conn.batch_execute(&format!(
+            "CREATE SCHEMA {schema}; SET search_path TO {schema};",
+            schema = quote_ident(&name)
+        ))?;
+        debug!("Created temporary schema {:?}", name);
+        Ok(TemporarySchema {
+            conn,
+            name,
+            dropped: false,
+        })
+    }
+
+    /// The connection whose `search_path` is the schema.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// The name of the schema.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Drop the schema, reporting errors that dropping it would only log.
+    pub fn drop_schema(mut self) -> Result<()> {
+        self.cleanup()
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        self.dropped = true;
+        // In case the test left a transaction open, where the DROP would be
+        // rolled back with it, or failed.
+        self.conn.batch_execute("ROLLBACK")?;
+        self.conn
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE;", quote_ident(&self.name)))?;
+        debug!("Dropped temporary schema {:?}", self.name);
+        Ok(())
+    }
+}
+
+impl Drop for TemporarySchema {
+    fn drop(&mut self) {
+        if !self.dropped {
+            if let Err(err) = self.cleanup() {
+                warn!("Failed to drop temporary schema {:?}: {:?}", self.name, err);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for TemporarySchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TemporarySchema")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// Connect to the database of `params`, and call `f` with the connection and
+/// the name of a [`TemporarySchema`] it uses, dropped once `f` returns or
+/// panics. See [`TemporarySchema`] for how this is isolated compared to
+/// [`with_temporary_database`](crate::with_temporary_database).
+pub fn with_temporary_schema<'a, T, P, M, F>(params: P, tls_mode: M, f: F) -> Result<T>
+where
+    P: Borrow<ConnectParams>,
+    M: Borrow<TlsMode<'a>>,
+    F: FnOnce(&Connection, &str) -> T,
+{
+    let conn = Connection::connect(params.borrow().clone(), clone_tls_mode(tls_mode.borrow()))?;
+    let schema = TemporarySchema::create(conn)?;
+    let result = f(schema.connection(), schema.name());
+    schema.drop_schema()?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+
+    #[test]
+    fn temp_schema() {
+        let admin_params = admin_params();
+        let name = with_temporary_schema(&admin_params, TlsMode::None, |conn, schema| {
+            // Left open, which the cleanup rolls back.
+            conn.batch_execute("CREATE TABLE test (id int); BEGIN; SELECT 1;")
+                .unwrap();
+            let rows = conn
+                .query(
+                    "SELECT relnamespace::regnamespace::text FROM pg_class
+                    WHERE oid = 'test'::regclass",
+                    &[],
+                )
+                .unwrap();
+            assert_eq!(rows.get(0).get::<_, String>(0), schema);
+            schema.to_owned()
+        })
+        .unwrap();
+
+        let conn = Connection::connect(admin_params, TlsMode::None).unwrap();
+        let rows = conn
+            .query("SELECT 1 FROM pg_namespace WHERE nspname = $1", &[&name])
+            .unwrap();
+        assert!(rows.is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use crate::{Error, Queryable, Result};
+use log::*;
+
+/// What `pg_stat_statements` recorded about one normalized statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementStats {
+    /// The statement with its constants replaced by `$1`, `$2`, ...
+    pub query: String,
+    /// How many times it was executed.
+    pub calls: i64,
+    /// Rows retrieved or affected, summed over all calls.
+    pub rows: i64,
+    /// Time spent executing it, summed over all calls.
+    pub total_time: Duration,
+}
+
+/// Throw away everything `pg_stat_statements` recorded so far. `conn` needs to
+/// be allowed to call `pg_stat_statements_reset()`, e.g. be a superuser.
+pub fn reset_statement_stats(conn: &(impl Queryable + ?Sized)) -> Result<()> {
+    conn.batch_execute("SELECT pg_stat_statements_reset();")?;
+    Ok(())
+}
+
+/// The statements `pg_stat_statements` recorded in `conn`'s database, most
+/// called first, leaving out the ones querying `pg_stat_statements` itself.
+///
+/// Query texts of other roles are only visible to superusers or members of
+/// `pg_read_all_stats`.
+pub fn statement_stats(conn: &(impl Queryable + ?Sized)) -> Result<Vec<StatementStats>> {
+    // The time column was renamed to total_exec_time in postgres 13, when
+    // planning started being tracked separately. Going through jsonb picks
+    // whichever exists without querying the version, which would be captured.
+    let rows = conn.query(
+        "SELECT query, calls, rows,
+                coalesce(to_jsonb(s)->>'total_exec_time', to_jsonb(s)->>'total_time')::float8
+            FROM pg_stat_statements s
+            WHERE dbid = (SELECT oid FROM pg_database WHERE datname = current_database())
+                AND query NOT LIKE '%pg_stat_statements%'
+            ORDER BY calls DESC, query",
+        &[],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| StatementStats {
+            query: row.get(0),
+            calls: row.get(1),
+            rows: row.get(2),
+            total_time: Duration::from_secs_f64(row.get::<_, f64>(3) / 1000.0),
+        })
+        .collect())
+}
+
+/// Reset the statement stats, run `f`, and return what it ran in `conn`'s
+/// database along with its result.
+///
+/// Needs the server started with `pg_stat_statements` in
+/// `shared_preload_libraries` (see `PostgresConfig::pg_stat_statements`) and
+/// the extension created in the database. `conn` should be a superuser
+/// connection to the database, and `f` can use any other connection to it.
+pub fn with_statement_capture<T, F: FnOnce() -> T>(
+    conn: &(impl Queryable + ?Sized),
+    f: F,
+) -> Result<(T, Vec<StatementStats>)> {
+    reset_statement_stats(conn)?;
+    let result = f();
+    let statements = statement_stats(conn)?;
+    debug!("Captured {} statements", statements.len());
+    Ok((result, statements))
+}
+
+/// Reset the statement stats and run `f` like [`with_statement_capture`],
+/// then fail with [`Error::UnexpectedQueries`], listing the offenders, if it
+/// ran any statement in `conn`'s database that doesn't match one of the
+/// `allowed` patterns, e.g. to guard against N+1 queries or extra round
+/// trips. Returns what `f` returned otherwise.
+///
+/// Patterns are postgres regular expressions matched case insensitively
+/// against the normalized text of each statement, which has its constants
+/// replaced by `$1`, `$2`, ... and its whitespace collapsed to single spaces,
+/// such as `^SELECT .* FROM users WHERE id = \$1$`.
+pub fn assert_only_queries<T, F: FnOnce() -> T>(
+    conn: &(impl Queryable + ?Sized),
+    allowed: &[&str],
+    f: F,
+) -> Result<T> {
+    let (result, statements) = with_statement_capture(conn, f)?;
+    let allowed: Vec<String> = allowed.iter().map(|&pattern| pattern.to_owned()).collect();
+    let queries: Vec<String> = statements
+        .into_iter()
+        .map(|stats| stats.query.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    // The comment keeps this out of later statement_stats calls.
+    let rows = conn.query(
+        "SELECT query /* not from the code under test: pg_stat_statements */
+            FROM unnest($1::text[]) query WHERE NOT query ~* ANY($2::text[])",
+        &[&queries, &allowed],
+    )?;
+    let unexpected: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    if !unexpected.is_empty() {
+        debug!("Unexpected queries (allowed: {:?})", allowed);
+        return Err(Error::UnexpectedQueries(unexpected));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::admin_params;
+    use crate::{params_with_database, with_temporary_database_config, DatabaseConfig};
+    use postgres::{Connection, TlsMode};
+
+    #[test]
+    #[ignore = "needs shared_preload_libraries=pg_stat_statements"]
+    fn captures_statements_of_the_closure() {
+        let admin_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.extension("pg_stat_statements");
+        with_temporary_database_config(&admin_params, TlsMode::None, &config, |params, _| {
+            let admin = Connection::connect(
+                params_with_database(&admin_params, params.database()),
+                TlsMode::None,
+            )
+            .unwrap();
+            let conn = Connection::connect(params, TlsMode::None).unwrap();
+            let ((), statements) = with_statement_capture(&admin, || {
+                for i in 0..3 {
+                    conn.execute("SELECT $1::int", &[&i]).unwrap();
+                }
+            })
+            .unwrap();
+            assert_eq!(statements.len(), 1, "{:#?}", statements);
+            assert_eq!(statements[0].calls, 3);
+        })
+        .expect("Failed to create temporary database");
+    }
+
+    #[test]
+    #[ignore = "needs shared_preload_libraries=pg_stat_statements"]
+    fn only_allowed_queries() {
+        let admin_params = admin_params();
+        let mut config = DatabaseConfig::new();
+        config.extension("pg_stat_statements");
+        with_temporary_database_config(&admin_params, TlsMode::None, &config, |params, _| {
+            let admin = Connection::connect(
+                params_with_database(&admin_params, params.database()),
+                TlsMode::None,
+            )
+            .unwrap();
+            let conn = Connection::connect(params, TlsMode::None).unwrap();
+            let select = || conn.execute("SELECT   $1::int\n  + 1", &[&1]).unwrap();
+            assert_eq!(
+                assert_only_queries(&admin, &[r"^select \$1::int \+ \$2$"], select).unwrap(),
+                1
+            );
+            // The matching itself isn't left for the next check.
+            assert_eq!(statement_stats(&admin).unwrap().len(), 1);
+            assert_only_queries(&admin, &[], || ()).unwrap();
+            match assert_only_queries(&admin, &["^INSERT"], select) {
+                Err(Error::UnexpectedQueries(queries)) => assert!(queries
+                    .iter()
+                    .any(|query| query.eq_ignore_ascii_case("select $1::int + $2"))),
+                other => panic!("Expected UnexpectedQueries, got {:?}", other),
+            }
+        })
+        .expect("Failed to create temporary database");
+    }
+}
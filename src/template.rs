@@ -0,0 +1,204 @@
+use log::*;
+use postgres::params::ConnectParams;
+use postgres::{Connection, TlsMode};
+
+use crate::record::{self, SqlRecorder};
+use crate::{
+    connect_admin, execute_setup_step, install_extensions, quote_ident, quote_literal,
+    terminate_connections, ConnectionPurpose, DatabaseConfig, Result,
+};
+
+/// Bumped whenever what goes into a cached template changes, so templates
+/// built by an older version of the crate are rebuilt.
+const FORMAT: &str = "kpg_fixture template v1";
+
+/// Prefix of the COMMENT ON DATABASE recording a cached template's hash.
+const COMMENT_PREFIX: &str = "kpg_fixture template ";
+
+/// Hold a session level advisory lock for the cached template `name` on
+/// `conn`, so only one process at a time checks, builds or copies it.
+pub(crate) fn lock(conn: &Connection, name: &str) -> Result<()> {
+    conn.execute(
+        "SELECT pg_advisory_lock(hashtext($1))",
+        &[&format!("{}{}", COMMENT_PREFIX, name)],
+    )?;
+    Ok(())
+}
+
+pub(crate) fn unlock(conn: &Connection, name: &str) -> Result<()> {
+    conn.execute(
+        "SELECT pg_advisory_unlock(hashtext($1))",
+        &[&format!("{}{}", COMMENT_PREFIX, name)],
+    )?;
+    Ok(())
+}
+
+/// Hash of everything that ends up in the template: the extensions and the
+/// contents of the setup steps, in order. Each input is length prefixed so
+/// that moving SQL between steps changes the hash too.
+fn inputs_hash(conn: &Connection, config: &DatabaseConfig, setup: &[String]) -> Result<String> {
+    let mut inputs = format!("{}\n", FORMAT);
+    for extension in &config.extensions {
+        inputs.push_str(&format!("extension {}\n{}\n", extension.len(), extension));
+    }
+    for sql in setup {
+        inputs.push_str(&format!("setup {}\n{}\n", sql.len(), sql));
+    }
+    // md5 on the server rather than std's hasher, whose output isn't
+    // guaranteed to stay the same between Rust releases.
+    Ok(conn.query("SELECT md5($1)", &[&inputs])?.get(0).get(0))
+}
+
+/// Make sure the cached template `name` exists and was built from the current
+/// inputs, (re)building it if not. Must be called with the lock held.
+///
+/// The template's objects are owned by a NOLOGIN role of the same name, so
+/// that they can be handed over to each temporary role with REASSIGN OWNED.
+/// The database itself isn't, since REASSIGN OWNED would hand that over too.
+pub(crate) fn ensure(
+    config: &DatabaseConfig,
+    params: &ConnectParams,
+    tls_mode: &TlsMode,
+    conn: &Connection,
+    name: &str,
+) -> Result<()> {
+    let recorder = config.sql_recorder.as_ref();
+    let setup = config
+        .setup
+        .iter()
+        .map(|step| step.sql())
+        .collect::<Result<Vec<_>>>()?;
+    let comment = format!("{}{}", COMMENT_PREFIX, inputs_hash(conn, config, &setup)?);
+
+    let rows = conn.query(
+        "SELECT shobj_description(oid, 'pg_database') FROM pg_database WHERE datname = $1",
+        &[&name],
+    )?;
+    if !rows.is_empty() {
+        let current: Option<String> = rows.get(0).get(0);
+        if current.as_ref() == Some(&comment) {
+            debug!("Reusing cached template {:?}", name);
+            return Ok(());
+        }
+        info!(
+            "Rebuilding cached template {:?} since its inputs changed",
+            name
+        );
+        terminate_connections(conn, name)?;
+        record::batch_execute(
+            recorder,
+            conn,
+            &format!("DROP DATABASE {};", quote_ident(name)),
+        )?;
+    } else {
+        info!("Building cached template {:?}", name);
+    }
+
+    let owner_exists = !conn
+        .query("SELECT 1 FROM pg_roles WHERE rolname = $1", &[&name])?
+        .is_empty();
+    if !owner_exists {
+        record::batch_execute(
+            recorder,
+            conn,
+            &format!("CREATE ROLE {} NOLOGIN;", quote_ident(name)),
+        )?;
+    }
+    record::batch_execute(
+        recorder,
+        conn,
+        &format!("CREATE DATABASE {};", quote_ident(name)),
+    )?;
+    record::batch_execute(
+        recorder,
+        conn,
+        &format!(
+            "GRANT CREATE ON DATABASE {name} TO {name};",
+            name = quote_ident(name)
+        ),
+    )?;
+    let built = (|| -> Result<()> {
+        // Closed again before the template is copied.
+        let template_conn = connect_admin(
+            config,
+            ConnectionPurpose::CachedTemplate,
+            params,
+            tls_mode,
+            Some(name),
+            &[],
+        )?;
+        install_extensions(recorder, &template_conn, &config.extensions)?;
+        record::batch_execute(
+            recorder,
+            &template_conn,
+            &format!(
+                "GRANT CREATE ON SCHEMA public TO {name}; SET ROLE {name};",
+                name = quote_ident(name)
+            ),
+        )?;
+        for sql in &setup {
+            execute_setup_step(config, &template_conn, sql)?;
+        }
+        Ok(())
+    })();
+    if let Err(err) = built {
+        // Don't leave a half built template behind to be rebuilt next time
+        // anyway.
+        record::batch_execute(
+            recorder,
+            conn,
+            &format!("DROP DATABASE {};", quote_ident(name)),
+        )?;
+        return Err(err);
+    }
+    // Written last, so a template only counts as built once it completely is.
+    record::batch_execute(
+        recorder,
+        conn,
+        &format!(
+            "COMMENT ON DATABASE {} IS {};",
+            quote_ident(name),
+            quote_literal(&comment)
+        ),
+    )?;
+    Ok(())
+}
+
+/// Give `role` the objects copied from the cached template `name`, and drop
+/// the privileges the template's role had, so nothing in the copy refers to
+/// it anymore. Runs in the new database.
+pub(crate) fn reassign_owned(
+    recorder: Option<&SqlRecorder>,
+    conn: &Connection,
+    name: &str,
+    role: &str,
+) -> Result<()> {
+    record::batch_execute(
+        recorder,
+        conn,
+        &format!(
+            "REASSIGN OWNED BY {name} TO {role}; DROP OWNED BY {name};",
+            name = quote_ident(name),
+            role = quote_ident(role)
+        ),
+    )?;
+    Ok(())
+}
+
+/// Drop the cached template `name` and the role owning its objects, e.g. at
+/// the end of a test run or to force a rebuild. Does nothing if neither
+/// exists.
+///
+/// `conn` must be an admin connection to a database other than the template.
+pub fn drop_cached_template(conn: &Connection, name: &str) -> Result<()> {
+    lock(conn, name)?;
+    let result = (|| -> Result<()> {
+        terminate_connections(conn, name)?;
+        // Separately, since DROP DATABASE can't run in the implicit transaction.
+        conn.batch_execute(&format!("DROP DATABASE IF EXISTS {};", quote_ident(name)))?;
+        conn.batch_execute(&format!("DROP ROLE IF EXISTS {};", quote_ident(name)))?;
+        Ok(())
+    })();
+    unlock(conn, name)?;
+    result
+}